@@ -0,0 +1,97 @@
+use anyhow::{Context, Result};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+/// Compress a folder-mode output directory into `<dir>.zip` beside it.
+/// Entries are stored under the directory's own name (e.g. `book/README.md`).
+pub fn zip_directory(dir: &Path) -> Result<PathBuf> {
+    let name = dir
+        .file_name()
+        .context("Output directory has no name")?
+        .to_string_lossy()
+        .to_string();
+    let archive_path = archive_path(dir);
+
+    let file = File::create(&archive_path)
+        .with_context(|| format!("Failed to create archive: {}", archive_path.display()))?;
+    let mut zip = ZipWriter::new(file);
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+
+    add_directory(&mut zip, dir, &name, options)?;
+
+    zip.finish()
+        .with_context(|| format!("Failed to finish archive: {}", archive_path.display()))?;
+
+    Ok(archive_path)
+}
+
+/// Split an `archive.zip!inner/book.epub` input into the archive path and the
+/// entry name. Returns `None` for plain paths (or when the archive part does not exist).
+pub fn split_entry_path(input: &Path) -> Option<(PathBuf, String)> {
+    let input = input.to_str()?;
+    // Try each `!` so archive names containing one still resolve
+    input.match_indices('!').find_map(|(i, _)| {
+        let archive = Path::new(&input[..i]);
+        let entry = &input[i + 1..];
+        (archive.is_file() && !entry.is_empty()).then(|| (archive.to_path_buf(), entry.to_string()))
+    })
+}
+
+/// Read one entry of a zip archive into memory
+pub fn read_entry(archive_path: &Path, entry: &str) -> Result<Vec<u8>> {
+    let file = File::open(archive_path)
+        .with_context(|| format!("Failed to open archive: {}", archive_path.display()))?;
+    let mut zip = ZipArchive::new(file)
+        .with_context(|| format!("Failed to read archive: {}", archive_path.display()))?;
+    let mut entry_file = zip
+        .by_name(entry.trim_start_matches('/'))
+        .with_context(|| format!("No entry {} in {}", entry, archive_path.display()))?;
+
+    let mut bytes = Vec::new();
+    entry_file
+        .read_to_end(&mut bytes)
+        .with_context(|| format!("Failed to read {} from {}", entry, archive_path.display()))?;
+    Ok(bytes)
+}
+
+/// Archive location for a folder-mode output directory
+pub fn archive_path(dir: &Path) -> PathBuf {
+    let name = dir
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_else(|| "output".to_string());
+    dir.with_file_name(format!("{}.zip", name))
+}
+
+fn add_directory(
+    zip: &mut ZipWriter<File>,
+    dir: &Path,
+    prefix: &str,
+    options: SimpleFileOptions,
+) -> Result<()> {
+    // Sort entries so archives are reproducible across runs
+    let mut entries: Vec<_> = fs::read_dir(dir)
+        .with_context(|| format!("Failed to read directory: {}", dir.display()))?
+        .collect::<std::io::Result<_>>()?;
+    entries.sort_by_key(|e| e.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        let entry_name = format!("{}/{}", prefix, entry.file_name().to_string_lossy());
+
+        if path.is_dir() {
+            zip.add_directory(entry_name.as_str(), options)?;
+            add_directory(zip, &path, &entry_name, options)?;
+        } else {
+            let data = fs::read(&path)
+                .with_context(|| format!("Failed to read file: {}", path.display()))?;
+            zip.start_file(entry_name.as_str(), options)?;
+            zip.write_all(&data)?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,217 @@
+//! Directory and glob inputs (`books/`, `books/**/*.epub`). Each matching EPUB or
+//! DOCX file is converted on its own, to an output mirroring its directory relative
+//! to the input under the `--output` base; `--catalog` lists the same files instead.
+
+use crate::cli::{Cli, OutputFormat};
+use crate::converter;
+use crate::image;
+use anyhow::{bail, Context, Result};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// A file found for a directory or glob input
+pub struct BatchInput {
+    pub path: PathBuf,
+    /// Path below the directory, or below the glob's fixed leading directories
+    pub relative: PathBuf,
+}
+
+/// Whether an input stands for several files rather than naming one
+pub fn is_batch_input(input: &Path) -> bool {
+    input.is_dir() || (!input.exists() && is_glob(&input.to_string_lossy()))
+}
+
+fn is_glob(text: &str) -> bool {
+    text.contains(['*', '?'])
+}
+
+/// The files the inputs stand for, in name order per input. Directories give the
+/// EPUB and DOCX files directly inside them (or below them with `--recursive`);
+/// plain file inputs are kept as they are and must have a supported format
+pub fn expand_inputs(cli: &Cli) -> Result<Vec<BatchInput>> {
+    let mut inputs = Vec::new();
+    for input in &cli.input {
+        let mut found = if input.is_dir() {
+            let depth = if cli.recursive { usize::MAX } else { 1 };
+            walk(input, depth)?
+        } else if is_batch_input(input) {
+            glob_files(&input.to_string_lossy())?
+        } else {
+            converter::input_format(cli, input)?;
+            let name = input.file_name().context("Input file has no name")?;
+            inputs.push(BatchInput {
+                path: input.clone(),
+                relative: PathBuf::from(name),
+            });
+            continue;
+        };
+
+        found.retain(|file| converter::input_format(cli, &file.path).is_ok());
+        if found.is_empty() {
+            eprintln!(
+                "Warning: no EPUB or DOCX files found for {}",
+                input.display()
+            );
+        }
+        found.sort_by(|a, b| a.path.cmp(&b.path));
+        inputs.extend(found);
+    }
+    Ok(inputs)
+}
+
+/// Files below `dir`, at most `depth` directory levels down
+fn walk(dir: &Path, depth: usize) -> Result<Vec<BatchInput>> {
+    let mut files = Vec::new();
+    let mut pending = vec![(dir.to_path_buf(), 1)];
+    while let Some((current, level)) = pending.pop() {
+        let entries = fs::read_dir(&current)
+            .with_context(|| format!("Failed to read directory: {}", current.display()))?;
+        for entry in entries {
+            let path = entry?.path();
+            if path.is_dir() {
+                if level < depth {
+                    pending.push((path, level + 1));
+                }
+            } else if path.is_file() {
+                let relative = path.strip_prefix(dir).unwrap_or(&path).to_path_buf();
+                files.push(BatchInput { path, relative });
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Files matching `pattern`, where `*` and `?` stay within one path segment and a
+/// `**` segment matches any number of directories
+fn glob_files(pattern: &str) -> Result<Vec<BatchInput>> {
+    let pattern = pattern.replace('\\', "/");
+    let segments: Vec<&str> = pattern.split('/').collect();
+    let fixed = segments.iter().take_while(|s| !is_glob(s)).count();
+    let base = match segments[..fixed].join("/") {
+        ref joined if joined.is_empty() && pattern.starts_with('/') => PathBuf::from("/"),
+        joined if joined.is_empty() => PathBuf::from("."),
+        joined => PathBuf::from(joined),
+    };
+    let rest = &segments[fixed..];
+    if !base.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let depth = if rest.contains(&"**") {
+        usize::MAX
+    } else {
+        rest.len()
+    };
+    let mut files = walk(&base, depth)?;
+    files.retain(|file| {
+        let relative = file.relative.to_string_lossy().replace('\\', "/");
+        let parts: Vec<&str> = relative.split('/').collect();
+        segments_match(rest, &parts)
+    });
+    Ok(files)
+}
+
+fn segments_match(pattern: &[&str], parts: &[&str]) -> bool {
+    match (pattern.split_first(), parts.split_first()) {
+        (None, None) => true,
+        (Some((&"**", rest)), _) => {
+            segments_match(rest, parts)
+                || (!parts.is_empty() && segments_match(pattern, &parts[1..]))
+        }
+        (Some((segment, rest)), Some((part, parts))) => {
+            image::glob_match(segment, part) && segments_match(rest, parts)
+        }
+        _ => false,
+    }
+}
+
+/// Convert every file the inputs stand for separately, mirroring their relative
+/// directories under the output base. A file that fails is reported and skipped
+pub fn convert_batch(cli: &Cli) -> Result<()> {
+    if cli.stdout {
+        bail!("--stdout needs a single input file, not a directory or pattern");
+    }
+    let inputs = expand_inputs(cli)?;
+    let base = match cli.output {
+        Some(ref path) => path.clone(),
+        None if cli.temp => converter::create_temp_dir()?,
+        None => PathBuf::from("."),
+    };
+
+    let mut used = HashSet::new();
+    let mut failed = 0;
+    for input in &inputs {
+        let output = unique_output(&base, input, cli, &mut used);
+        // Single files sharing a directory would otherwise share its images/
+        let asset_dir = (cli.single || cli.output_format == OutputFormat::Html)
+            .then(|| output.file_stem().map(|s| s.to_string_lossy().to_string()))
+            .flatten();
+        let file_cli = Cli {
+            input: vec![input.path.clone()],
+            output: Some(output),
+            temp: false,
+            ..cli.clone()
+        };
+        if let Err(e) = converter::convert_inputs(&file_cli, asset_dir.as_deref(), Instant::now()) {
+            eprintln!(
+                "Warning: failed to convert {}: {:#}",
+                input.path.display(),
+                e
+            );
+            failed += 1;
+        }
+    }
+
+    eprintln!(
+        "Converted {} of {} file{} under {}",
+        inputs.len() - failed,
+        inputs.len(),
+        if inputs.len() == 1 { "" } else { "s" },
+        base.display()
+    );
+    if cli.temp {
+        println!("{}", base.display());
+    }
+    if failed > 0 {
+        bail!("{} of {} files failed to convert", failed, inputs.len());
+    }
+    Ok(())
+}
+
+/// Output path for one file: its relative directory under `base`, named after its
+/// stem, with `-2`, `-3`, … added when two files would share an output
+fn unique_output(
+    base: &Path,
+    input: &BatchInput,
+    cli: &Cli,
+    used: &mut HashSet<PathBuf>,
+) -> PathBuf {
+    let dir = base.join(input.relative.parent().unwrap_or(Path::new("")));
+    let stem = input
+        .relative
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_else(|| "book".to_string());
+    let extension = if cli.output_format == OutputFormat::Html {
+        ".html"
+    } else if cli.single {
+        ".md"
+    } else {
+        ""
+    };
+
+    let mut suffix = 1;
+    loop {
+        let name = match suffix {
+            1 => format!("{}{}", stem, extension),
+            n => format!("{}-{}{}", stem, n, extension),
+        };
+        let candidate = dir.join(name);
+        if used.insert(candidate.clone()) {
+            return candidate;
+        }
+        suffix += 1;
+    }
+}
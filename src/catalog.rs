@@ -0,0 +1,77 @@
+//! `--catalog`: a listing of books built from their metadata and cover alone,
+//! without converting any chapters.
+
+use crate::json::json_string;
+
+/// Display width of the cover thumbnails in `catalog.md`; covers are copied as is
+const THUMBNAIL_WIDTH: u32 = 120;
+
+/// One book of the catalog
+pub struct CatalogEntry {
+    /// Input path as given (or found in a directory)
+    pub file: String,
+    pub title: String,
+    pub authors: Vec<String>,
+    pub description: Option<String>,
+    /// Path of the copied cover, relative to the catalog file
+    pub cover: Option<String>,
+}
+
+/// `catalog.md`: a section per book with its cover thumbnail, authors and description
+pub fn render_markdown(entries: &[CatalogEntry]) -> String {
+    let mut out = String::from("# Catalog\n");
+
+    for entry in entries {
+        out.push_str(&format!("\n## {}\n\n", one_line(&entry.title)));
+        if let Some(ref cover) = entry.cover {
+            out.push_str(&format!(
+                "<img src=\"{}\" alt=\"cover\" width=\"{}\">\n\n",
+                cover.replace('"', "&quot;"),
+                THUMBNAIL_WIDTH
+            ));
+        }
+        if !entry.authors.is_empty() {
+            out.push_str(&format!("**Author:** {}\n", entry.authors.join(", ")));
+        }
+        out.push_str(&format!("**File:** `{}`\n", entry.file));
+        if let Some(ref description) = entry.description {
+            out.push_str(&format!("\n> {}\n", one_line(description)));
+        }
+    }
+
+    out
+}
+
+/// `catalog.json`: an array with one object per book; missing fields are `null`
+pub fn render_json(entries: &[CatalogEntry]) -> String {
+    let optional = |value: &Option<String>| {
+        value
+            .as_deref()
+            .map_or_else(|| "null".to_string(), json_string)
+    };
+
+    let objects: Vec<String> = entries
+        .iter()
+        .map(|entry| {
+            let authors: Vec<String> = entry.authors.iter().map(|a| json_string(a)).collect();
+            format!(
+                "  {{\"file\":{},\"title\":{},\"authors\":[{}],\"description\":{},\"cover\":{}}}",
+                json_string(&entry.file),
+                json_string(&entry.title),
+                authors.join(","),
+                optional(&entry.description),
+                optional(&entry.cover)
+            )
+        })
+        .collect();
+
+    if objects.is_empty() {
+        return "[]\n".to_string();
+    }
+    format!("[\n{}\n]\n", objects.join(",\n"))
+}
+
+/// Collapse line breaks and whitespace runs, which would end a heading or quote
+fn one_line(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
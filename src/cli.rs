@@ -1,23 +1,506 @@
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use std::path::PathBuf;
 
 /// Convert EPUB and DOCX documents to clean Markdown
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(name = "epub2md", version, about)]
 pub struct Cli {
-    /// Path to the input file (.epub or .docx)
-    pub input: PathBuf,
+    /// Path to the input file (.epub or .docx), or `archive.zip!inner/book.epub` for a
+    /// book inside a zip. Several inputs are merged into one output, each becoming a
+    /// top-level section. A directory or a quoted glob (`'books/**/*.epub'`) stands
+    /// for the EPUB and DOCX files it matches, each converted separately under the
+    /// output directory (or listed, with `--catalog`)
+    #[arg(required = true)]
+    pub input: Vec<PathBuf>,
+
+    /// Include files in subdirectories of directory inputs
+    #[arg(short, long, default_value_t = false)]
+    pub recursive: bool,
 
     /// Output path (directory for folder mode, file for single-file mode).
     /// Defaults to a directory or file named after the input in the current directory.
     #[arg(short, long)]
     pub output: Option<PathBuf>,
 
+    /// Write the output into a new directory under the system temp directory and
+    /// print the resulting path to stdout
+    #[arg(long, default_value_t = false, conflicts_with_all = ["output", "stdout"])]
+    pub temp: bool,
+
     /// Output as a single combined Markdown file instead of a directory of chapter files
     #[arg(short, long, default_value_t = false)]
     pub single: bool,
 
+    /// In single-file mode, split output larger than N kilobytes into `<name>-part-01.md`,
+    /// `<name>-part-02.md`, … breaking only between chapters
+    #[arg(long, value_name = "N", requires = "single", conflicts_with = "stdout")]
+    pub split_size: Option<u64>,
+
+    /// Write the combined Markdown (metadata + chapters), or with `--output-format html` the
+    /// HTML document, to standard output instead of files.
+    /// Implies single-file semantics and disables image extraction
+    #[arg(
+        long,
+        alias = "output-stdout",
+        default_value_t = false,
+        conflicts_with = "output"
+    )]
+    pub stdout: bool,
+
+    /// Input format, overriding detection from the file extension
+    #[arg(long, value_enum)]
+    pub format: Option<InputFormat>,
+
+    /// Output format. `html` serializes EPUB chapters into one HTML document
+    #[arg(long, value_enum, default_value_t = OutputFormat::Markdown)]
+    pub output_format: OutputFormat,
+
+    /// Write a catalog of the inputs instead of converting them: one entry per book with
+    /// its cover, title, authors and description, as `catalog.md` in the output
+    /// directory. Chapter content is not read
+    #[arg(
+        long,
+        default_value_t = false,
+        conflicts_with_all = ["single", "output_format", "output_template", "zip", "summary_json"]
+    )]
+    pub catalog: bool,
+
+    /// Format of the `--catalog` listing
+    #[arg(long, value_enum, default_value_t = CatalogFormat::Markdown, requires = "catalog")]
+    pub catalog_format: CatalogFormat,
+
+    /// With `--output-format html`, inline a reading stylesheet, open with a title
+    /// block and wrap chapters in `<main>` / `<article>` elements
+    #[arg(long, default_value_t = false)]
+    pub with_css: bool,
+
     /// Do not extract images (only convert text content)
     #[arg(long, default_value_t = false)]
     pub no_images: bool,
+
+    /// Write images directly beside the output files instead of an `images/` subdirectory
+    #[arg(long, alias = "flatten", default_value_t = false)]
+    pub flat_images: bool,
+
+    /// Also copy EPUB audio and video files alongside the extracted images
+    #[arg(long, default_value_t = false)]
+    pub extract_media: bool,
+
+    /// Convert XHTML documents of the book that chapters embed with `<iframe>` or
+    /// `<object>` in place, instead of leaving an `[embedded content: src]` note.
+    /// Documents that are chapters themselves keep the note
+    #[arg(long, default_value_t = false)]
+    pub inline_embedded: bool,
+
+    /// Name extracted images image-001, image-002, … in the order they are first
+    /// referenced in the text
+    #[arg(long, default_value_t = false)]
+    pub retain_source_order_images: bool,
+
+    /// Skip images larger than N bytes, pointing their references at a placeholder image
+    #[arg(long, value_name = "N")]
+    pub max_image_bytes: Option<u64>,
+
+    /// With --max-image-bytes, keep oversized images and only warn about them
+    #[arg(long, default_value_t = false, requires = "max_image_bytes")]
+    pub warn_large_images: bool,
+
+    /// Write extracted images on N threads. Defaults to one per core, up to eight
+    #[arg(long, value_name = "N")]
+    pub image_concurrency: Option<usize>,
+
+    /// Also write `images.json` beside the images, mapping every original image href
+    /// to its output path with the file's format, width, height and size in bytes
+    #[arg(long, default_value_t = false, conflicts_with_all = ["no_images", "stdout", "catalog"])]
+    pub output_per_image_manifest: bool,
+
+    /// Leave out images whose path matches this glob (`*`, `?`), e.g. `*separator*`,
+    /// and drop their references. Repeatable; 1×1 spacer images are always left out
+    #[arg(long, value_name = "GLOB")]
+    pub ignore_images_matching: Vec<String>,
+
+    /// Use the image filename as alt text when an image has none
+    #[arg(long, default_value_t = false)]
+    pub image_alt_from_filename: bool,
+
+    /// Join wrapped lines inside DOCX table cells with spaces; only separate
+    /// paragraphs in a cell are kept apart with the cell break (`<br>`)
+    #[arg(long, default_value_t = false)]
+    pub collapse_whitespace_in_tables: bool,
+
+    /// Token for line breaks inside table cells, e.g. `<br/>` [default: `<br>`]
+    #[arg(long, value_name = "TOKEN")]
+    pub cell_break: Option<String>,
+
+    /// Replace images that were not extracted (e.g. with --no-images) with an
+    /// `[image: alt text]` placeholder instead of a broken link
+    #[arg(long, default_value_t = false)]
+    pub image_placeholder: bool,
+
+    /// Keep line breaks within paragraphs (`<br>`, DOCX text-wrapping breaks) as hard
+    /// line breaks instead of joining the lines, for poetry and addresses
+    #[arg(long, default_value_t = false)]
+    pub preserve_line_breaks: bool,
+
+    /// Join headings that contain line breaks (DOCX soft returns, `<br>` in EPUB
+    /// headings) into one line, as markdown headings can't span lines. On by
+    /// default; pass `--strip-soft-returns-in-headings false` to keep the breaks
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set, num_args = 0..=1, default_missing_value = "true")]
+    pub strip_soft_returns_in_headings: bool,
+
+    /// Keep DOCX left indentation of paragraphs that aren't list items, one level per
+    /// half inch: `blockquote` nests them in `>` quotes, `spaces` indents their text
+    #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "blockquote")]
+    pub preserve_indent: Option<IndentStyle>,
+
+    /// Spaces per indent level with `--preserve-indent spaces`. Most renderers show
+    /// text indented four or more spaces as a code block
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 2,
+        requires = "preserve_indent"
+    )]
+    pub indent_width: usize,
+
+    /// Keep empty paragraphs as `&nbsp;` spacer lines (useful for poetry and spaced layouts)
+    #[arg(long, default_value_t = false)]
+    pub keep_empty_paragraphs: bool,
+
+    /// Name chapter files after their EPUB source documents (`ch01.xhtml` → `ch01.md`)
+    /// instead of `chapter-NN.md`
+    #[arg(long, default_value_t = false)]
+    pub keep_original_filenames: bool,
+
+    /// Open each EPUB chapter with a `<!-- source: OEBPS/ch03.xhtml (idref: ch03) -->`
+    /// comment naming the spine item it was converted from
+    #[arg(long, default_value_t = false)]
+    pub retain_source_ref: bool,
+
+    /// Write an Obsidian vault: chapter notes named after their titles, an `_index`
+    /// note linking them with `[[wikilinks]]`, and cross-references as wikilinks
+    #[arg(long, default_value_t = false, conflicts_with_all = ["single", "stdout"])]
+    pub obsidian: bool,
+
+    /// Build the folder-mode README from a template file. Placeholders: `{title}`,
+    /// `{author}`, `{publisher}`, `{language}`, `{date}`, `{rights}`, `{description}`,
+    /// `{cover}`, `{metadata}` (the standard header) and `{toc}` (the chapter list);
+    /// missing values are left blank, and a line holding only one is dropped
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["single", "stdout"])]
+    pub output_template: Option<PathBuf>,
+
+    /// Start each chapter with a `# Title` heading unless its content already opens with one
+    #[arg(long, default_value_t = false)]
+    pub chapter_heading: bool,
+
+    /// Shift all headings so the shallowest one becomes this level, keeping relative depth
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=6))]
+    pub min_heading_level: Option<u8>,
+
+    /// List only creators with the `aut` role in the header, leaving out editors,
+    /// translators and illustrators (all creators are kept when the book has no roles)
+    #[arg(long, alias = "author-role-filter", default_value_t = false)]
+    pub authors_only: bool,
+
+    /// Comma-separated metadata header fields to render, in this order
+    /// (e.g. `title,author,date`); unlisted fields are left out
+    #[arg(long, value_enum, value_delimiter = ',', value_name = "FIELDS")]
+    pub metadata_fields: Option<Vec<MetadataField>>,
+
+    /// Comma-separated DOCX custom document properties or document variables to add
+    /// to the metadata header (e.g. `Classification,Version`); names ignore case
+    #[arg(long, value_delimiter = ',', value_name = "NAMES")]
+    pub custom_properties: Vec<String>,
+
+    /// Add an estimated reading time to the metadata header
+    #[arg(long, default_value_t = false)]
+    pub emit_reading_time: bool,
+
+    /// Reading speed used by --emit-reading-time
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 200,
+        requires = "emit_reading_time"
+    )]
+    pub words_per_minute: usize,
+
+    /// Give every heading an explicit anchor derived from its text:
+    /// `attribute` appends `{#slug}`, `html` appends `<a id="slug"></a>`
+    #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "attribute")]
+    pub heading_anchors: Option<HeadingAnchors>,
+
+    /// Group runs of N or more consecutive images (default 3) into a
+    /// `<div class="gallery">` so they render side by side
+    #[arg(long, value_name = "N", num_args = 0..=1, default_missing_value = "3")]
+    pub gallery: Option<usize>,
+
+    /// Drop headings with no text (a bare `##`), left by styled but empty DOCX
+    /// paragraphs or stripped EPUB markup
+    #[arg(long, default_value_t = false)]
+    pub dedupe_blank_headings: bool,
+
+    /// Close skipped heading levels (`#` then `###` becomes `#` then `##`), keeping
+    /// the relative hierarchy, so each heading is at most one level below its parent
+    #[arg(long, default_value_t = false)]
+    pub normalize_headings: bool,
+
+    /// Turn list items into plain paragraphs prefixed with their bullet or number,
+    /// for targets that render nested lists poorly
+    #[arg(long, default_value_t = false)]
+    pub flatten_lists: bool,
+
+    /// Pad table columns to their widest cell so tables line up in the raw markdown
+    /// (wide CJK characters count as two columns)
+    #[arg(long, default_value_t = false)]
+    pub prettify_tables: bool,
+
+    /// Rework tables with more than N columns for narrow viewers: a single-row table
+    /// becomes a `Header: value` list, others are split into stacked tables of at
+    /// most N columns, each repeating the first column and its header cells
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(2..))]
+    pub max_table_width: Option<u16>,
+
+    /// Run adjacent footnote references together (`text[^1][^2].`), dropping the
+    /// spaces around them and before following punctuation
+    #[arg(long, default_value_t = false)]
+    pub collapse_footnote_refs: bool,
+
+    /// Turn EPUB footnote links shown as bracketed numbers (`[[12]](#fn12)`) into
+    /// `[^12]` references, and the linked number opening a note into its `[^12]:` definition
+    #[arg(long, default_value_t = false)]
+    pub trim_footnote_markers: bool,
+
+    /// How EPUB editorial `<ins>` / `<del>` markup is kept: `markdown` writes
+    /// `==inserted==` and `~~deleted~~`, `html` keeps the elements as inline HTML
+    #[arg(long, value_enum, default_value_t = EditMarkup::Markdown)]
+    pub edit_markup: EditMarkup,
+
+    /// Render all chapter content as a blockquote (for quoting source material in notes)
+    #[arg(long, default_value_t = false)]
+    pub blockquote_all: bool,
+
+    /// Keep DOCX text colors as `<span style="color:#RRGGBB">` (automatic/black text is left plain)
+    #[arg(long, default_value_t = false)]
+    pub preserve_color: bool,
+
+    /// Decode HTML entities (`&amp;`, `&#8217;`, …) left in the markdown, outside code.
+    /// On by default; pass `--convert-entities false` to keep them
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set, num_args = 0..=1, default_missing_value = "true")]
+    pub convert_entities: bool,
+
+    /// Strip `<script>` and `<style>` elements, `on*` event handlers and `javascript:`
+    /// URLs from HTML kept in the output (inline HTML in the markdown, `--output-format
+    /// html`). On by default; pass `--html-sanitize false` for trusted books
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set, num_args = 0..=1, default_missing_value = "true")]
+    pub html_sanitize: bool,
+
+    /// Escape literal markdown characters in text so `*`, `_`, `#`, `[` and backticks
+    /// in prose aren't read as formatting
+    #[arg(long, default_value_t = false)]
+    pub escape_markdown: bool,
+
+    /// Join EPUB emphasis spans that touch or are separated only by spaces,
+    /// e.g. `**foo** **bar**` → `**foo bar**`
+    #[arg(long, default_value_t = false)]
+    pub merge_adjacent_formatting: bool,
+
+    /// How DOCX endnotes are written: `collected` lists them under a trailing
+    /// "Endnotes" heading, `footnotes` numbers them along with the footnotes
+    #[arg(long, value_enum, default_value_t = Endnotes::Collected)]
+    pub endnotes: Endnotes,
+
+    /// List DOCX tracked insertions and deletions with their author and date under a
+    /// trailing "Revision History" heading
+    #[arg(long, default_value_t = false)]
+    pub changelog: bool,
+
+    /// Title chapters that have no title of their own after their opening sentence
+    /// (up to eight words) instead of "Chapter N"
+    #[arg(long, default_value_t = false)]
+    pub rename_untitled: bool,
+
+    /// Mark the print page positions listed in an EPUB's `page-list` nav in the
+    /// markdown, for cross-referencing a print edition
+    #[arg(long, default_value_t = false)]
+    pub page_anchors: bool,
+
+    /// How `--page-anchors` marks a page: an `<a id="page-42"></a>` anchor or a
+    /// `<!-- page 42 -->` comment
+    #[arg(long, value_enum, default_value_t = PageAnchorStyle::Anchor, requires = "page_anchors")]
+    pub page_anchor_style: PageAnchorStyle,
+
+    /// Keep DOCX optional hyphens as invisible soft hyphens (U+00AD) instead of
+    /// dropping them
+    #[arg(long, default_value_t = false)]
+    pub keep_soft_hyphens: bool,
+
+    /// Keep DOCX right-to-left direction: RTL paragraphs become `<div dir="rtl">` blocks
+    /// and runs against the paragraph direction get RLM/LRM marks
+    #[arg(long, default_value_t = false)]
+    pub preserve_direction: bool,
+
+    /// Package the folder-mode output into `<name>.zip` after writing
+    #[arg(long, default_value_t = false, conflicts_with_all = ["single", "stdout"])]
+    pub zip: bool,
+
+    /// With --zip, remove the output directory once the archive is written
+    #[arg(long, default_value_t = false, requires = "zip")]
+    pub zip_only: bool,
+
+    /// Show the cover image at the top of the folder-mode README
+    #[arg(long, default_value_t = false)]
+    pub cover_in_readme: bool,
+
+    /// Recover bold/italic that EPUB stylesheets apply through CSS classes on `<span>`s
+    #[arg(long, default_value_t = false)]
+    pub css_emphasis: bool,
+
+    /// Spell out `<abbr title>` abbreviations on first use, e.g. `WHO (World Health Organization)`
+    #[arg(long, default_value_t = false)]
+    pub expand_abbr: bool,
+
+    /// Keep the EPUB 3 navigation document (nav.xhtml) as a chapter
+    #[arg(long, default_value_t = false)]
+    pub keep_nav: bool,
+
+    /// Keep a hand-written contents page (a first chapter made up mostly of links
+    /// to other chapters), which is skipped by default like the nav document
+    #[arg(long, default_value_t = false)]
+    pub keep_manual_toc: bool,
+
+    /// Only convert EPUB chapters between the `bodymatter` and `backmatter` landmarks
+    #[arg(long, default_value_t = false)]
+    pub body_matter_only: bool,
+
+    /// Split DOCX output into one chapter per heading of this level or shallower (1-6).
+    /// Links to bookmarks in another chapter are rewritten to point at its file
+    #[arg(long, value_name = "LEVEL", value_parser = clap::value_parser!(u8).range(1..=6))]
+    pub split_by_heading: Option<u8>,
+
+    /// Convert the EPUB cover page as chapter one with its image, moving it to the
+    /// front and keeping it when --body-matter-only drops the rest of the front matter
+    #[arg(long, default_value_t = false)]
+    pub keep_cover_as_first_chapter: bool,
+
+    /// Convert fixed-layout (pre-paginated) EPUBs, whose output is usually near-empty
+    #[arg(long, default_value_t = false)]
+    pub force: bool,
+
+    /// Print the run summary to stdout as a JSON object instead of a sentence on stderr
+    #[arg(long, default_value_t = false, conflicts_with = "stdout")]
+    pub summary_json: bool,
+
+    /// Print progress to stderr as NDJSON events, one line per converted chapter and
+    /// written image: {"event":"chapter","index":3,"total":40}
+    #[arg(long, default_value_t = false)]
+    pub progress_json: bool,
+
+    /// Check the generated markdown with a CommonMark parser and warn on stderr about
+    /// unmatched emphasis, malformed tables and undefined link references
+    #[arg(long, default_value_t = false)]
+    pub strict_markdown: bool,
+
+    /// With --strict-markdown, fail without writing output when any warning is found
+    #[arg(long, default_value_t = false, requires = "strict_markdown")]
+    pub strict: bool,
+
+    /// Print extra diagnostics to stderr, including a per-chapter image report
+    #[arg(short, long, default_value_t = false)]
+    pub verbose: bool,
+
+    /// Line endings for written Markdown files
+    #[arg(long, value_enum, default_value_t = LineEnding::Lf)]
+    pub line_ending: LineEnding,
+
+    /// Separator inserted between chapters in single-file mode.
+    /// Special tokens: `hr` (horizontal rule), `pagebreak` (HTML page break), `none`
+    #[arg(long, default_value = "hr")]
+    pub chapter_separator: String,
+
+    /// Ignore `.epub2md.toml` in the working directory and the user config file
+    /// (`<config dir>/epub2md/config.toml`), using only the options given here
+    #[arg(long, default_value_t = false)]
+    pub no_config: bool,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InputFormat {
+    Epub,
+    Docx,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LineEnding {
+    Lf,
+    Crlf,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeadingAnchors {
+    /// kramdown/pandoc header attribute: `## Title {#title}`
+    Attribute,
+    /// Inline HTML anchor: `## Title <a id="title"></a>`
+    Html,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CatalogFormat {
+    /// `catalog.md`, a section per book
+    Markdown,
+    /// `catalog.json`, an array of book objects
+    Json,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PageAnchorStyle {
+    /// `<a id="page-42"></a>`, linkable as `#page-42`
+    Anchor,
+    /// `<!-- page 42 -->`, invisible when rendered
+    Comment,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EditMarkup {
+    /// `==inserted==` and `~~deleted~~`
+    #[default]
+    Markdown,
+    /// `<ins>inserted</ins>` and `<del>deleted</del>`
+    Html,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IndentStyle {
+    /// One `>` per indent level
+    Blockquote,
+    /// `--indent-width` leading spaces per indent level
+    Spaces,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Endnotes {
+    /// A separate "Endnotes" section at the end of the document
+    #[default]
+    Collected,
+    /// Markdown footnotes, numbered in one sequence with the footnotes
+    Footnotes,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MetadataField {
+    Title,
+    Author,
+    Publisher,
+    Language,
+    Date,
+    Rights,
+    Description,
+    /// Estimated reading time (needs --emit-reading-time)
+    ReadingTime,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Markdown,
+    Html,
 }
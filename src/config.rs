@@ -0,0 +1,212 @@
+//! Default options from `.epub2md.toml` files. The user config
+//! (`<config dir>/epub2md/config.toml`) is read first, then `.epub2md.toml` in the
+//! working directory; later values win and options given on the command line win
+//! over both. Keys are option names (`no_images` or `no-images`); `[epub]` and
+//! `[docx]` tables apply only when every input is of that format.
+//!
+//! Values are handed back to clap as arguments, so they're validated exactly
+//! like the command line. Options take strings, integers, floats, booleans and
+//! arrays of them.
+
+use crate::cli::{Cli, InputFormat};
+use anyhow::{bail, Context, Result};
+use clap::parser::ValueSource;
+use clap::{ArgAction, CommandFactory, FromArgMatches, Parser};
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml::{Table, Value};
+
+const PROJECT_FILE: &str = ".epub2md.toml";
+
+/// Options that make no sense as defaults
+const NOT_CONFIGURABLE: [&str; 4] = ["input", "no_config", "help", "version"];
+
+/// One `key = value` option, with the table it appeared under
+struct Entry {
+    table: Option<String>,
+    key: String,
+    value: Value,
+}
+
+/// Parse the command line, filling in options it doesn't set from the config files
+pub fn parse_args() -> Result<Cli> {
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let matches = Cli::command().get_matches_from(&args);
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if cli.no_config {
+        return Ok(cli);
+    }
+
+    let files: Vec<PathBuf> = [user_config_path(), Some(PathBuf::from(PROJECT_FILE))]
+        .into_iter()
+        .flatten()
+        .filter(|path| path.is_file())
+        .collect();
+    if files.is_empty() {
+        return Ok(cli);
+    }
+
+    let format = common_format(&cli);
+    let mut extra = Vec::new();
+    for path in &files {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file: {}", path.display()))?;
+        let entries = parse_toml(&text)
+            .with_context(|| format!("Failed to parse config file: {}", path.display()))?;
+        // A format's table overrides the top level of the same file
+        let (general, specific): (Vec<_>, Vec<_>) =
+            entries.into_iter().partition(|entry| entry.table.is_none());
+        for entry in general.into_iter().chain(specific) {
+            if let Some(ref table) = entry.table {
+                let known = ["epub", "docx"].contains(&table.as_str());
+                if !known {
+                    eprintln!(
+                        "Warning: {}: unknown table [{}], expected [epub] or [docx]",
+                        path.display(),
+                        table
+                    );
+                }
+                if !known || format != Some(table.as_str()) {
+                    continue;
+                }
+            }
+            let id = entry.key.replace('-', "_");
+            let args = match option_args(&id, &entry.value) {
+                Ok(args) => args,
+                Err(e) => {
+                    eprintln!("Warning: {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            // Command-line values win; a later file replaces an earlier one's value
+            if matches.value_source(&id) != Some(ValueSource::CommandLine) {
+                extra.retain(|(key, _)| *key != id);
+                extra.push((id, args));
+            }
+        }
+    }
+    if extra.is_empty() {
+        return Ok(cli);
+    }
+
+    // Config options go before any `--`, after everything else, so an option with an
+    // optional value can't take the input path as its value
+    let split = args.iter().position(|a| a == "--").unwrap_or(args.len());
+    let mut merged = args[..split].to_vec();
+    merged.extend(extra.into_iter().flat_map(|(_, args)| args));
+    merged.extend_from_slice(&args[split..]);
+
+    Ok(Cli::try_parse_from(&merged).unwrap_or_else(|e| {
+        eprintln!(
+            "Note: defaults were read from {}",
+            files
+                .iter()
+                .map(|p| p.display().to_string())
+                .collect::<Vec<_>>()
+                .join(" and ")
+        );
+        e.exit()
+    }))
+}
+
+/// `<config dir>/epub2md/config.toml`: `$XDG_CONFIG_HOME`, `~/.config` or `%APPDATA%`
+fn user_config_path() -> Option<PathBuf> {
+    let env_dir = |name: &str| {
+        std::env::var_os(name)
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from)
+    };
+    let dir = env_dir("XDG_CONFIG_HOME")
+        .or_else(|| env_dir("HOME").map(|home| home.join(".config")))
+        .or_else(|| env_dir("APPDATA"))?;
+    Some(dir.join("epub2md").join("config.toml"))
+}
+
+/// `epub` or `docx` when every input has that format
+fn common_format(cli: &Cli) -> Option<&'static str> {
+    let format_of = |input: &Path| match cli.format {
+        Some(InputFormat::Epub) => Some("epub"),
+        Some(InputFormat::Docx) => Some("docx"),
+        None => match input.extension()?.to_string_lossy().to_lowercase().as_str() {
+            "epub" => Some("epub"),
+            "docx" => Some("docx"),
+            _ => None,
+        },
+    };
+    let first = format_of(&cli.input[0])?;
+    cli.input
+        .iter()
+        .all(|input| format_of(input) == Some(first))
+        .then_some(first)
+}
+
+/// Command-line arguments setting option `id` to `value`
+fn option_args(id: &str, value: &Value) -> Result<Vec<OsString>> {
+    let command = Cli::command();
+    let arg = command
+        .get_arguments()
+        .find(|arg| arg.get_id() == id && !NOT_CONFIGURABLE.contains(&id))
+        .with_context(|| format!("unknown option `{}`", id))?;
+    let long = arg
+        .get_long()
+        .with_context(|| format!("unknown option `{}`", id))?;
+    let flag = matches!(arg.get_action(), ArgAction::SetTrue);
+
+    let values = match value {
+        Value::Array(items) => items.iter().collect(),
+        other => vec![other],
+    };
+    let mut args = Vec::new();
+    for value in values {
+        let text = match value {
+            Value::Boolean(true)
+                if flag || arg.get_num_args().is_some_and(|n| n.min_values() == 0) =>
+            {
+                args.push(format!("--{}", long).into());
+                continue;
+            }
+            Value::Boolean(false) if flag => continue,
+            Value::Boolean(b) => b.to_string(),
+            _ if flag => bail!("option `{}` takes true or false", id),
+            Value::String(text) => text.clone(),
+            Value::Integer(n) => n.to_string(),
+            Value::Float(n) if n.is_finite() => n.to_string(),
+            Value::Float(_) => bail!("option `{}` takes a finite number", id),
+            Value::Array(_) => bail!("nested arrays are not supported for `{}`", id),
+            Value::Table(_) | Value::Datetime(_) => {
+                bail!("option `{}` takes a string, number, boolean or array", id)
+            }
+        };
+        args.push(format!("--{}={}", long, text).into());
+    }
+    Ok(args)
+}
+
+/// Option entries of a config file, top level first, then each table's
+fn parse_toml(text: &str) -> Result<Vec<Entry>> {
+    let document: Table = toml::from_str(text)?;
+    let (tables, general): (Vec<_>, Vec<_>) = document
+        .into_iter()
+        .partition(|(_, value)| matches!(value, Value::Table(_)));
+
+    let mut entries: Vec<Entry> = general
+        .into_iter()
+        .map(|(key, value)| Entry {
+            table: None,
+            key,
+            value,
+        })
+        .collect();
+    for (name, table) in tables {
+        let Value::Table(table) = table else {
+            continue;
+        };
+        entries.extend(table.into_iter().map(|(key, value)| Entry {
+            table: Some(name.clone()),
+            key,
+            value,
+        }));
+    }
+    Ok(entries)
+}
@@ -1,147 +1,1204 @@
-use crate::cli::Cli;
+use crate::archive;
+use crate::batch;
+use crate::catalog::{self, CatalogEntry};
+use crate::cli::{CatalogFormat, Cli, InputFormat, LineEnding, OutputFormat};
 use crate::docx_reader::DocxData;
 use crate::epub_reader::EpubData;
-use crate::image::{self, ImageMap};
+use crate::html_output;
+use crate::html_preprocess;
+use crate::image::{self, ImageInfo, ImageMap, SizeLimit};
+use crate::json::json_string;
+use crate::markdown;
 use crate::metadata;
-use crate::reader::{BookReader, Chapter};
+use crate::reader::{BookReader, ConvertOptions, ImageResource, Metadata, NavEntry};
+use crate::validate;
 use anyhow::{bail, Context, Result};
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
 
-struct ConvertedChapter {
-    title: String,
-    filename: String,
-    content: String,
+/// Words kept of the opening sentence a `--rename-untitled` title is taken from
+const UNTITLED_WORDS: usize = 8;
+
+/// Written with `--output-per-image-manifest`
+const IMAGE_MANIFEST: &str = "images.json";
+
+/// A chapter ready to be written, with its output filename and final title
+pub struct ConvertedChapter {
+    pub title: String,
+    /// The source gave no title, so `title` is the chapter's number
+    pub untitled: bool,
+    pub filename: String,
+    pub content: String,
+    pub landmark: Option<String>,
+    /// Path of the source document within the book (EPUB spine href)
+    pub source_href: Option<String>,
+    /// `idref` of the EPUB spine item
+    pub source_idref: Option<String>,
+    /// Index and label of the input this chapter came from, when merging several inputs
+    pub source: Option<(usize, String)>,
+    /// Nesting of the chapter in the book's navigation, `0` for top level
+    pub nav_depth: usize,
+    /// Navigation group labels with no document of their own (e.g. "Part I") that
+    /// open right before this chapter, as `(depth, label)`, outermost first
+    pub nav_groups: Vec<(usize, String)>,
+}
+
+/// Everything produced by converting one input, before anything is written
+pub struct ConversionResult {
+    pub metadata: Metadata,
+    pub chapters: Vec<ConvertedChapter>,
+    /// Planned output paths of the images, keyed by original href
+    pub images: ImageMap,
+    /// Planned output path of the cover image, when the input has one
+    pub cover: Option<String>,
+    /// Image bytes to write at the paths in `images`
+    image_data: Vec<ImageResource>,
+}
+
+/// Where extracted images go, relative to the output base directory
+pub struct ImageLayout {
+    /// Write images beside the output files instead of an `images/` subdirectory
+    pub flat: bool,
+    /// Extra subdirectory for this input's images (used when merging inputs)
+    pub subdir: Option<String>,
+    /// Name images `image-001.ext`, … in the order the text first references them
+    pub numbered: bool,
+    /// Also copy audio and video files next to the images
+    pub media: bool,
+    /// Skip (or only warn about) images larger than this
+    pub size_limit: Option<SizeLimit>,
+    /// Glob patterns of decorative images to leave out
+    pub ignore: Vec<String>,
+}
+
+/// One converted input file, before its chapters are merged into the output
+struct Source {
+    label: String,
+    result: ConversionResult,
+}
+
+/// An `--output-template` and its metadata values; `{toc}` and `{cover}` are
+/// added once the chapters are written
+struct ReadmeTemplate {
+    text: String,
+    values: Vec<(&'static str, String)>,
 }
 
 pub fn convert(cli: &Cli) -> Result<()> {
-    let ext = cli
-        .input
-        .extension()
-        .map(|e| e.to_string_lossy().to_lowercase())
-        .unwrap_or_default();
+    let started = Instant::now();
+
+    // Catalogs take directories and skip conversion entirely
+    if cli.catalog {
+        return write_catalog(cli);
+    }
+    // Directories and patterns are converted file by file
+    if cli.input.iter().any(|input| batch::is_batch_input(input)) {
+        return batch::convert_batch(cli);
+    }
+    convert_inputs(cli, None, started)
+}
+
+/// Convert the inputs into one output. `asset_dir` gives a single input's images a
+/// subdirectory of their own, as each merged input gets
+pub fn convert_inputs(cli: &Cli, asset_dir: Option<&str>, started: Instant) -> Result<()> {
+    // Fail on an unsupported input before anything is written
+    for input in &cli.input {
+        input_format(cli, input)?;
+    }
 
     let output_path = resolve_output_path(cli)?;
+    let template = match cli.output_template {
+        Some(ref path) => Some(
+            fs::read_to_string(path)
+                .with_context(|| format!("Failed to read output template: {}", path.display()))?,
+        ),
+        None => None,
+    };
+
+    if cli.stdout && !cli.no_images {
+        eprintln!("Warning: images are not extracted when writing to stdout");
+    }
 
     // Resolve the images output dir:
     // - Folder mode: images go inside the output directory
-    // - Single mode: images go next to the output file
-    let images_base = if cli.single {
+    // - Single mode and HTML output: images go next to the output file
+    let images_base = if cli.single || cli.output_format == OutputFormat::Html {
         output_path
             .parent()
-            .unwrap_or_else(|| std::path::Path::new("."))
+            .unwrap_or_else(|| Path::new("."))
             .to_path_buf()
     } else {
         output_path.clone()
     };
 
-    // Dispatch based on file extension
+    if cli.output_format == OutputFormat::Html {
+        return match cli.input.as_slice() {
+            [input] if input_format(cli, input)? == InputFormat::Epub => {
+                let images_base = match asset_dir {
+                    Some(dir) => images_base.join(dir),
+                    None => images_base,
+                };
+                convert_epub_html(cli, input, &output_path, &images_base, asset_dir, started)
+            }
+            [_] => bail!("HTML output is only supported for EPUB input"),
+            _ => bail!("HTML output is only supported for a single EPUB input"),
+        };
+    }
+
+    // Merged inputs each get their own asset directory so image names can't collide
+    let merging = cli.input.len() > 1;
+    let mut used_dirs = HashSet::new();
+    let mut sources = Vec::new();
+    for input in &cli.input {
+        // Extract images unless --no-images (stdout has no location to anchor images/)
+        let layout = (!cli.no_images && !cli.stdout).then(|| ImageLayout {
+            flat: cli.flat_images,
+            subdir: merging
+                .then(|| unique_source_dir(input, &mut used_dirs))
+                .or_else(|| asset_dir.map(str::to_string)),
+            numbered: cli.retain_source_order_images,
+            media: cli.extract_media,
+            size_limit: size_limit(cli),
+            ignore: cli.ignore_images_matching.clone(),
+        });
+        let result = load_source(cli, input, layout.as_ref())?;
+        sources.push(Source {
+            label: source_label(input, &result.metadata),
+            result,
+        });
+    }
+
+    let reading_minutes = cli
+        .emit_reading_time
+        .then(|| reading_minutes(&sources, cli.words_per_minute));
+    let (metadata_header, cover) = match sources.as_slice() {
+        [source] => (
+            metadata::format_metadata(
+                &source.result.metadata,
+                reading_minutes,
+                cli.metadata_fields
+                    .as_deref()
+                    .unwrap_or(&metadata::DEFAULT_FIELDS),
+                &cli.custom_properties,
+            ),
+            resolve_cover_path(cli, &source.result),
+        ),
+        _ => {
+            let labeled: Vec<_> = sources
+                .iter()
+                .map(|s| (s.label.as_str(), &s.result.metadata))
+                .collect();
+            (
+                metadata::format_combined_metadata(&labeled, reading_minutes),
+                None,
+            )
+        }
+    };
+
+    // Merged output takes its template fields from the first input, like its name
+    let readme_template = template.map(|text| ReadmeTemplate {
+        text,
+        values: metadata::template_values(&sources[0].result.metadata, &metadata_header),
+    });
+
+    let converted = merge_chapters(cli, &sources);
+    if cli.verbose {
+        print_image_report(&converted, &sources);
+    }
+    if cli.strict_markdown {
+        let warnings = check_chapters(&converted);
+        if cli.strict && warnings > 0 {
+            bail!(
+                "{} markdown warning(s) found; nothing was written (--strict)",
+                warnings
+            );
+        }
+    }
+    let mut written = Vec::new();
+    let image_total = sources
+        .iter()
+        .map(|s| image::write_count(&s.result.image_data, &s.result.images))
+        .sum();
+    let images_done = AtomicUsize::new(0);
+    let on_written = |_| {
+        let index = images_done.fetch_add(1, Ordering::Relaxed) + 1;
+        progress_event(cli, "image", index, image_total);
+    };
+    for source in &sources {
+        if !source.result.images.is_empty() {
+            written.extend(image::write_images(
+                &source.result.image_data,
+                &source.result.images,
+                &images_base,
+                image_concurrency(cli),
+                &on_written,
+            )?);
+        }
+    }
+    if cli.output_per_image_manifest {
+        write_image_manifest(&images_base, &written)?;
+    }
+    let destinations = write_output(
+        cli,
+        &output_path,
+        &metadata_header,
+        &converted,
+        cover.as_deref(),
+        readme_template.as_ref(),
+    )?;
+
+    // Files written: skipped oversized images share one placeholder
+    let image_count = sources.iter().map(|s| s.result.image_data.len()).sum();
+    print_summary(cli, converted.len(), image_count, &destinations, started);
+
+    Ok(())
+}
+
+/// `--output-per-image-manifest`: `images.json` in the directory the image paths
+/// are relative to
+fn write_image_manifest(images_base: &Path, written: &[ImageInfo]) -> Result<()> {
+    let path = images_base.join(IMAGE_MANIFEST);
+    fs::write(&path, image::manifest_json(written))
+        .with_context(|| format!("Failed to write image manifest: {}", path.display()))
+}
+
+fn size_limit(cli: &Cli) -> Option<SizeLimit> {
+    cli.max_image_bytes.map(|max_bytes| SizeLimit {
+        max_bytes,
+        warn_only: cli.warn_large_images,
+    })
+}
+
+fn image_concurrency(cli: &Cli) -> usize {
+    cli.image_concurrency
+        .unwrap_or_else(image::default_concurrency)
+        .max(1)
+}
+
+/// Estimated minutes to read every chapter of every source, rounded up
+fn reading_minutes(sources: &[Source], words_per_minute: usize) -> usize {
+    let words: usize = sources
+        .iter()
+        .flat_map(|s| &s.result.chapters)
+        .map(|c| markdown::word_count(&c.content))
+        .sum();
+    words.div_ceil(words_per_minute.max(1)).max(1)
+}
+
+/// Convert an opened document entirely in memory. Nothing is written, so the
+/// chapters and planned image paths can be inspected without touching the filesystem.
+/// Images are skipped when `layout` is `None`.
+pub fn convert_book(
+    reader: &dyn BookReader,
+    options: &ConvertOptions,
+    layout: Option<&ImageLayout>,
+    on_chapter: &dyn Fn(usize, usize),
+) -> Result<ConversionResult> {
+    let mut metadata = reader.metadata();
+    if options.authors_only {
+        metadata.keep_primary_authors();
+    }
+
+    let mut ignored = HashSet::new();
+    let (image_data, images) = match layout {
+        Some(layout) => {
+            let mut data = reader.images()?;
+            ignored = image::ignored_images(&data, &layout.ignore);
+            data.retain(|img| !ignored.contains(&img.original_href));
+            let mut map = image::plan_images(&data, layout.flat);
+            if layout.numbered {
+                // First pass only discovers the order images are referenced in
+                let order: Vec<String> = reader
+                    .chapters(&map, options, &|_, _| {})?
+                    .iter()
+                    .flat_map(|chapter| markdown::image_references(&chapter.content))
+                    .collect();
+                map = image::number_images(&map, &order);
+            }
+            if let Some(limit) = layout.size_limit {
+                image::apply_size_limit(&mut data, &mut map, limit);
+            }
+            if layout.media {
+                // Media keep their own names even when images are numbered
+                let media = reader.media()?;
+                map.extend(image::plan_images(&media, layout.flat));
+                data.extend(media);
+            }
+            if let Some(ref subdir) = layout.subdir {
+                for path in map.values_mut() {
+                    *path = format!("{}/{}", subdir, path);
+                }
+            }
+            (data, map)
+        }
+        None => (Vec::new(), ImageMap::new()),
+    };
+
+    // References to ignored images are dropped rather than left dangling
+    let options = &ConvertOptions {
+        ignored_images: ignored,
+        ..options.clone()
+    };
+
+    // Image references are rewritten via the image map during conversion
+    let chapters = reader.chapters(&images, options, on_chapter)?;
+    let hrefs: Vec<Option<&str>> = chapters.iter().map(|c| c.source_href.as_deref()).collect();
+    let placement = nav_placement(&reader.nav(), &hrefs);
+    let chapters = chapters
+        .into_iter()
+        .zip(placement)
+        .enumerate()
+        .map(|(i, (chapter, (nav_depth, nav_groups)))| {
+            let title = chapter
+                .title
+                .or_else(|| extract_title_from_markdown(&chapter.content))
+                .or_else(|| {
+                    options
+                        .rename_untitled
+                        .then(|| markdown::title_from_content(&chapter.content, UNTITLED_WORDS))
+                        .flatten()
+                });
+            ConvertedChapter {
+                untitled: title.is_none(),
+                title: title.unwrap_or_else(|| untitled_title(i + 1)),
+                filename: format!("chapter-{:02}.md", i + 1),
+                content: chapter.content,
+                landmark: chapter.landmark,
+                source_href: chapter.source_href,
+                source_idref: chapter.source_idref,
+                source: None,
+                nav_depth,
+                nav_groups,
+            }
+        })
+        .collect();
+
+    // With images skipped the map is empty, so there is no cover either
+    let cover = reader
+        .cover_href()
+        .and_then(|href| images.get(&href).cloned());
+
+    Ok(ConversionResult {
+        metadata,
+        chapters,
+        images,
+        cover,
+        image_data,
+    })
+}
+
+/// Place each chapter (by source href) in the navigation tree: the depth of the
+/// first nav entry pointing at its document, and the group labels without a
+/// document that open before it. Chapters missing from the nav stay at the top level
+fn nav_placement(nav: &[NavEntry], hrefs: &[Option<&str>]) -> Vec<(usize, Vec<(usize, String)>)> {
+    let mut placement = vec![(0, Vec::new()); hrefs.len()];
+    let mut index = HashMap::new();
+    for (i, href) in hrefs.iter().enumerate() {
+        if let Some(href) = href {
+            index.entry(*href).or_insert(i);
+        }
+    }
+
+    let mut placed = HashSet::new();
+    let mut pending: Vec<(usize, String)> = Vec::new();
+    for entry in nav {
+        // Deeper labels that never got a chapter are closed by this entry
+        pending.retain(|(depth, _)| *depth < entry.depth);
+        match entry.href.as_deref().and_then(|href| index.get(href)) {
+            Some(&i) => {
+                // Later entries for a placed document are sections within it
+                if placed.insert(i) {
+                    placement[i] = (entry.depth, std::mem::take(&mut pending));
+                }
+            }
+            None => pending.push((entry.depth, entry.title.clone())),
+        }
+    }
+
+    placement
+}
+
+/// Format from `--format` when given, otherwise from the file extension
+pub fn input_format(cli: &Cli, input: &Path) -> Result<InputFormat> {
+    if let Some(format) = cli.format {
+        return Ok(format);
+    }
+
+    let ext = input
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
     match ext.as_str() {
-        "epub" => convert_epub(cli, &output_path, &images_base),
-        "docx" => convert_docx(cli, &output_path, &images_base),
+        "epub" => Ok(InputFormat::Epub),
+        "docx" => Ok(InputFormat::Docx),
         _ => bail!(
-            "Unsupported file format: .{}. Supported formats: .epub, .docx",
+            "Unsupported file format: .{}. Supported formats: .epub, .docx \
+             (use --format to override detection)",
             ext
         ),
     }
 }
 
-fn convert_epub(cli: &Cli, output_path: &PathBuf, images_base: &PathBuf) -> Result<()> {
-    let epub = EpubData::open(&cli.input)?;
-    let meta = epub.metadata();
-    let metadata_header = metadata::format_metadata(&meta);
+/// Asset subdirectory name for a merged input, deduplicated by file stem
+fn unique_source_dir(input: &Path, used: &mut HashSet<String>) -> String {
+    let stem = input
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_else(|| "source".to_string());
+
+    let mut candidate = stem.clone();
+    let mut suffix = 1;
+    while !used.insert(candidate.clone()) {
+        suffix += 1;
+        candidate = format!("{}-{}", stem, suffix);
+    }
+    candidate
+}
+
+/// Section label for a merged input: its title, falling back to the file stem
+fn source_label(input: &Path, metadata: &Metadata) -> String {
+    metadata
+        .title
+        .clone()
+        .filter(|t| !t.trim().is_empty())
+        .or_else(|| input.file_stem().map(|s| s.to_string_lossy().to_string()))
+        .unwrap_or_else(|| input.display().to_string())
+}
 
-    // Extract images unless --no-images
-    let image_map = if !cli.no_images {
+fn convert_epub_html(
+    cli: &Cli,
+    input: &Path,
+    output_path: &Path,
+    images_base: &Path,
+    asset_dir: Option<&str>,
+    started: Instant,
+) -> Result<()> {
+    let epub = open_epub(input)?;
+    check_layout(cli, input, &epub)?;
+    let mut meta = epub.metadata();
+    if cli.authors_only {
+        meta.keep_primary_authors();
+    }
+
+    let image_map = if !cli.no_images && !cli.stdout {
         fs::create_dir_all(images_base)?;
-        image::extract_images(&epub, images_base)?
+        let images_done = AtomicUsize::new(0);
+        let (image_map, written) = image::extract_images(
+            &epub,
+            images_base,
+            cli.flat_images,
+            size_limit(cli),
+            &cli.ignore_images_matching,
+            image_concurrency(cli),
+            &|total| {
+                let index = images_done.fetch_add(1, Ordering::Relaxed) + 1;
+                progress_event(cli, "image", index, total);
+            },
+        )?;
+        if cli.output_per_image_manifest {
+            write_image_manifest(images_base, &written)?;
+        }
+        match asset_dir {
+            // Images were written under the asset directory, next to the output file
+            Some(dir) => image_map
+                .into_iter()
+                .map(|(href, path)| (href, format!("{}/{}", dir, path)))
+                .collect(),
+            None => image_map,
+        }
     } else {
         ImageMap::new()
     };
 
-    // EPUB needs image map for path rewriting during html→md conversion
-    let chapters = epub.convert_chapters(&image_map)?;
-
-    let converted = build_converted_chapters(&chapters)?;
-    write_output(cli, output_path, &metadata_header, &converted)?;
-    print_summary(&converted, &image_map, output_path);
-
+    let sections = epub.html_sections(&image_map, &convert_options(cli), &|index, total| {
+        progress_event(cli, "chapter", index, total)
+    })?;
+    let destinations = if cli.stdout {
+        let html = html_output::render_html(&meta, &sections, cli.with_css);
+        std::io::stdout()
+            .write_all(html.as_bytes())
+            .context("Failed to write to stdout")?;
+        Vec::new()
+    } else {
+        html_output::write_html(output_path, &meta, &sections, cli.with_css)?;
+        vec![output_path.to_path_buf()]
+    };
+    print_summary(cli, sections.len(), image_map.len(), &destinations, started);
     Ok(())
 }
 
-fn convert_docx(cli: &Cli, output_path: &PathBuf, images_base: &PathBuf) -> Result<()> {
-    let docx = DocxData::open(&cli.input)?;
-    let meta = docx.metadata();
-    let metadata_header = metadata::format_metadata(&meta);
+/// `--catalog`: read each book's metadata and cover and write one listing of them.
+/// A book that can't be read is reported and left out
+fn write_catalog(cli: &Cli) -> Result<()> {
+    let inputs: Vec<PathBuf> = batch::expand_inputs(cli)?
+        .into_iter()
+        .map(|input| input.path)
+        .collect();
+    let output_dir = match cli.output {
+        Some(ref path) => path.clone(),
+        None if cli.temp => create_temp_dir()?.join("catalog"),
+        None => PathBuf::from("catalog"),
+    };
+    let with_covers = !cli.no_images && !cli.stdout;
+    if cli.stdout && !cli.no_images {
+        eprintln!("Warning: covers are not extracted when writing to stdout");
+    }
 
-    // Extract images unless --no-images
-    let image_map = if !cli.no_images {
-        fs::create_dir_all(images_base)?;
-        image::extract_images(&docx, images_base)?
+    let mut used_names = HashSet::new();
+    let mut entries = Vec::new();
+    let mut covers = 0;
+    for input in &inputs {
+        let entry = catalog_entry(cli, input, with_covers, &output_dir, &mut used_names);
+        match entry {
+            Ok(entry) => {
+                covers += usize::from(entry.cover.is_some());
+                entries.push(entry);
+            }
+            Err(e) => eprintln!("Warning: skipping {}: {:#}", input.display(), e),
+        }
+    }
+
+    let (content, file_name) = match cli.catalog_format {
+        CatalogFormat::Markdown => (catalog::render_markdown(&entries), "catalog.md"),
+        CatalogFormat::Json => (catalog::render_json(&entries), "catalog.json"),
+    };
+    let content = apply_line_ending(&content, cli.line_ending);
+    let destination = output_dir.join(file_name);
+    if cli.stdout {
+        std::io::stdout()
+            .write_all(content.as_bytes())
+            .context("Failed to write to stdout")?;
     } else {
-        ImageMap::new()
+        fs::create_dir_all(&output_dir).with_context(|| {
+            format!(
+                "Failed to create output directory: {}",
+                output_dir.display()
+            )
+        })?;
+        fs::write(&destination, content)
+            .with_context(|| format!("Failed to write output file: {}", destination.display()))?;
+    }
+
+    eprintln!(
+        "Cataloged {} book{}{} to {}",
+        entries.len(),
+        if entries.len() == 1 { "" } else { "s" },
+        if covers > 0 {
+            format!(
+                " with {} cover{}",
+                covers,
+                if covers == 1 { "" } else { "s" }
+            )
+        } else {
+            String::new()
+        },
+        if cli.stdout {
+            "stdout".to_string()
+        } else {
+            destination.display().to_string()
+        }
+    );
+    if cli.temp {
+        println!("{}", destination.display());
+    }
+    Ok(())
+}
+
+/// Catalog entry for one input; its cover is copied to `covers/` under `output_dir`
+/// when `with_covers`
+fn catalog_entry(
+    cli: &Cli,
+    input: &Path,
+    with_covers: bool,
+    output_dir: &Path,
+    used_names: &mut HashSet<String>,
+) -> Result<CatalogEntry> {
+    let reader: Box<dyn BookReader> = match input_format(cli, input)? {
+        InputFormat::Epub => Box::new(open_epub(input)?),
+        InputFormat::Docx => Box::new(open_docx(input)?),
     };
+    let mut metadata = reader.metadata();
+    if cli.authors_only {
+        metadata.keep_primary_authors();
+    }
+
+    let mut cover = None;
+    if with_covers {
+        if let Some(image) = reader.cover_image()? {
+            let href = image.original_href.clone();
+            let extension = Path::new(html_preprocess::file_name(&href))
+                .extension()
+                .map(|e| format!(".{}", e.to_string_lossy()))
+                .unwrap_or_default();
+            let path = format!(
+                "covers/{}{}",
+                unique_source_dir(input, used_names),
+                extension
+            );
+            let map = ImageMap::from([(href, path.clone())]);
+            image::write_images(&[image], &map, output_dir, 1, &|_| {})?;
+            cover = Some(path);
+        }
+    }
+
+    Ok(CatalogEntry {
+        file: input.display().to_string(),
+        title: source_label(input, &metadata),
+        authors: metadata
+            .authors
+            .into_iter()
+            .filter(|a| !a.trim().is_empty())
+            .collect(),
+        description: metadata.description.filter(|d| !d.trim().is_empty()),
+        cover,
+    })
+}
+
+/// Open one input and convert it in memory
+fn load_source(cli: &Cli, input: &Path, layout: Option<&ImageLayout>) -> Result<ConversionResult> {
+    let options = convert_options(cli);
+    let on_chapter = |index, total| progress_event(cli, "chapter", index, total);
+    match input_format(cli, input)? {
+        InputFormat::Epub => {
+            let epub = open_epub(input)?;
+            check_layout(cli, input, &epub)?;
+            convert_book(&epub, &options, layout, &on_chapter)
+        }
+        InputFormat::Docx => convert_book(&open_docx(input)?, &options, layout, &on_chapter),
+    }
+}
 
-    // DOCX chapters already have image paths set during conversion
-    let chapters = docx.chapters()?;
+/// Open an EPUB from disk or from inside a zip (`archive.zip!inner/book.epub`)
+fn open_epub(input: &Path) -> Result<EpubData> {
+    match archive::split_entry_path(input) {
+        Some((zip, entry)) => EpubData::from_bytes(archive::read_entry(&zip, &entry)?, input),
+        None => EpubData::open(input),
+    }
+}
 
-    let converted = build_converted_chapters(&chapters)?;
-    write_output(cli, output_path, &metadata_header, &converted)?;
-    print_summary(&converted, &image_map, output_path);
+/// Open a DOCX from disk or from inside a zip (`archive.zip!inner/doc.docx`)
+fn open_docx(input: &Path) -> Result<DocxData> {
+    match archive::split_entry_path(input) {
+        Some((zip, entry)) => DocxData::from_bytes(archive::read_entry(&zip, &entry)?, input),
+        None => DocxData::open(input),
+    }
+}
 
+/// Fixed-layout (pre-paginated) books are positioned page images and text
+/// fragments, so they convert to near-empty chapters; refuse unless forced
+fn check_layout(cli: &Cli, input: &Path, epub: &EpubData) -> Result<()> {
+    if !epub.is_fixed_layout() {
+        return Ok(());
+    }
+    if !cli.force {
+        bail!(
+            "{} is a fixed-layout EPUB, which does not convert meaningfully to Markdown. \
+             Use --force to convert it anyway",
+            input.display()
+        );
+    }
+    eprintln!(
+        "Warning: {} is a fixed-layout EPUB; the output will likely be incomplete",
+        input.display()
+    );
     Ok(())
 }
 
-fn build_converted_chapters(chapters: &[Chapter]) -> Result<Vec<ConvertedChapter>> {
+fn convert_options(cli: &Cli) -> ConvertOptions {
+    ConvertOptions {
+        image_alt_from_filename: cli.image_alt_from_filename,
+        keep_empty_paragraphs: cli.keep_empty_paragraphs,
+        keep_nav: cli.keep_nav,
+        inline_embedded: cli.inline_embedded,
+        preserve_color: cli.preserve_color,
+        body_matter_only: cli.body_matter_only,
+        keep_cover_as_first_chapter: cli.keep_cover_as_first_chapter,
+        split_by_heading: cli.split_by_heading,
+        image_placeholder: cli.image_placeholder,
+        collapse_table_whitespace: cli.collapse_whitespace_in_tables,
+        heading_slugs: cli.heading_anchors.is_some(),
+        css_emphasis: cli.css_emphasis,
+        expand_abbr: cli.expand_abbr,
+        preserve_direction: cli.preserve_direction,
+        keep_soft_hyphens: cli.keep_soft_hyphens,
+        page_anchors: cli.page_anchors.then_some(cli.page_anchor_style),
+        escape_markdown: cli.escape_markdown,
+        merge_adjacent_formatting: cli.merge_adjacent_formatting,
+        trim_footnote_markers: cli.trim_footnote_markers,
+        convert_entities: cli.convert_entities,
+        html_sanitize: cli.html_sanitize,
+        cell_break: cli.cell_break.clone(),
+        authors_only: cli.authors_only,
+        rename_untitled: cli.rename_untitled,
+        endnotes: cli.endnotes,
+        changelog: cli.changelog,
+        // Filled in per input once its images have been read
+        ignored_images: HashSet::new(),
+        keep_manual_toc: cli.keep_manual_toc,
+        preserve_line_breaks: cli.preserve_line_breaks,
+        edit_markup: cli.edit_markup,
+        strip_soft_returns_in_headings: cli.strip_soft_returns_in_headings,
+        preserve_indent: cli.preserve_indent,
+        indent_width: cli.indent_width,
+    }
+}
+
+/// Number chapters across all sources and apply output-only rewrites;
+/// source labels are only kept when merging
+fn merge_chapters(cli: &Cli, sources: &[Source]) -> Vec<ConvertedChapter> {
     let mut converted = Vec::new();
+    let merging = sources.len() > 1;
+    let mut anchors = HashMap::new();
+    // README.md (or the Obsidian index note) is written beside the chapters in folder mode
+    let index = if cli.obsidian {
+        "_index.md"
+    } else {
+        "readme.md"
+    };
+    let mut used_filenames = HashSet::from([index.to_string()]);
 
-    for (i, chapter) in chapters.iter().enumerate() {
-        let title = chapter
-            .title
-            .clone()
-            .or_else(|| extract_title_from_markdown(&chapter.content))
-            .unwrap_or_else(|| format!("Chapter {}", i + 1));
-
-        let filename = format!("chapter-{:02}.md", i + 1);
-
-        converted.push(ConvertedChapter {
-            title,
-            filename,
-            content: chapter.content.clone(),
+    // Shift relative to the shallowest heading anywhere in the output
+    let heading_shift = cli.min_heading_level.and_then(|target| {
+        let shallowest = sources
+            .iter()
+            .flat_map(|s| &s.result.chapters)
+            .filter_map(|c| markdown::min_heading_level(&c.content))
+            .min()?;
+        Some(target as isize - shallowest as isize)
+    });
+
+    for (index, source) in sources.iter().enumerate() {
+        for chapter in &source.result.chapters {
+            // Numbered across all sources, like the untitled chapters' titles
+            let number = converted.len() + 1;
+            let title = if chapter.untitled {
+                untitled_title(number)
+            } else {
+                chapter.title.clone()
+            };
+            let numbered = format!("chapter-{:02}.md", number);
+            let note = markdown::note_name(&title);
+            let filename = match chapter.source_href.as_deref() {
+                _ if cli.obsidian && !note.is_empty() => {
+                    unique_chapter_filename(&format!("{}.md", note), &mut used_filenames)
+                }
+                Some(href) if cli.keep_original_filenames => {
+                    unique_chapter_filename(href, &mut used_filenames)
+                }
+                _ => unique_chapter_filename(&numbered, &mut used_filenames),
+            };
+
+            let mut content = chapter.content.clone();
+            if cli.dedupe_blank_headings {
+                content = markdown::remove_empty_headings(&content);
+            }
+            if let Some(shift) = heading_shift.filter(|s| *s != 0) {
+                content = markdown::shift_headings(&content, shift);
+            }
+            if cli.chapter_heading {
+                content = prepend_chapter_heading(&content, &title, cli.min_heading_level);
+            }
+            if cli.normalize_headings {
+                content = markdown::normalize_heading_levels(&content);
+            }
+            if let Some(style) = cli.heading_anchors {
+                content = markdown::add_heading_anchors(&content, style, &mut anchors);
+            }
+            if let Some(min) = cli.gallery {
+                content = markdown::gallery(&content, min);
+            }
+            if cli.flatten_lists {
+                content = markdown::flatten_lists(&content);
+            }
+            if let Some(max) = cli.max_table_width {
+                content = markdown::wrap_wide_tables(&content, max as usize);
+            }
+            if cli.prettify_tables {
+                content = markdown::prettify_tables(&content);
+            }
+            if cli.collapse_footnote_refs {
+                content = markdown::collapse_footnote_refs(&content);
+            }
+            if cli.blockquote_all {
+                content = markdown::blockquote(&content);
+            }
+            if cli.retain_source_ref {
+                if let Some(ref href) = chapter.source_href {
+                    content = prepend_source_ref(&content, href, chapter.source_idref.as_deref());
+                }
+            }
+
+            converted.push(ConvertedChapter {
+                title,
+                untitled: chapter.untitled,
+                filename,
+                content,
+                landmark: chapter.landmark.clone(),
+                source_href: chapter.source_href.clone(),
+                source_idref: chapter.source_idref.clone(),
+                source: merging.then(|| (index, source.label.clone())),
+                nav_depth: chapter.nav_depth,
+                nav_groups: chapter.nav_groups.clone(),
+            });
+        }
+    }
+
+    if cli.obsidian {
+        link_notes(&mut converted);
+    } else {
+        link_chapters(&mut converted, cli.single || cli.stdout);
+    }
+
+    converted
+}
+
+/// Point links between chapters of the same input at the chapter's output file,
+/// matching link targets against each chapter's source document. Combined output
+/// keeps only the fragment, which is in the same file
+fn link_chapters(chapters: &mut [ConvertedChapter], combined: bool) {
+    let files = by_source_document(chapters, |chapter| chapter.filename.clone());
+
+    for chapter in chapters.iter_mut() {
+        let Some(files) = files.get(&source_index(chapter)) else {
+            continue;
+        };
+        let own = chapter.filename.clone();
+        chapter.content = markdown::retarget_links(&chapter.content, |target| {
+            if target.contains("://") || target.starts_with('#') {
+                return None;
+            }
+            let (document, fragment) = match target.split_once('#') {
+                Some((document, fragment)) => (document, Some(fragment)),
+                None => (target, None),
+            };
+            let file = files.get(
+                html_preprocess::percent_decode(html_preprocess::file_name(document)).as_ref(),
+            )?;
+            match fragment {
+                Some(fragment) if combined || *file == own => Some(format!("#{}", fragment)),
+                None if combined => None,
+                Some(fragment) => Some(format!("{}#{}", file, fragment)),
+                None => Some(file.clone()),
+            }
         });
     }
+}
+
+/// Rewrite links between chapters of the same input as `[[Note]]` wikilinks,
+/// matching link targets against each chapter's source document
+fn link_notes(chapters: &mut [ConvertedChapter]) {
+    let notes = by_source_document(chapters, |chapter| note_of(chapter).to_string());
+
+    for chapter in chapters.iter_mut() {
+        if let Some(notes) = notes.get(&source_index(chapter)) {
+            chapter.content = markdown::wikilinks(&chapter.content, notes);
+        }
+    }
+}
+
+/// For each input, `value` of every chapter keyed by the decoded filename of its
+/// source document, which is how links between chapters name their target
+fn by_source_document(
+    chapters: &[ConvertedChapter],
+    value: impl Fn(&ConvertedChapter) -> String,
+) -> HashMap<usize, HashMap<String, String>> {
+    let mut documents: HashMap<usize, HashMap<String, String>> = HashMap::new();
+    for chapter in chapters {
+        if let Some(ref href) = chapter.source_href {
+            documents.entry(source_index(chapter)).or_default().insert(
+                html_preprocess::percent_decode(html_preprocess::file_name(href)).into_owned(),
+                value(chapter),
+            );
+        }
+    }
+    documents
+}
+
+/// Index of the input a chapter came from; `0` when there is only one
+fn source_index(chapter: &ConvertedChapter) -> usize {
+    chapter.source.as_ref().map_or(0, |(i, _)| *i)
+}
+
+/// Obsidian note name of a chapter: its filename without `.md`
+fn note_of(chapter: &ConvertedChapter) -> &str {
+    chapter
+        .filename
+        .strip_suffix(".md")
+        .unwrap_or(&chapter.filename)
+}
+
+/// Start `content` with a heading for `title` unless it already opens with one
+fn prepend_chapter_heading(content: &str, title: &str, level: Option<u8>) -> String {
+    let has_title = markdown::leading_heading(content)
+        .is_some_and(|heading| heading.trim().eq_ignore_ascii_case(title.trim()));
+    if has_title {
+        return content.to_string();
+    }
+    let hashes = "#".repeat(level.unwrap_or(1) as usize);
+    format!(
+        "{} {}\n\n{}",
+        hashes,
+        title,
+        content.trim_start_matches('\n')
+    )
+}
+
+/// Open `content` with a comment naming the source document and its spine idref
+fn prepend_source_ref(content: &str, href: &str, idref: Option<&str>) -> String {
+    // `--` can't appear inside an HTML comment
+    let href = href.trim_start_matches('/').replace("--", "-&#45;");
+    let comment = match idref {
+        Some(idref) => format!(
+            "<!-- source: {} (idref: {}) -->",
+            href,
+            idref.replace("--", "-&#45;")
+        ),
+        None => format!("<!-- source: {} -->", href),
+    };
+    format!("{}\n\n{}", comment, content.trim_start_matches('\n'))
+}
+
+/// `<stem>.md` for a source path, suffixed `-2`, `-3`, … when already taken
+fn unique_chapter_filename(source: &str, used: &mut HashSet<String>) -> String {
+    let stem = Path::new(source)
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .filter(|s| !s.is_empty())
+        .unwrap_or_else(|| "chapter".to_string());
+
+    let mut candidate = format!("{}.md", stem);
+    let mut suffix = 1;
+    // Compare case-insensitively so output stays valid on case-insensitive filesystems
+    while !used.insert(candidate.to_lowercase()) {
+        suffix += 1;
+        candidate = format!("{}-{}.md", stem, suffix);
+    }
+    candidate
+}
+
+/// Print the markdown validation warnings of every chapter and return how many there were
+fn check_chapters(chapters: &[ConvertedChapter]) -> usize {
+    let mut count = 0;
+    for chapter in chapters {
+        for issue in validate::check_markdown(&chapter.content) {
+            eprintln!(
+                "Warning: {}:{}: {}",
+                chapter.filename, issue.line, issue.message
+            );
+            count += 1;
+        }
+    }
+    count
+}
+
+/// List each chapter's image references and whether they point at an extracted
+/// image, then any extracted images no chapter references
+fn print_image_report(chapters: &[ConvertedChapter], sources: &[Source]) {
+    let extracted: HashSet<&str> = sources
+        .iter()
+        .flat_map(|s| s.result.images.values())
+        .map(|p| p.as_str())
+        .collect();
+    let mut referenced = HashSet::new();
+
+    eprintln!("Image report:");
+    for chapter in chapters {
+        let refs = markdown::image_references(&chapter.content);
+        if refs.is_empty() {
+            continue;
+        }
+        eprintln!("  {} ({}):", chapter.filename, chapter.title);
+        for target in refs {
+            let status = if extracted.contains(target.as_str()) {
+                "ok"
+            } else {
+                "missing"
+            };
+            eprintln!("    [{}] {}", status, target);
+            referenced.insert(target);
+        }
+    }
 
-    Ok(converted)
+    let mut orphans: Vec<_> = extracted
+        .iter()
+        .filter(|path| !referenced.contains(**path))
+        .collect();
+    if !orphans.is_empty() {
+        orphans.sort();
+        eprintln!("  Extracted but not referenced by any chapter:");
+        for path in orphans {
+            eprintln!("    {}", path);
+        }
+    }
 }
 
+/// Relative path of the extracted cover image, if `--cover-in-readme` applies
+fn resolve_cover_path(cli: &Cli, result: &ConversionResult) -> Option<String> {
+    if !cli.cover_in_readme || cli.single || cli.stdout {
+        return None;
+    }
+    result.cover.clone()
+}
+
+/// Write the converted chapters and return where they went: the single file or
+/// its numbered parts, the output folder, or the archive when zipping. Nothing is
+/// returned for stdout
 fn write_output(
     cli: &Cli,
-    output_path: &PathBuf,
+    output_path: &Path,
     metadata_header: &str,
     converted: &[ConvertedChapter],
-) -> Result<()> {
-    if cli.single {
-        write_single_file(output_path, metadata_header, converted)?;
+    cover: Option<&str>,
+    readme_template: Option<&ReadmeTemplate>,
+) -> Result<Vec<PathBuf>> {
+    if cli.stdout {
+        let separator = resolve_chapter_separator(&cli.chapter_separator);
+        let content = render_single_file(metadata_header, converted, &separator);
+        let content = apply_line_ending(&content, cli.line_ending);
+        std::io::stdout()
+            .write_all(content.as_bytes())
+            .context("Failed to write to stdout")?;
+        Ok(Vec::new())
+    } else if cli.single {
+        let separator = resolve_chapter_separator(&cli.chapter_separator);
+        write_single_file(
+            output_path,
+            metadata_header,
+            converted,
+            &separator,
+            cli.line_ending,
+            cli.split_size,
+        )
+    } else {
+        write_folder(
+            output_path,
+            metadata_header,
+            converted,
+            cover,
+            readme_template,
+            cli.obsidian,
+            cli.line_ending,
+        )?;
+
+        if cli.zip {
+            archive::zip_directory(output_path)?;
+            if cli.zip_only {
+                fs::remove_dir_all(output_path).with_context(|| {
+                    format!("Failed to remove directory: {}", output_path.display())
+                })?;
+            }
+            return Ok(vec![archive::archive_path(output_path)]);
+        }
+        Ok(vec![output_path.to_path_buf()])
+    }
+}
+
+fn format_size(bytes: u64) -> String {
+    if bytes < 1024 {
+        format!("{} B", bytes)
+    } else if bytes < 1024 * 1024 {
+        format!("{:.1} KB", bytes as f64 / 1024.0)
     } else {
-        write_folder(output_path, metadata_header, converted)?;
+        format!("{:.1} MB", bytes as f64 / (1024.0 * 1024.0))
     }
-    Ok(())
 }
 
-fn print_summary(converted: &[ConvertedChapter], image_map: &ImageMap, output_path: &PathBuf) {
-    let chapter_count = converted.len();
-    let image_count = image_map.len();
+/// `--progress-json`: one NDJSON line on stderr per finished chapter or image
+fn progress_event(cli: &Cli, event: &str, index: usize, total: usize) {
+    if cli.progress_json {
+        eprintln!(
+            "{{\"event\":{},\"index\":{},\"total\":{}}}",
+            json_string(event),
+            index,
+            total
+        );
+    }
+}
+
+/// Report the run; `destinations` are the paths `write_output` wrote
+fn print_summary(
+    cli: &Cli,
+    chapter_count: usize,
+    image_count: usize,
+    destinations: &[PathBuf],
+    started: Instant,
+) {
+    if cli.summary_json {
+        let output = destinations
+            .first()
+            .map(|path| path.display().to_string())
+            .unwrap_or_default();
+        println!(
+            "{{\"chapters\":{},\"images\":{},\"output\":{},\"bytes\":{},\"elapsed_ms\":{}}}",
+            chapter_count,
+            image_count,
+            json_string(&output),
+            destinations
+                .iter()
+                .map(|path| output_size(path))
+                .sum::<u64>(),
+            started.elapsed().as_millis()
+        );
+        return;
+    }
+
     eprintln!(
         "Converted {} chapter{}{} to {}",
         chapter_count,
         if chapter_count == 1 { "" } else { "s" },
         if image_count > 0 {
-            format!(" and {} image{}", image_count, if image_count == 1 { "" } else { "s" })
+            format!(
+                " and {} image{}",
+                image_count,
+                if image_count == 1 { "" } else { "s" }
+            )
         } else {
             String::new()
         },
-        output_path.display()
+        describe_destination(cli, destinations)
     );
+
+    // The caller picks the temp location up from stdout
+    if cli.temp {
+        for path in destinations {
+            println!("{}", path.display());
+        }
+    }
+}
+
+/// Size of a file, or the total size of the files under a directory
+fn output_size(path: &Path) -> u64 {
+    let Ok(meta) = fs::metadata(path) else {
+        return 0;
+    };
+    if !meta.is_dir() {
+        return meta.len();
+    }
+    fs::read_dir(path)
+        .map(|entries| {
+            entries
+                .flatten()
+                .map(|entry| output_size(&entry.path()))
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+fn describe_destination(cli: &Cli, destinations: &[PathBuf]) -> String {
+    match destinations {
+        [] => "stdout".to_string(),
+        [archive] if cli.zip => match fs::metadata(archive) {
+            Ok(meta) => format!("{} ({})", archive.display(), format_size(meta.len())),
+            Err(_) => archive.display().to_string(),
+        },
+        [path] => path.display().to_string(),
+        // Output over --split-size is written as numbered parts
+        [first, ..] => format!("{} parts ({} …)", destinations.len(), first.display()),
+    }
 }
 
 fn resolve_output_path(cli: &Cli) -> Result<PathBuf> {
@@ -149,17 +1206,61 @@ fn resolve_output_path(cli: &Cli) -> Result<PathBuf> {
         return Ok(path.clone());
     }
 
-    let stem = cli
-        .input
+    // Merged output is named after the first input
+    let stem = cli.input[0]
         .file_stem()
         .context("Input file has no name")?
         .to_string_lossy();
 
-    if cli.single {
-        Ok(PathBuf::from(format!("{}.md", stem)))
+    let name = if cli.output_format == OutputFormat::Html {
+        format!("{}.html", stem)
+    } else if cli.single {
+        format!("{}.md", stem)
+    } else {
+        stem.to_string()
+    };
+
+    if cli.temp {
+        // A fresh directory keeps single-file output and its images together
+        Ok(create_temp_dir()?.join(name))
     } else {
-        Ok(PathBuf::from(stem.as_ref()))
+        Ok(PathBuf::from(name))
+    }
+}
+
+/// Create a uniquely named directory under the system temp directory
+pub fn create_temp_dir() -> Result<PathBuf> {
+    let base = std::env::temp_dir();
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or_default();
+
+    for attempt in 0..100 {
+        let dir = base.join(format!(
+            "epub2md-{}-{:08x}-{}",
+            std::process::id(),
+            nanos,
+            attempt
+        ));
+        match fs::create_dir(&dir) {
+            Ok(()) => return Ok(dir),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to create temp directory: {}", dir.display()))
+            }
+        }
     }
+    bail!(
+        "Failed to create a unique temp directory in {}",
+        base.display()
+    )
+}
+
+/// Title of a chapter the source leaves untitled
+fn untitled_title(number: usize) -> String {
+    format!("Chapter {}", number)
 }
 
 fn extract_title_from_markdown(md: &str) -> Option<String> {
@@ -175,67 +1276,445 @@ fn extract_title_from_markdown(md: &str) -> Option<String> {
     None
 }
 
+/// Expand special separator tokens; any other value is used verbatim
+fn resolve_chapter_separator(value: &str) -> String {
+    match value {
+        "hr" => "---".to_string(),
+        "pagebreak" => "<div style=\"page-break-after: always\"></div>".to_string(),
+        "none" => String::new(),
+        other => other.replace("\\n", "\n"),
+    }
+}
+
 fn write_single_file(
-    output_path: &PathBuf,
+    output_path: &Path,
     metadata_header: &str,
     chapters: &[ConvertedChapter],
-) -> Result<()> {
+    separator: &str,
+    line_ending: LineEnding,
+    split_size: Option<u64>,
+) -> Result<Vec<PathBuf>> {
+    let content = render_single_file(metadata_header, chapters, separator);
+    let content = apply_line_ending(&content, line_ending);
+
+    if let Some(parent) = output_path.parent() {
+        if !parent.as_os_str().is_empty() {
+            fs::create_dir_all(parent)?;
+        }
+    }
+
+    let max_bytes = split_size.map(|kb| kb.saturating_mul(1024) as usize);
+    if let Some(max_bytes) = max_bytes.filter(|&max| content.len() > max) {
+        let parts = split_single_file(metadata_header, chapters, separator, line_ending, max_bytes);
+        let mut written = Vec::with_capacity(parts.len());
+        for (i, part) in parts.iter().enumerate() {
+            let path = part_path(output_path, i + 1);
+            fs::write(&path, part)
+                .with_context(|| format!("Failed to write output file: {}", path.display()))?;
+            written.push(path);
+        }
+        return Ok(written);
+    }
+
+    fs::write(output_path, &content)
+        .with_context(|| format!("Failed to write output file: {}", output_path.display()))?;
+
+    Ok(vec![output_path.to_path_buf()])
+}
+
+/// Convert LF to CRLF when requested, normalizing first so existing CRLF isn't doubled
+fn apply_line_ending(content: &str, line_ending: LineEnding) -> String {
+    match line_ending {
+        LineEnding::Lf => content.to_string(),
+        LineEnding::Crlf => content.replace("\r\n", "\n").replace('\n', "\r\n"),
+    }
+}
+
+/// Combine the metadata header and all chapters into one markdown document
+fn render_single_file(
+    metadata_header: &str,
+    chapters: &[ConvertedChapter],
+    separator: &str,
+) -> String {
     let mut content = String::new();
 
     content.push_str(metadata_header);
 
-    for (i, chapter) in chapters.iter().enumerate() {
+    for i in 0..chapters.len() {
         if i > 0 {
-            content.push_str("\n---\n\n");
+            content.push_str(&chapter_joiner(separator));
         }
-        content.push_str(&chapter.content);
-        content.push('\n');
+        content.push_str(&chapter_block(chapters, i));
     }
 
-    if let Some(parent) = output_path.parent() {
-        if !parent.as_os_str().is_empty() {
-            fs::create_dir_all(parent)?;
+    content
+}
+
+/// Break the single-file document into parts of at most `max_bytes` (after line
+/// ending conversion), only between chapters. The metadata header opens the first
+/// part; a chapter larger than the limit gets a part of its own
+fn split_single_file(
+    metadata_header: &str,
+    chapters: &[ConvertedChapter],
+    separator: &str,
+    line_ending: LineEnding,
+    max_bytes: usize,
+) -> Vec<String> {
+    let joiner = apply_line_ending(&chapter_joiner(separator), line_ending);
+    let mut parts = Vec::new();
+    let mut part = apply_line_ending(metadata_header, line_ending);
+    let mut part_chapters = 0;
+
+    for i in 0..chapters.len() {
+        let block = apply_line_ending(&chapter_block(chapters, i), line_ending);
+        if part_chapters > 0 {
+            if part.len() + joiner.len() + block.len() > max_bytes {
+                parts.push(std::mem::take(&mut part));
+                part_chapters = 0;
+            } else {
+                part.push_str(&joiner);
+            }
         }
+        part.push_str(&block);
+        part_chapters += 1;
     }
 
-    fs::write(output_path, &content)
-        .with_context(|| format!("Failed to write output file: {}", output_path.display()))?;
+    parts.push(part);
+    parts
+}
 
-    Ok(())
+/// Text between two chapters in single-file output
+fn chapter_joiner(separator: &str) -> String {
+    if separator.is_empty() {
+        "\n".to_string()
+    } else {
+        format!("\n{}\n\n", separator)
+    }
+}
+
+/// A chapter as it appears in single-file output
+fn chapter_block(chapters: &[ConvertedChapter], i: usize) -> String {
+    let mut block = String::new();
+    // Each merged input opens with a top-level section heading
+    if let Some(source) = new_source(chapters, i) {
+        block.push_str(&format!("# {}\n\n", source));
+    }
+    block.push_str(&chapters[i].content);
+    block.push('\n');
+    block
+}
+
+/// `book.md` → `book-part-01.md`, beside the requested output file
+fn part_path(output_path: &Path, part: usize) -> PathBuf {
+    let stem = output_path
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_else(|| "book".to_string());
+    let name = match output_path.extension() {
+        Some(ext) => format!("{}-part-{:02}.{}", stem, part, ext.to_string_lossy()),
+        None => format!("{}-part-{:02}", stem, part),
+    };
+    output_path.with_file_name(name)
 }
 
 fn write_folder(
-    output_dir: &PathBuf,
+    output_dir: &Path,
     metadata_header: &str,
     chapters: &[ConvertedChapter],
+    cover: Option<&str>,
+    template: Option<&ReadmeTemplate>,
+    obsidian: bool,
+    line_ending: LineEnding,
 ) -> Result<()> {
     fs::create_dir_all(output_dir)?;
+    let index_name = if obsidian { "_index.md" } else { "README.md" };
 
     // Write chapter files
     for chapter in chapters {
         let path = output_dir.join(&chapter.filename);
-        fs::write(&path, &chapter.content)
+        fs::write(&path, apply_line_ending(&chapter.content, line_ending))
             .with_context(|| format!("Failed to write chapter: {}", path.display()))?;
     }
 
-    // Write README.md with metadata and table of contents
+    // A one-entry table of contents is noise; just link the lone chapter
+    let toc = match chapters {
+        [chapter] => format!("{}\n", toc_link(chapter, obsidian)),
+        _ => toc_list(chapters, obsidian),
+    };
+
+    if let Some(template) = template {
+        let cover = cover
+            .map(|cover| format!("![cover]({})", cover))
+            .unwrap_or_default();
+        let mut values = template.values.clone();
+        values.push(("toc", toc.trim_end().to_string()));
+        values.push(("cover", cover));
+        let readme = metadata::fill_template(&template.text, &values);
+        return write_readme(output_dir, index_name, &readme, line_ending);
+    }
+
+    // Write README.md (or the `_index` note) with metadata and table of contents
     let mut readme = String::new();
+    if let Some(cover) = cover {
+        readme.push_str(&format!("![cover]({})\n\n", cover));
+    }
     readme.push_str(metadata_header);
-    readme.push_str("## Table of Contents\n\n");
+    if chapters.len() == 1 {
+        readme.push_str(&toc);
+    } else {
+        readme.push_str("## Table of Contents\n\n");
+        readme.push_str(&toc);
+        readme.push('\n');
+    }
 
+    write_readme(output_dir, index_name, &readme, line_ending)
+}
+
+/// Numbered list linking every chapter, nested following the book's navigation
+fn toc_list(chapters: &[ConvertedChapter], obsidian: bool) -> String {
+    let mut toc = String::new();
+    // Item numbers per nesting level
+    let mut counters = Vec::new();
     for (i, chapter) in chapters.iter().enumerate() {
-        readme.push_str(&format!(
-            "{}. [{}]({})\n",
-            i + 1,
-            chapter.title,
-            chapter.filename
-        ));
+        if let Some(source) = new_source(chapters, i) {
+            if i > 0 {
+                toc.push('\n');
+            }
+            toc.push_str(&format!("### {}\n\n", source));
+        }
+        for (depth, label) in &chapter.nav_groups {
+            toc.push_str(&toc_item(&mut counters, *depth));
+            toc.push_str(label);
+            toc.push('\n');
+        }
+        toc.push_str(&toc_item(&mut counters, chapter.nav_depth));
+        toc.push_str(&toc_link(chapter, obsidian));
+        // Label front/back matter; body chapters need no tag
+        match chapter.landmark.as_deref() {
+            Some("bodymatter") | None => {}
+            Some(landmark) => toc.push_str(&format!(" *({})*", landmark)),
+        }
+        toc.push('\n');
+    }
+    toc
+}
+
+/// Indented number for the next table of contents item at `depth`, which is
+/// clamped to one level below the previous item so the nested list stays valid
+fn toc_item(counters: &mut Vec<usize>, depth: usize) -> String {
+    let depth = depth.min(counters.len());
+    counters.truncate(depth + 1);
+    if counters.len() == depth {
+        counters.push(0);
     }
+    counters[depth] += 1;
+    // Four spaces nest under markers up to `99. `
+    format!("{}{}. ", "    ".repeat(depth), counters[depth])
+}
 
-    readme.push('\n');
+/// Table of contents link to a chapter: relative markdown link, or a wikilink for Obsidian
+fn toc_link(chapter: &ConvertedChapter, obsidian: bool) -> String {
+    if obsidian {
+        markdown::wikilink(note_of(chapter), &chapter.title)
+    } else {
+        format!("[{}]({})", chapter.title, chapter.filename)
+    }
+}
 
-    fs::write(output_dir.join("README.md"), &readme)
-        .with_context(|| "Failed to write README.md")?;
+fn write_readme(
+    output_dir: &Path,
+    name: &str,
+    readme: &str,
+    line_ending: LineEnding,
+) -> Result<()> {
+    fs::write(
+        output_dir.join(name),
+        apply_line_ending(readme, line_ending),
+    )
+    .with_context(|| format!("Failed to write {}", name))?;
 
     Ok(())
 }
+
+/// Source label when chapter `i` is the first one from its merged input
+fn new_source(chapters: &[ConvertedChapter], i: usize) -> Option<&str> {
+    let (index, label) = chapters[i].source.as_ref()?;
+    if i > 0 && chapters[i - 1].source.as_ref().map(|(prev, _)| prev) == Some(index) {
+        return None;
+    }
+    Some(label)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use std::io::Cursor;
+    use zip::write::SimpleFileOptions;
+    use zip::ZipWriter;
+
+    /// A minimal EPUB 3 package with the given `(file, body)` chapters in the
+    /// spine and one image, `images/fig one.png`
+    fn epub_bytes(chapters: &[(&str, &str)]) -> Vec<u8> {
+        let manifest: String = chapters
+            .iter()
+            .enumerate()
+            .map(|(i, (file, _))| {
+                format!(
+                    r#"<item id="c{}" href="{}" media-type="application/xhtml+xml"/>"#,
+                    i, file
+                )
+            })
+            .collect();
+        let spine: String = (0..chapters.len())
+            .map(|i| format!(r#"<itemref idref="c{}"/>"#, i))
+            .collect();
+        let opf = format!(
+            r#"<?xml version="1.0"?><package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="id"><metadata xmlns:dc="http://purl.org/dc/elements/1.1/"><dc:identifier id="id">test</dc:identifier><dc:title>Test Book</dc:title><dc:language>en</dc:language></metadata><manifest><item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/><item id="fig" href="images/fig%20one.png" media-type="image/png"/>{}</manifest><spine>{}</spine></package>"#,
+            manifest, spine
+        );
+
+        let mut parts = vec![
+            ("mimetype".to_string(), "application/epub+zip".to_string()),
+            (
+                "META-INF/container.xml".to_string(),
+                r#"<?xml version="1.0"?><container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container"><rootfiles><rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/></rootfiles></container>"#.to_string(),
+            ),
+            ("OEBPS/content.opf".to_string(), opf),
+            (
+                "OEBPS/nav.xhtml".to_string(),
+                r#"<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops"><body><nav epub:type="toc"><ol></ol></nav></body></html>"#.to_string(),
+            ),
+            ("OEBPS/images/fig one.png".to_string(), "not really a png".to_string()),
+        ];
+        for (file, body) in chapters {
+            parts.push((
+                format!("OEBPS/{}", file),
+                format!(
+                    r#"<html xmlns="http://www.w3.org/1999/xhtml"><head><title></title></head><body>{}</body></html>"#,
+                    body
+                ),
+            ));
+        }
+
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, content) in parts {
+            zip.start_file(name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(content.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    fn layout() -> ImageLayout {
+        ImageLayout {
+            flat: false,
+            subdir: None,
+            numbered: false,
+            media: false,
+            size_limit: None,
+            ignore: Vec::new(),
+        }
+    }
+
+    fn convert_in_memory(chapters: &[(&str, &str)]) -> ConversionResult {
+        let epub = EpubData::from_bytes(epub_bytes(chapters), Path::new("test.epub")).unwrap();
+        convert_book(
+            &epub,
+            &ConvertOptions::default(),
+            Some(&layout()),
+            &|_, _| {},
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn convert_book_returns_chapters_and_planned_images() {
+        let result = convert_in_memory(&[
+            (
+                "one.xhtml",
+                r#"<p>See the figure.</p><p><img src="images/fig one.png" alt="Figure"/></p>"#,
+            ),
+            ("two.xhtml", "<h1>Second</h1><p>Text</p>"),
+        ]);
+
+        assert_eq!(result.metadata.title.as_deref(), Some("Test Book"));
+        let files: Vec<_> = result
+            .chapters
+            .iter()
+            .map(|c| c.filename.as_str())
+            .collect();
+        assert_eq!(files, ["chapter-01.md", "chapter-02.md"]);
+        assert_eq!(
+            result.chapters[1].source_href.as_deref(),
+            Some("/OEBPS/two.xhtml")
+        );
+        assert!(result.chapters[0]
+            .content
+            .contains("![Figure](images/fig_one.png)"));
+
+        assert_eq!(result.images.len(), 1);
+        assert_eq!(
+            result.images.values().next().map(String::as_str),
+            Some("images/fig_one.png")
+        );
+        assert_eq!(result.image_data.len(), 1);
+    }
+
+    #[test]
+    fn untitled_chapters_are_numbered_across_merged_inputs() {
+        let cli = Cli::parse_from(["epub2md", "a.epub", "b.epub"]);
+        let sources: Vec<Source> = ["a", "b"]
+            .into_iter()
+            .map(|label| Source {
+                label: label.to_string(),
+                result: convert_in_memory(&[
+                    ("one.xhtml", "<p>No heading here.</p>"),
+                    ("two.xhtml", "<p>Nor here.</p>"),
+                ]),
+            })
+            .collect();
+
+        let merged = merge_chapters(&cli, &sources);
+        let chapters: Vec<_> = merged
+            .iter()
+            .map(|c| (c.filename.as_str(), c.title.as_str()))
+            .collect();
+        assert_eq!(
+            chapters,
+            [
+                ("chapter-01.md", "Chapter 1"),
+                ("chapter-02.md", "Chapter 2"),
+                ("chapter-03.md", "Chapter 3"),
+                ("chapter-04.md", "Chapter 4"),
+            ]
+        );
+    }
+
+    #[test]
+    fn links_find_chapters_with_encoded_source_names() {
+        let chapter = |filename: &str, href: &str, content: &str| ConvertedChapter {
+            title: String::new(),
+            untitled: false,
+            filename: filename.to_string(),
+            content: content.to_string(),
+            landmark: None,
+            source_href: Some(href.to_string()),
+            source_idref: None,
+            source: None,
+            nav_depth: 0,
+            nav_groups: Vec::new(),
+        };
+        let mut chapters = [
+            chapter(
+                "one.md",
+                "/OEBPS/one.xhtml",
+                "[Next](part%20two.xhtml#start)",
+            ),
+            chapter("two.md", "/OEBPS/part%20two.xhtml", "[Back](one.xhtml)"),
+        ];
+
+        link_chapters(&mut chapters, false);
+        assert_eq!(chapters[0].content, "[Next](two.md#start)");
+        assert_eq!(chapters[1].content, "[Back](one.md)");
+    }
+}
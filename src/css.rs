@@ -0,0 +1,118 @@
+//! Minimal stylesheet reading for recovering emphasis that EPUBs express only
+//! through CSS classes. Only simple class selectors and the `font-weight` /
+//! `font-style` / `font` properties are understood.
+
+use std::collections::HashMap;
+
+/// Emphasis a class sets; `None` means the class leaves that property alone
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Emphasis {
+    pub bold: Option<bool>,
+    pub italic: Option<bool>,
+}
+
+/// Class name → emphasis implied by the rules that target it
+pub type ClassEmphasis = HashMap<String, Emphasis>;
+
+/// Add the class rules in `css` to `classes`; later rules override earlier ones
+pub fn read_class_emphasis(css: &str, classes: &mut ClassEmphasis) {
+    let css = strip_comments(css);
+
+    for rule in css.split('}') {
+        let Some((prefix, declarations)) = rule.rsplit_once('{') else {
+            continue;
+        };
+        // Rules nested in @media blocks follow the block's own `{`; at-rules
+        // with declarations (@font-face, @page) are skipped
+        let selectors = prefix.rsplit('{').next().unwrap_or(prefix);
+        if selectors.trim_start().starts_with('@') {
+            continue;
+        }
+
+        let emphasis = parse_declarations(declarations);
+        if emphasis == Emphasis::default() {
+            continue;
+        }
+
+        for selector in selectors.split(',') {
+            if let Some(class) = target_class(selector) {
+                let entry = classes.entry(class.to_string()).or_default();
+                entry.bold = emphasis.bold.or(entry.bold);
+                entry.italic = emphasis.italic.or(entry.italic);
+            }
+        }
+    }
+}
+
+fn strip_comments(css: &str) -> String {
+    let mut result = String::with_capacity(css.len());
+    let mut rest = css;
+    while let Some(start) = rest.find("/*") {
+        result.push_str(&rest[..start]);
+        rest = match rest[start..].find("*/") {
+            Some(end) => &rest[start + end + 2..],
+            None => "",
+        };
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Class of the element a selector styles: `.b`, `span.b`, `p .b` → `b`.
+/// Selectors with pseudo-classes, attributes or several classes are skipped.
+fn target_class(selector: &str) -> Option<&str> {
+    let last = selector
+        .split(|c: char| c.is_whitespace() || matches!(c, '>' | '+' | '~'))
+        .rfind(|s| !s.is_empty())?;
+    let (_, class) = last.split_once('.')?;
+    let valid = !class.is_empty()
+        && class
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_');
+    valid.then_some(class)
+}
+
+fn parse_declarations(declarations: &str) -> Emphasis {
+    let mut emphasis = Emphasis::default();
+
+    for declaration in declarations.split(';') {
+        let Some((property, value)) = declaration.split_once(':') else {
+            continue;
+        };
+        let value = value.replace("!important", "").trim().to_ascii_lowercase();
+        match property.trim().to_ascii_lowercase().as_str() {
+            "font-weight" => emphasis.bold = weight_is_bold(&value),
+            "font-style" => emphasis.italic = style_is_italic(&value),
+            "font" => {
+                // Shorthand: only the style and weight keywords matter here
+                for token in value.split_whitespace() {
+                    if let Some(bold) = weight_is_bold(token).filter(|b| *b) {
+                        emphasis.bold = Some(bold);
+                    }
+                    if let Some(italic) = style_is_italic(token).filter(|i| *i) {
+                        emphasis.italic = Some(italic);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    emphasis
+}
+
+fn weight_is_bold(value: &str) -> Option<bool> {
+    match value {
+        "bold" | "bolder" => Some(true),
+        "normal" | "lighter" => Some(false),
+        _ => value.parse::<u32>().ok().map(|weight| weight >= 600),
+    }
+}
+
+fn style_is_italic(value: &str) -> Option<bool> {
+    match value {
+        "italic" | "oblique" => Some(true),
+        "normal" => Some(false),
+        _ => None,
+    }
+}
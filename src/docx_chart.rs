@@ -0,0 +1,178 @@
+//! Cached data of DOCX charts (`word/charts/chartN.xml`). Word stores the values
+//! a chart was last drawn with beside the chart definition, so the data can be
+//! recovered without the embedded spreadsheet.
+
+use crate::docx_xml::attribute;
+use crate::html_preprocess::decode_entities;
+
+/// A chart's title and data series, as cached in its chart part
+#[derive(Debug, Default)]
+pub struct Chart {
+    pub title: Option<String>,
+    /// Category (x axis) labels shared by the series
+    pub categories: Vec<String>,
+    pub series: Vec<Series>,
+}
+
+#[derive(Debug, Default)]
+pub struct Series {
+    pub name: Option<String>,
+    pub values: Vec<String>,
+}
+
+/// Read the title and cached series data from a chart part
+pub fn parse_chart(xml: &str) -> Chart {
+    // Axis titles come after the plot area starts; only the chart title precedes it
+    let head = xml.split("<c:plotArea").next().unwrap_or(xml);
+    let title = elements(head, "c:title")
+        .first()
+        .map(|title| {
+            elements(title, "a:t")
+                .iter()
+                .map(|t| decode_entities(t))
+                .collect::<String>()
+        })
+        .filter(|t| !t.trim().is_empty());
+
+    let mut chart = Chart {
+        title,
+        ..Default::default()
+    };
+
+    for ser in elements(xml, "c:ser") {
+        let name = elements(ser, "c:tx")
+            .first()
+            .and_then(|tx| elements(tx, "c:v").first().map(|v| decode_entities(v)));
+        // Scatter and bubble charts use x/y values instead of categories/values
+        let categories = ["c:cat", "c:xVal"]
+            .iter()
+            .find_map(|tag| elements(ser, tag).first().map(|cat| points(cat)))
+            .unwrap_or_default();
+        let values = ["c:val", "c:yVal"]
+            .iter()
+            .find_map(|tag| elements(ser, tag).first().map(|val| points(val)))
+            .unwrap_or_default();
+
+        if chart.categories.is_empty() {
+            chart.categories = categories;
+        }
+        chart.series.push(Series { name, values });
+    }
+
+    chart
+}
+
+/// Render a chart as a caption and a table with one row per category
+pub fn chart_table(chart: &Chart) -> Option<String> {
+    let rows = chart
+        .series
+        .iter()
+        .map(|s| s.values.len())
+        .chain([chart.categories.len()])
+        .max()
+        .unwrap_or(0);
+    if chart.series.is_empty() || rows == 0 {
+        return None;
+    }
+
+    let mut md = match chart.title {
+        Some(ref title) => format!("*Chart: {}*\n\n", cell(title)),
+        None => "*Chart*\n\n".to_string(),
+    };
+
+    let mut header = vec!["Category".to_string()];
+    for (i, series) in chart.series.iter().enumerate() {
+        header.push(match series.name {
+            Some(ref name) => cell(name),
+            None => format!("Series {}", i + 1),
+        });
+    }
+    md.push_str(&format!("| {} |\n", header.join(" | ")));
+    md.push_str(&format!("|{}\n", " --- |".repeat(header.len())));
+
+    for row in 0..rows {
+        let mut cells = vec![chart
+            .categories
+            .get(row)
+            .map(|c| cell(c))
+            .unwrap_or_else(|| (row + 1).to_string())];
+        for series in &chart.series {
+            cells.push(series.values.get(row).map(|v| cell(v)).unwrap_or_default());
+        }
+        md.push_str(&format!("| {} |\n", cells.join(" | ")));
+    }
+
+    Some(md)
+}
+
+/// Escape a value for use inside a table cell
+pub fn cell(text: &str) -> String {
+    text.trim().replace('|', "\\|").replace('\n', " ")
+}
+
+/// Values of the `c:pt` points in a string or number cache, placed by their index
+fn points(xml: &str) -> Vec<String> {
+    let mut values = Vec::new();
+    let count = attribute(xml, "<c:ptCount", "val").and_then(|v| v.parse::<usize>().ok());
+    if let Some(count) = count {
+        values.resize(count, String::new());
+    }
+
+    let mut rest = xml;
+    while let Some(start) = rest.find("<c:pt ") {
+        let Some(len) = rest[start..].find("</c:pt>") else {
+            break;
+        };
+        let end = start + len + "</c:pt>".len();
+        let point = &rest[start..end];
+        let value = elements(point, "c:v")
+            .first()
+            .map(|v| decode_entities(v))
+            .unwrap_or_default();
+        let index = attribute(point, "<c:pt", "idx").and_then(|i| i.parse::<usize>().ok());
+        match index {
+            Some(i) if i < 10_000 => {
+                if values.len() <= i {
+                    values.resize(i + 1, String::new());
+                }
+                values[i] = value;
+            }
+            _ => values.push(value),
+        }
+        rest = &rest[end..];
+    }
+
+    values
+}
+
+/// Inner content of every `<tag>…</tag>` element, outermost first
+fn elements<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+    let open = format!("<{}", tag);
+    let close = format!("</{}>", tag);
+    let mut found = Vec::new();
+    let mut rest = xml;
+
+    while let Some(start) = rest.find(&open) {
+        let after = &rest[start + open.len()..];
+        // Skip longer tag names (`<c:txPr>` when looking for `<c:tx>`)
+        if !after.starts_with(['>', ' ', '/']) {
+            rest = after;
+            continue;
+        }
+        let Some(open_len) = after.find('>') else {
+            break;
+        };
+        if after[..open_len].ends_with('/') {
+            rest = &after[open_len + 1..];
+            continue;
+        }
+        let content = &after[open_len + 1..];
+        let Some(end) = content.find(&close) else {
+            break;
+        };
+        found.push(&content[..end]);
+        rest = &content[end + close.len()..];
+    }
+
+    found
+}
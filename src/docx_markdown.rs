@@ -1,32 +1,155 @@
+use crate::cli::{Endnotes, IndentStyle};
+use crate::docx_chart;
+use crate::docx_preprocess::{
+    EmbeddedContent, CHART_PLACEHOLDER, CHECKED_BOX, OBJECT_PLACEHOLDER, REPEATING_ITEM_GALLERY,
+    UNCHECKED_BOX,
+};
+use crate::docx_worksheet;
 use crate::image::ImageMap;
+use crate::markdown;
+use crate::reader::ConvertOptions;
 use docx_rust::document::{
-    BodyContent, ParagraphContent, RunContent, TableCellContent, TableRowContent,
+    BodyContent, CharType, ParagraphContent, RunContent, TableCellContent, TableRowContent, SDT,
 };
 use docx_rust::formatting::CharacterProperty;
 use docx_rust::Docx;
+use std::collections::HashMap;
 
 /// Convert a parsed DOCX document body to markdown
-pub fn docx_to_markdown(docx: &Docx, image_map: &ImageMap) -> String {
+pub fn docx_to_markdown(
+    docx: &Docx,
+    image_map: &ImageMap,
+    options: &ConvertOptions,
+    embedded: &EmbeddedContent,
+) -> String {
     let mut ctx = ConvertContext {
         docx,
         image_map,
+        options,
         output: String::new(),
-        list_counters: std::collections::HashMap::new(),
+        list_counters: HashMap::new(),
+        heading_numbers: HeadingNumbering::default(),
+        fields: Vec::new(),
+        heading_bookmarks: if options.heading_slugs {
+            heading_bookmarks(docx, &docx.document.body.content)
+        } else {
+            HashMap::new()
+        },
+        rtl_paragraph: false,
+        tab: " ".repeat(tab_width(docx)),
+        notes: Vec::new(),
     };
 
     for content in &docx.document.body.content {
         ctx.convert_body_content(content);
     }
+    ctx.convert_notes();
 
-    ctx.output
+    ctx.restore_embedded(embedded)
+}
+
+/// The part of a document split before headings of `level` or shallower that each
+/// link anchor (bookmark, or heading slug with heading slugs enabled) falls in.
+/// Part 0 is whatever precedes the first such heading
+pub fn anchor_sections(docx: &Docx, options: &ConvertOptions, level: u8) -> HashMap<String, usize> {
+    fn walk(
+        content: &[BodyContent],
+        level: u8,
+        section: &mut usize,
+        out: &mut HashMap<String, usize>,
+    ) {
+        for item in content {
+            match item {
+                BodyContent::Paragraph(para) => {
+                    if heading_level(para).is_some_and(|l| l <= level) {
+                        *section += 1;
+                    }
+                    for pc in &para.content {
+                        if let ParagraphContent::BookmarkStart(bookmark) = pc {
+                            if let Some(ref name) = bookmark.name {
+                                out.insert(name.to_string(), *section);
+                            }
+                        }
+                    }
+                }
+                BodyContent::Sdt(sdt) => {
+                    if let Some(ref sdt_content) = sdt.content {
+                        walk(&sdt_content.content, level, section, out);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let content = &docx.document.body.content;
+    let mut sections = HashMap::new();
+    walk(content, level, &mut 0, &mut sections);
+    if options.heading_slugs {
+        for (bookmark, slug) in heading_bookmarks(docx, content) {
+            if let Some(&section) = sections.get(&bookmark) {
+                sections.insert(slug, section);
+            }
+        }
+    }
+    sections
 }
 
 struct ConvertContext<'a> {
     docx: &'a Docx<'a>,
     image_map: &'a ImageMap,
+    options: &'a ConvertOptions,
     output: String,
     /// Track numbering counters: (num_id, level) -> current count
-    list_counters: std::collections::HashMap<(isize, isize), usize>,
+    list_counters: HashMap<(isize, isize), usize>,
+    /// Counters of the multilevel lists that number headings
+    heading_numbers: HeadingNumbering,
+    /// Stack of open complex fields (`w:fldChar` begin … end), which may span paragraphs
+    fields: Vec<FieldState>,
+    /// Bookmark name → slug of the heading it sits on (only with heading slugs enabled)
+    heading_bookmarks: HashMap<String, String>,
+    /// Whether the paragraph being collected is right-to-left (only with direction preserved)
+    rtl_paragraph: bool,
+    /// Spaces a tab expands to, from the document's default tab stop
+    tab: String,
+    /// Footnotes and endnotes in the order they are first referenced
+    notes: Vec<NoteRef>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum NoteKind {
+    Footnote,
+    Endnote,
+}
+
+/// A referenced footnote or endnote, converted once the body is done
+struct NoteRef {
+    kind: NoteKind,
+    id: isize,
+    /// Number shown at the reference
+    label: usize,
+    /// Rendered as a markdown footnote; endnotes are unless collected separately
+    as_footnote: bool,
+}
+
+/// A complex field being walked between its `begin` and `end` field characters
+#[derive(Default)]
+struct FieldState {
+    /// Accumulated `w:instrText`, e.g. `HYPERLINK "https://example.com"`
+    instruction: String,
+    /// Resolved once the `separate` character is reached
+    kind: Option<FieldKind>,
+    /// Buffered cached result for fields rendered when they end (HYPERLINK)
+    result: String,
+}
+
+enum FieldKind {
+    /// HYPERLINK field with its resolved target
+    Hyperlink(String),
+    /// PAGEREF / PAGE / NUMPAGES — page numbers are meaningless in markdown
+    PageNumber,
+    /// TOC and everything else: emit the cached result as-is
+    Other,
 }
 
 impl<'a> ConvertContext<'a> {
@@ -37,8 +160,16 @@ impl<'a> ConvertContext<'a> {
             BodyContent::Sdt(sdt) => {
                 // SDT has content: Option<SDTContent> which has content: Vec<BodyContent>
                 if let Some(ref sdt_content) = sdt.content {
+                    // Repeating-section items are numbered per section
+                    let mut instance = 0;
                     for item in &sdt_content.content {
-                        self.convert_body_content(item);
+                        match item {
+                            BodyContent::Sdt(inner) if is_repeating_item(inner) => {
+                                instance += 1;
+                                self.convert_repeating_item(inner, instance);
+                            }
+                            _ => self.convert_body_content(item),
+                        }
                     }
                 }
             }
@@ -46,27 +177,46 @@ impl<'a> ConvertContext<'a> {
         }
     }
 
+    /// Emit one repeating-section instance as a numbered list item, indenting its
+    /// blocks beneath the number so instances don't run together
+    fn convert_repeating_item(&mut self, sdt: &SDT, instance: usize) {
+        let outer = std::mem::take(&mut self.output);
+        for content in sdt.content.iter().flat_map(|c| &c.content) {
+            self.convert_body_content(content);
+        }
+        let item = std::mem::replace(&mut self.output, outer);
+
+        let marker = format!("{}. ", instance);
+        let indent = " ".repeat(marker.len());
+        let mut lines = item.trim().lines();
+        let Some(first) = lines.next() else {
+            return;
+        };
+        if !self.output.is_empty() && !self.output.ends_with("\n\n") {
+            self.output.push_str(if self.output.ends_with('\n') {
+                "\n"
+            } else {
+                "\n\n"
+            });
+        }
+        self.output.push_str(&marker);
+        self.output.push_str(first);
+        self.output.push('\n');
+        for line in lines {
+            if !line.is_empty() {
+                self.output.push_str(&indent);
+                self.output.push_str(line);
+            }
+            self.output.push('\n');
+        }
+        self.output.push('\n');
+    }
+
     fn convert_paragraph(&mut self, para: &docx_rust::document::Paragraph) {
-        let mut heading_level: Option<u8> = None;
+        let heading_level = heading_level(para);
         let mut numbering: Option<(isize, isize)> = None; // (num_id, level)
 
         if let Some(ref prop) = para.property {
-            // Detect heading via style ID
-            if let Some(ref style_id) = prop.style_id {
-                let id = style_id.value.as_ref();
-                heading_level = match id {
-                    "Heading1" | "heading1" | "heading 1" => Some(1),
-                    "Heading2" | "heading2" | "heading 2" => Some(2),
-                    "Heading3" | "heading3" | "heading 3" => Some(3),
-                    "Heading4" | "heading4" | "heading 4" => Some(4),
-                    "Heading5" | "heading5" | "heading 5" => Some(5),
-                    "Heading6" | "heading6" | "heading 6" => Some(6),
-                    "Title" | "title" => Some(1),
-                    "Subtitle" | "subtitle" => Some(2),
-                    _ => None,
-                };
-            }
-
             // Detect list numbering — both id and level are Option<T>
             if let Some(ref num_prop) = prop.numbering {
                 if let (Some(ref id), Some(ref level)) = (&num_prop.id, &num_prop.level) {
@@ -76,19 +226,39 @@ impl<'a> ConvertContext<'a> {
         }
 
         // Collect inline content (runs + hyperlinks)
-        let inline_md = self.collect_inline_content(para);
+        let mut inline_md = self.collect_inline_content(para);
+        if self.options.preserve_line_breaks && heading_level.is_none() {
+            inline_md = markdown::hard_line_breaks(inline_md.trim());
+        }
 
-        // Skip empty paragraphs
+        // Skip empty paragraphs, or keep them as a non-collapsing spacer line
         if inline_md.trim().is_empty() && heading_level.is_none() && numbering.is_none() {
-            self.output.push('\n');
+            if self.options.keep_empty_paragraphs {
+                self.output.push_str(markdown::EMPTY_PARAGRAPH);
+                self.output.push_str("\n\n");
+            } else {
+                self.output.push('\n');
+            }
             return;
         }
 
-        // Emit heading prefix
+        // Headings and list items can't be wrapped in a block, so they lead with an RLM
+        let rtl_mark = if self.rtl_paragraph { RLM } else { "" };
+
+        // Emit heading prefix, with the number Word generates for numbered headings
         if let Some(level) = heading_level {
             let prefix: String = "#".repeat(level as usize);
             self.output.push_str(&prefix);
             self.output.push(' ');
+            self.output.push_str(rtl_mark);
+            if let Some(number) = self.heading_numbers.next(self.docx, para) {
+                self.output.push_str(&number);
+                self.output.push(' ');
+            }
+            if self.options.strip_soft_returns_in_headings {
+                let lines: Vec<&str> = inline_md.lines().map(str::trim).collect();
+                inline_md = lines.join(" ");
+            }
             self.output.push_str(inline_md.trim());
             self.output.push_str("\n\n");
             return;
@@ -101,27 +271,63 @@ impl<'a> ConvertContext<'a> {
             self.output.push_str(&indent);
             self.output.push_str(&bullet);
             self.output.push(' ');
+            self.output.push_str(rtl_mark);
             self.output.push_str(inline_md.trim());
             self.output.push('\n');
             return;
         }
 
+        // A paragraph opening with a checkbox is a task list item; checkboxes in list
+        // items and within sentences stay glyphs
+        if let Some(task) = task_item(inline_md.trim(), rtl_mark) {
+            self.output.push_str(&task);
+            self.output.push('\n');
+            return;
+        }
+
         // Regular paragraph
-        self.output.push_str(inline_md.trim());
+        let text = match self.options.preserve_indent {
+            Some(style) => indent_block(
+                inline_md.trim(),
+                indent_level(para),
+                style,
+                self.options.indent_width,
+            ),
+            None => inline_md.trim().to_string(),
+        };
+        if self.rtl_paragraph {
+            self.output.push_str("<div dir=\"rtl\">\n\n");
+            self.output.push_str(&text);
+            self.output.push_str("\n\n</div>\n\n");
+            return;
+        }
+        self.output.push_str(&text);
         self.output.push_str("\n\n");
     }
 
     fn collect_inline_content(&mut self, para: &docx_rust::document::Paragraph) -> String {
         let mut result = String::new();
+        self.rtl_paragraph = self.options.preserve_direction && is_rtl_paragraph(para);
 
         for pc in &para.content {
             match pc {
                 ParagraphContent::Run(run) => {
+                    self.handle_field_codes(run, &mut result);
                     let text = self.collect_run_text(run);
                     if !text.is_empty() {
-                        let formatted = format_run_text(&text, &run.property);
-                        result.push_str(&formatted);
+                        let mut formatted = format_run_text(&text, &run.property, self.options);
+                        if self.options.preserve_direction && !text.trim().is_empty() {
+                            // A mark after a run against the paragraph direction keeps
+                            // following punctuation on the run's side
+                            match (is_rtl_run(&run.property), self.rtl_paragraph) {
+                                (true, false) => formatted.push_str(RLM),
+                                (false, true) => formatted.push_str(LRM),
+                                _ => {}
+                            }
+                        }
+                        self.emit_inline(&mut result, &formatted);
                     }
+                    self.handle_note_references(run, &mut result);
                 }
                 ParagraphContent::Link(link) => {
                     let display_text = link
@@ -132,23 +338,202 @@ impl<'a> ConvertContext<'a> {
 
                     let target = self.resolve_hyperlink_target(link);
 
-                    if let Some(url) = target {
-                        if !display_text.is_empty() {
-                            result.push_str(&format!("[{}]({})", display_text, url));
-                        } else {
-                            result.push_str(&url);
+                    let link_md = match target {
+                        Some(url) if !display_text.is_empty() => {
+                            format!("[{}]({})", display_text, url)
                         }
-                    } else {
-                        result.push_str(&display_text);
-                    }
+                        Some(url) => url,
+                        None => display_text,
+                    };
+                    self.emit_inline(&mut result, &link_md);
                 }
                 _ => {}
             }
         }
 
+        // A field left open at the paragraph boundary keeps its state, but
+        // buffered link text is flushed as plain text rather than held over
+        for field in &mut self.fields {
+            if !field.result.is_empty() {
+                result.push_str(&std::mem::take(&mut field.result));
+            }
+        }
+
         result
     }
 
+    /// Track `w:fldChar` / `w:instrText` run content. Word places these in
+    /// dedicated runs, so they are handled separately from the run's text.
+    fn handle_field_codes(&mut self, run: &docx_rust::document::Run, out: &mut String) {
+        for rc in &run.content {
+            match rc {
+                RunContent::FieldChar(fc) => match fc.ty {
+                    Some(CharType::Begin) => self.fields.push(FieldState::default()),
+                    Some(CharType::Separate) => {
+                        if let Some(field) = self.fields.last_mut() {
+                            field.kind = Some(parse_field_instruction(&field.instruction));
+                        }
+                    }
+                    Some(CharType::End) => {
+                        let Some(field) = self.fields.pop() else {
+                            continue;
+                        };
+                        let kind = field
+                            .kind
+                            .unwrap_or_else(|| parse_field_instruction(&field.instruction));
+                        if let FieldKind::Hyperlink(url) = kind {
+                            let url = match url.strip_prefix('#') {
+                                Some(anchor) => self.internal_link(anchor),
+                                None => url,
+                            };
+                            let text = field.result.trim();
+                            let link_md = if text.is_empty() {
+                                url
+                            } else {
+                                format!("[{}]({})", text, url)
+                            };
+                            self.emit_inline(out, &link_md);
+                        }
+                    }
+                    None => {}
+                },
+                RunContent::InstrText(instr) => {
+                    if let Some(field) = self.fields.last_mut() {
+                        if field.kind.is_none() {
+                            field.instruction.push_str(&instr.text);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Emit a marker for each footnote or endnote reference in the run: a markdown
+    /// footnote `[^N]`, or a link to the collected endnotes section
+    fn handle_note_references(&mut self, run: &docx_rust::document::Run, out: &mut String) {
+        for rc in &run.content {
+            let (kind, id) = match rc {
+                RunContent::FootnoteReference(r) => (NoteKind::Footnote, r.id.as_deref()),
+                RunContent::EndnoteReference(r) => (NoteKind::Endnote, r.id.as_deref()),
+                _ => continue,
+            };
+            let Some(id) = id.and_then(|id| id.trim().parse::<isize>().ok()) else {
+                continue;
+            };
+            let note = self.note_ref(kind, id);
+            let marker = if note.as_footnote {
+                format!("[^{}]", note.label)
+            } else {
+                format!("<sup>[{0}](#endnote-{0})</sup>", note.label)
+            };
+            self.emit_inline(out, &marker);
+        }
+    }
+
+    /// The note's entry, numbered on its first reference. Footnotes and endnotes
+    /// share one sequence when endnotes become footnotes, otherwise each has its own
+    fn note_ref(&mut self, kind: NoteKind, id: isize) -> &NoteRef {
+        if let Some(i) = self.notes.iter().position(|n| n.kind == kind && n.id == id) {
+            return &self.notes[i];
+        }
+        let as_footnote =
+            kind == NoteKind::Footnote || self.options.endnotes == Endnotes::Footnotes;
+        let label = self
+            .notes
+            .iter()
+            .filter(|n| n.as_footnote == as_footnote)
+            .count()
+            + 1;
+        self.notes.push(NoteRef {
+            kind,
+            id,
+            label,
+            as_footnote,
+        });
+        self.notes.last().expect("note was just pushed")
+    }
+
+    /// Append footnote definitions and the collected endnotes section. Notes
+    /// referenced from within other notes are picked up as the list grows
+    fn convert_notes(&mut self) {
+        let mut footnotes = String::new();
+        let mut endnotes = String::new();
+        let mut i = 0;
+
+        while i < self.notes.len() {
+            let NoteRef {
+                kind,
+                id,
+                label,
+                as_footnote,
+            } = self.notes[i];
+            i += 1;
+
+            let content = match kind {
+                NoteKind::Footnote => self.docx.footnotes.as_ref().and_then(|notes| {
+                    notes
+                        .content
+                        .iter()
+                        .find(|n| n.id == Some(id))
+                        .map(|n| &n.content)
+                }),
+                NoteKind::Endnote => self.docx.endnotes.as_ref().and_then(|notes| {
+                    notes
+                        .content
+                        .iter()
+                        .find(|n| n.id == Some(id))
+                        .map(|n| &n.content)
+                }),
+            };
+            let Some(content) = content else {
+                continue;
+            };
+
+            let outer = std::mem::take(&mut self.output);
+            for item in content {
+                self.convert_body_content(item);
+            }
+            let text = std::mem::replace(&mut self.output, outer);
+
+            if as_footnote {
+                let marker = format!("[^{}]: ", label);
+                footnotes.push_str(&indent_continuation(&marker, text.trim(), 4));
+            } else {
+                let marker = format!("{}. ", label);
+                let anchored = format!("<a id=\"endnote-{}\"></a>{}", label, text.trim());
+                endnotes.push_str(&indent_continuation(&marker, &anchored, marker.len()));
+            }
+        }
+
+        if !footnotes.is_empty() {
+            self.output.push_str("\n\n");
+            self.output.push_str(&footnotes);
+        }
+        if !endnotes.is_empty() {
+            self.output.push_str("\n\n## Endnotes\n\n");
+            self.output.push_str(&endnotes);
+        }
+    }
+
+    /// Append inline markdown, routing it through any open fields: page numbers
+    /// are dropped, hyperlink results are buffered, other results pass through
+    fn emit_inline(&mut self, out: &mut String, text: &str) {
+        for field in self.fields.iter_mut().rev() {
+            match field.kind {
+                // Still inside the field instructions; nothing here is display text
+                None => return,
+                Some(FieldKind::PageNumber) => return,
+                Some(FieldKind::Hyperlink(_)) => {
+                    field.result.push_str(text);
+                    return;
+                }
+                Some(FieldKind::Other) => continue,
+            }
+        }
+        out.push_str(text);
+    }
+
     fn collect_run_text(&mut self, run: &docx_rust::document::Run) -> String {
         let mut text = String::new();
 
@@ -156,7 +541,12 @@ impl<'a> ConvertContext<'a> {
             match rc {
                 RunContent::Text(t) => text.push_str(&t.text),
                 RunContent::Break(_) => text.push('\n'),
-                RunContent::Tab(_) => text.push('\t'),
+                RunContent::Tab(_) => text.push_str(&self.tab),
+                // Word shows these as `-` where it breaks the line or where it won't
+                RunContent::NoBreakHyphen(_) => text.push('-'),
+                RunContent::SoftHyphen(_) if self.options.keep_soft_hyphens => {
+                    text.push('\u{00AD}')
+                }
                 RunContent::Drawing(drawing) => {
                     if let Some(md) = self.convert_drawing(drawing) {
                         text.push_str(&md);
@@ -179,6 +569,8 @@ impl<'a> ConvertContext<'a> {
                     let alt = inline.doc_property.descr.as_deref().unwrap_or("");
                     return self.resolve_image(embed_id, alt);
                 }
+                // Charts, diagrams and other non-picture graphics
+                return Some(object_placeholder(graphic_kind(&graphic.data.uri)));
             }
         }
 
@@ -190,12 +582,54 @@ impl<'a> ConvertContext<'a> {
                     let alt = anchor.doc_property.descr.as_deref().unwrap_or("");
                     return self.resolve_image(embed_id, alt);
                 }
+                return Some(object_placeholder(graphic_kind(&graphic.data.uri)));
             }
         }
 
         None
     }
 
+    /// Replace chart placeholders with the chart's data table, and OLE object
+    /// placeholders with the embedded worksheet's table, or else a marker plus the
+    /// object's preview image when Word stored one
+    fn restore_embedded(&self, embedded: &EmbeddedContent) -> String {
+        let mut result = self.output.clone();
+        // The `X` terminator keeps placeholder 1 from matching inside placeholder 10
+        for (i, chart) in embedded.charts.iter().enumerate() {
+            let replacement = match chart.as_ref().and_then(docx_chart::chart_table) {
+                // The table needs blank lines around it to stand as its own block
+                Some(table) => format!("\n\n{}\n", table),
+                None => object_placeholder("chart"),
+            };
+            result = result.replace(&format!("{}{}X", CHART_PLACEHOLDER, i), &replacement);
+        }
+        for (i, object) in embedded.objects.iter().enumerate() {
+            if let Some(table) = object
+                .worksheet
+                .as_ref()
+                .and_then(docx_worksheet::worksheet_table)
+            {
+                result = result.replace(
+                    &format!("{}{}X", OBJECT_PLACEHOLDER, i),
+                    &format!("\n\n{}\n", table),
+                );
+                continue;
+            }
+            let mut replacement =
+                object_placeholder(object.prog_id.as_deref().unwrap_or("OLE object"));
+            if let Some(image) = object
+                .preview_rel
+                .as_deref()
+                .and_then(|rel| self.resolve_image(rel, ""))
+            {
+                replacement.push(' ');
+                replacement.push_str(&image);
+            }
+            result = result.replace(&format!("{}{}X", OBJECT_PLACEHOLDER, i), &replacement);
+        }
+        result
+    }
+
     fn resolve_image(&self, embed_id: &str, alt: &str) -> Option<String> {
         // Resolve relationship ID to file path
         let target = self
@@ -208,27 +642,51 @@ impl<'a> ConvertContext<'a> {
             .target
             .as_ref();
 
+        let word_target = format!("word/{}", target);
+        if self.options.ignored_images.contains(target)
+            || self.options.ignored_images.contains(&word_target)
+        {
+            return None;
+        }
+
         // Check if we have this image in our image map
-        let image_path = if let Some(mapped) = self.image_map.get(target) {
-            mapped.clone()
+        // (also with the "word/" prefix, since DOCX stores images as word/media/...)
+        let mapped = self
+            .image_map
+            .get(target)
+            .or_else(|| self.image_map.get(&word_target));
+
+        let alt = if alt.is_empty() && self.options.image_alt_from_filename {
+            markdown::alt_from_filename(target)
         } else {
-            // Try with "word/" prefix since DOCX stores images as word/media/...
-            let full_path = format!("word/{}", target);
-            if let Some(mapped) = self.image_map.get(&full_path) {
-                mapped.clone()
-            } else {
-                // Fallback: use the target path directly
-                format!("images/{}", target.rsplit('/').next().unwrap_or(target))
+            alt.to_string()
+        };
+
+        let image_path = match mapped {
+            Some(mapped) => mapped.clone(),
+            None if self.options.image_placeholder => {
+                return Some(markdown::image_placeholder(&alt));
             }
+            // Fallback: use the target path directly
+            None => format!("images/{}", target.rsplit('/').next().unwrap_or(target)),
         };
 
         Some(format!("![{}]({})", alt, image_path))
     }
 
+    /// Link to a bookmark, pointing at the heading's text slug when the bookmark
+    /// sits on a heading and heading anchors are enabled
+    fn internal_link(&self, bookmark: &str) -> String {
+        match self.heading_bookmarks.get(bookmark) {
+            Some(slug) => format!("#{}", slug),
+            None => format!("#{}", bookmark),
+        }
+    }
+
     fn resolve_hyperlink_target(&self, link: &docx_rust::document::Hyperlink) -> Option<String> {
         // Internal anchor link
         if let Some(ref anchor) = link.anchor {
-            return Some(format!("#{}", anchor));
+            return Some(self.internal_link(anchor));
         }
 
         // External link via relationship ID
@@ -267,12 +725,28 @@ impl<'a> ConvertContext<'a> {
                                             "bullet" => "-".to_string(),
                                             "decimal" | "upperRoman" | "lowerRoman"
                                             | "upperLetter" | "lowerLetter" => {
+                                                // A list instance may restart numbering via
+                                                // lvlOverride/startOverride; otherwise use the
+                                                // level's own start value
+                                                let start = num
+                                                    .level_overrides
+                                                    .iter()
+                                                    .find(|o| o.i_level == Some(level))
+                                                    .and_then(|o| o.start_override.as_ref())
+                                                    .and_then(|s| s.value)
+                                                    .or_else(|| {
+                                                        lvl.start.as_ref().and_then(|s| s.value)
+                                                    })
+                                                    .unwrap_or(1);
                                                 let counter = self
                                                     .list_counters
                                                     .entry((num_id, level))
-                                                    .or_insert(0);
+                                                    .or_insert((start - 1).max(0) as usize);
                                                 *counter += 1;
-                                                format!("{}.", counter)
+                                                format!(
+                                                    "{}.",
+                                                    format_list_number(*counter, fmt_val)
+                                                )
                                             }
                                             "none" => "-".to_string(),
                                             _ => "-".to_string(),
@@ -340,23 +814,451 @@ impl<'a> ConvertContext<'a> {
 
     fn collect_cell_text(&mut self, cell: &docx_rust::document::TableCell) -> String {
         let mut parts: Vec<String> = Vec::new();
+        let cell_break = self.options.cell_break.as_deref().unwrap_or("<br>");
 
         for tc in &cell.content {
             let TableCellContent::Paragraph(para) = tc;
             let text = self.collect_inline_content(para);
-            let trimmed = text.trim().to_string();
-            if !trimmed.is_empty() {
-                parts.push(trimmed);
+            // A raw newline would end the table row, so line breaks become
+            // the cell break token unless whitespace is being collapsed
+            let text = if self.options.collapse_table_whitespace {
+                text.split_whitespace().collect::<Vec<_>>().join(" ")
+            } else {
+                text.trim().replace('\n', cell_break)
+            };
+            if !text.is_empty() {
+                parts.push(text);
+            }
+        }
+
+        // Join multiple paragraphs in a cell with the same token
+        parts.join(cell_break)
+    }
+}
+
+/// Heading level from the paragraph style ID (Heading1-6, Title, Subtitle)
+fn heading_level(para: &docx_rust::document::Paragraph) -> Option<u8> {
+    let style_id = para.property.as_ref()?.style_id.as_ref()?;
+    match style_id.value.as_ref() {
+        "Heading1" | "heading1" | "heading 1" => Some(1),
+        "Heading2" | "heading2" | "heading 2" => Some(2),
+        "Heading3" | "heading3" | "heading 3" => Some(3),
+        "Heading4" | "heading4" | "heading 4" => Some(4),
+        "Heading5" | "heading5" | "heading 5" => Some(5),
+        "Heading6" | "heading6" | "heading 6" => Some(6),
+        "Title" | "title" => Some(1),
+        "Subtitle" | "subtitle" => Some(2),
+        _ => None,
+    }
+}
+
+/// Map bookmarks placed on headings to the slug `--heading-anchors` will give
+/// that heading, walking headings in document order so duplicates get the same suffix
+/// and numbered headings the same number
+fn heading_bookmarks(docx: &Docx, content: &[BodyContent]) -> HashMap<String, String> {
+    fn walk(
+        docx: &Docx,
+        content: &[BodyContent],
+        numbers: &mut HeadingNumbering,
+        slugs: &mut HashMap<String, usize>,
+        out: &mut HashMap<String, String>,
+    ) {
+        for item in content {
+            match item {
+                BodyContent::Paragraph(para) if heading_level(para).is_some() => {
+                    let text = match numbers.next(docx, para) {
+                        Some(number) => format!("{} {}", number, para.text()),
+                        None => para.text(),
+                    };
+                    let slug = markdown::unique_slug(&markdown::heading_slug(&text), slugs);
+                    for pc in &para.content {
+                        if let ParagraphContent::BookmarkStart(bookmark) = pc {
+                            if let Some(ref name) = bookmark.name {
+                                out.insert(name.to_string(), slug.clone());
+                            }
+                        }
+                    }
+                }
+                BodyContent::Sdt(sdt) => {
+                    if let Some(ref sdt_content) = sdt.content {
+                        walk(docx, &sdt_content.content, numbers, slugs, out);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let mut out = HashMap::new();
+    let mut numbers = HeadingNumbering::default();
+    walk(docx, content, &mut numbers, &mut HashMap::new(), &mut out);
+    out
+}
+
+/// Counters of multilevel lists linked to heading styles. Word generates these
+/// numbers (`1.2.3`) from `w:lvlText`; they never appear in the run text
+#[derive(Default)]
+struct HeadingNumbering {
+    /// (num_id, level) → current count
+    counters: HashMap<(isize, isize), usize>,
+}
+
+impl HeadingNumbering {
+    /// Advance the counters for a heading paragraph and render its number, or
+    /// `None` when no numbering applies to it
+    fn next(&mut self, docx: &Docx, para: &docx_rust::document::Paragraph) -> Option<String> {
+        let (num_id, level) = paragraph_numbering(docx, para)?;
+        let numbering = docx.numbering.as_ref()?;
+        let num = numbering
+            .numberings
+            .iter()
+            .find(|n| n.num_id == Some(num_id))?;
+        let abstract_id = num.abstract_num_id.as_ref()?.value;
+        let abstract_num = numbering
+            .abstract_numberings
+            .iter()
+            .find(|a| a.abstract_num_id == abstract_id)?;
+        let level_def = |level: isize| {
+            abstract_num
+                .levels
+                .iter()
+                .find(|l| l.i_level == Some(level))
+        };
+        let format = |level: isize| {
+            level_def(level)
+                .and_then(|l| l.number_format.as_ref())
+                .map_or("decimal", |f| f.value.as_ref())
+        };
+        // A list instance may restart numbering via lvlOverride/startOverride
+        let start = |level: isize| {
+            num.level_overrides
+                .iter()
+                .find(|o| o.i_level == Some(level))
+                .and_then(|o| o.start_override.as_ref())
+                .and_then(|s| s.value)
+                .or_else(|| level_def(level)?.start.as_ref()?.value)
+                .unwrap_or(1)
+                .max(0) as usize
+        };
+        if matches!(format(level), "bullet" | "none") {
+            return None;
+        }
+
+        // Counting a level restarts every deeper one
+        let count = self
+            .counters
+            .entry((num_id, level))
+            .or_insert(start(level).saturating_sub(1));
+        *count += 1;
+        self.counters
+            .retain(|&(id, l), _| id != num_id || l <= level);
+
+        // `%1.%2` shows the current count of levels 0 and 1; a level not reached yet
+        // shows one less than its start, as Word does
+        let mut number = level_def(level)?
+            .level_text
+            .as_ref()?
+            .value
+            .as_deref()?
+            .to_string();
+        for l in 0..=level.min(8) {
+            let count = self
+                .counters
+                .get(&(num_id, l))
+                .copied()
+                .unwrap_or_else(|| start(l).saturating_sub(1));
+            number = number.replace(
+                &format!("%{}", l + 1),
+                &format_list_number(count, format(l)),
+            );
+        }
+        let number = number.trim();
+        (!number.is_empty()).then(|| number.to_string())
+    }
+}
+
+/// List numbering (num_id, level) of a paragraph: its own `w:numPr`, else the one
+/// its style (or a style it is based on) carries. `numId 0` turns numbering off
+fn paragraph_numbering(
+    docx: &Docx,
+    para: &docx_rust::document::Paragraph,
+) -> Option<(isize, isize)> {
+    let prop = para.property.as_ref()?;
+    let direct = prop.numbering.as_ref();
+    let mut style_id = prop.style_id.as_ref().map(|s| s.value.as_ref());
+    let mut inherited = None;
+    // basedOn chains are short; the bound guards against cycles
+    for _ in 0..10 {
+        let Some(style) =
+            style_id.and_then(|id| docx.styles.styles.iter().find(|s| s.style_id == id))
+        else {
+            break;
+        };
+        if let Some(numbering) = style.paragraph.as_ref().and_then(|p| p.numbering.as_ref()) {
+            inherited = Some(numbering);
+            break;
+        }
+        style_id = style.base.as_ref().map(|b| b.value.as_ref());
+    }
+
+    let id = direct
+        .and_then(|n| n.id.as_ref())
+        .or_else(|| inherited?.id.as_ref())?
+        .value;
+    let level = direct
+        .and_then(|n| n.level.as_ref())
+        .or_else(|| inherited?.level.as_ref())
+        .map_or(0, |l| l.value);
+    (id != 0).then_some((id, level))
+}
+
+/// Render a list counter in the numbering format Word uses for the level
+fn format_list_number(n: usize, format: &str) -> String {
+    match format {
+        "upperRoman" => to_roman(n),
+        "lowerRoman" => to_roman(n).to_lowercase(),
+        "upperLetter" => to_letters(n),
+        "lowerLetter" => to_letters(n).to_lowercase(),
+        _ => n.to_string(),
+    }
+}
+
+fn to_roman(mut n: usize) -> String {
+    const NUMERALS: [(usize, &str); 13] = [
+        (1000, "M"),
+        (900, "CM"),
+        (500, "D"),
+        (400, "CD"),
+        (100, "C"),
+        (90, "XC"),
+        (50, "L"),
+        (40, "XL"),
+        (10, "X"),
+        (9, "IX"),
+        (5, "V"),
+        (4, "IV"),
+        (1, "I"),
+    ];
+
+    let mut result = String::new();
+    for (value, numeral) in NUMERALS {
+        while n >= value {
+            result.push_str(numeral);
+            n -= value;
+        }
+    }
+    result
+}
+
+/// Word's letter numbering: A..Z, then AA..ZZ, AAA.. (repeated, not base-26)
+fn to_letters(n: usize) -> String {
+    if n == 0 {
+        return String::new();
+    }
+    let letter = (b'A' + ((n - 1) % 26) as u8) as char;
+    letter.to_string().repeat((n - 1) / 26 + 1)
+}
+
+/// Classify a field from its instruction text (e.g. `HYPERLINK \l "_Toc123"`)
+fn parse_field_instruction(instruction: &str) -> FieldKind {
+    let tokens = tokenize_field_instruction(instruction);
+    let Some(name) = tokens.first() else {
+        return FieldKind::Other;
+    };
+
+    match name.to_ascii_uppercase().as_str() {
+        "HYPERLINK" => {
+            let mut url: Option<&str> = None;
+            let mut anchor: Option<&str> = None;
+            let mut iter = tokens[1..].iter();
+            while let Some(token) = iter.next() {
+                match token.as_str() {
+                    "\\l" => anchor = iter.next().map(|s| s.as_str()),
+                    // Tooltip and target frame switches take an argument we don't need
+                    "\\o" | "\\t" => {
+                        iter.next();
+                    }
+                    t if t.starts_with('\\') => {}
+                    t if url.is_none() => url = Some(t),
+                    _ => {}
+                }
+            }
+            match (url, anchor) {
+                (Some(u), Some(a)) => FieldKind::Hyperlink(format!("{}#{}", u, a)),
+                (Some(u), None) => FieldKind::Hyperlink(u.to_string()),
+                (None, Some(a)) => FieldKind::Hyperlink(format!("#{}", a)),
+                (None, None) => FieldKind::Other,
+            }
+        }
+        "PAGEREF" | "PAGE" | "NUMPAGES" | "SECTIONPAGES" => FieldKind::PageNumber,
+        _ => FieldKind::Other,
+    }
+}
+
+/// Split a field instruction on whitespace, keeping double-quoted arguments intact
+fn tokenize_field_instruction(instruction: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+
+    for c in instruction.chars() {
+        match c {
+            '"' => {
+                if in_quotes {
+                    tokens.push(std::mem::take(&mut current));
+                }
+                in_quotes = !in_quotes;
+            }
+            c if c.is_whitespace() && !in_quotes => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
             }
+            c => current.push(c),
         }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+
+    tokens
+}
 
-        // Join multiple paragraphs in a cell with <br>
-        parts.join("<br>")
+/// `marker` followed by `text`, with the text's later lines indented by `indent`
+/// spaces so they stay inside the list item or footnote, and a trailing blank line
+fn indent_continuation(marker: &str, text: &str, indent: usize) -> String {
+    let indent = " ".repeat(indent);
+    let mut block = marker.to_string();
+    for (i, line) in text.lines().enumerate() {
+        if i > 0 && !line.is_empty() {
+            block.push_str(&indent);
+        }
+        block.push_str(line);
+        block.push('\n');
     }
+    block.push('\n');
+    block
+}
+
+/// Word's indent step: the Increase Indent button moves half an inch
+const TWIPS_PER_INDENT: isize = 720;
+
+/// Indent levels of a paragraph's direct left indentation (`w:ind w:left`);
+/// indents under half an inch are treated as layout, not structure
+fn indent_level(para: &docx_rust::document::Paragraph) -> usize {
+    let left = para
+        .property
+        .as_ref()
+        .and_then(|p| p.indent.as_ref())
+        .and_then(|ind| ind.left)
+        .unwrap_or(0);
+    (left / TWIPS_PER_INDENT).max(0) as usize
+}
+
+/// Prefix every line of a paragraph for its indent level: nested `> ` markers,
+/// or `width` spaces per level
+fn indent_block(text: &str, level: usize, style: IndentStyle, width: usize) -> String {
+    if level == 0 {
+        return text.to_string();
+    }
+    let prefix = match style {
+        IndentStyle::Blockquote => "> ".repeat(level),
+        IndentStyle::Spaces => " ".repeat(level * width),
+    };
+    text.lines()
+        .map(|line| {
+            if line.is_empty() {
+                prefix.trim_end().to_string()
+            } else {
+                format!("{}{}", prefix, line)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// `- [x] text` / `- [ ] text` for text starting with a checkbox glyph, with
+/// `mark` (an RLM or nothing) leading the text
+fn task_item(text: &str, mark: &str) -> Option<String> {
+    let (checked, rest) = match text.strip_prefix(CHECKED_BOX) {
+        Some(rest) => ("x", rest),
+        None => (" ", text.strip_prefix(UNCHECKED_BOX)?),
+    };
+    Some(format!("- [{}] {}{}", checked, mark, rest.trim_start()))
+}
+
+/// True for a content control tagged as a repeating-section item during preprocessing
+fn is_repeating_item(sdt: &SDT) -> bool {
+    sdt.property
+        .as_ref()
+        .and_then(|p| p.doc_part_obj.as_ref())
+        .and_then(|obj| obj.doc_part_gallery.as_ref())
+        .and_then(|gallery| gallery.name.as_deref())
+        == Some(REPEATING_ITEM_GALLERY)
+}
+
+/// Marker for embedded content that has no Markdown equivalent
+fn object_placeholder(kind: &str) -> String {
+    format!("[embedded object: {}]", kind)
+}
+
+/// Short name for a `a:graphicData` URI, e.g. `…/drawingml/2006/chart` → `chart`
+fn graphic_kind(uri: &str) -> &str {
+    match uri.rsplit('/').next() {
+        Some(kind) if !kind.is_empty() => kind,
+        _ => "graphic",
+    }
+}
+
+/// Twips (1/20 pt) per character of 12pt body text, for turning tab stops into spaces
+const TWIPS_PER_CHAR: isize = 180;
+
+/// Characters per tab from `w:defaultTabStop` in settings.xml; Word's default
+/// half-inch stop (720 twips) is 4 characters
+fn tab_width(docx: &Docx) -> usize {
+    let twips = docx
+        .settings
+        .as_ref()
+        .and_then(|s| s.default_tab_stop.as_ref())
+        .map(|stop| stop.val)
+        .unwrap_or(720);
+    (twips / TWIPS_PER_CHAR).clamp(1, 8) as usize
+}
+
+/// Right-to-left mark
+const RLM: &str = "\u{200f}";
+/// Left-to-right mark
+const LRM: &str = "\u{200e}";
+
+/// True when the paragraph's `w:bidi` property lays it out right to left
+fn is_rtl_paragraph(para: &docx_rust::document::Paragraph) -> bool {
+    para.property
+        .as_ref()
+        .and_then(|p| p.bidi.as_ref())
+        .is_some_and(|bidi| bidi.value != Some(false))
+}
+
+/// True when the run's `w:rtl` property marks its text as right to left
+fn is_rtl_run(props: &Option<CharacterProperty>) -> bool {
+    props
+        .as_ref()
+        .and_then(|p| p.rtl.as_ref())
+        .is_some_and(|rtl| rtl.value != Some(false))
 }
 
 /// Wrap text in markdown formatting based on run properties
-fn format_run_text(text: &str, props: &Option<CharacterProperty>) -> String {
+fn format_run_text(
+    text: &str,
+    props: &Option<CharacterProperty>,
+    options: &ConvertOptions,
+) -> String {
+    let escaped;
+    let text = if options.escape_markdown {
+        escaped = markdown::escape_markdown(text);
+        escaped.as_str()
+    } else {
+        text
+    };
     let Some(props) = props else {
         return text.to_string();
     };
@@ -391,5 +1293,99 @@ fn format_run_text(text: &str, props: &Option<CharacterProperty>) -> String {
         result = format!("*{}*", result);
     }
 
+    if options.preserve_color {
+        if let Some(color) = props.color.as_ref().and_then(|c| explicit_color(&c.value)) {
+            result = format!("<span style=\"color:#{}\">{}</span>", color, result);
+        }
+    }
+
     result
 }
+
+/// Normalized `RRGGBB` for a `w:color` value, or `None` for automatic/black text
+fn explicit_color(value: &str) -> Option<String> {
+    let value = value.trim();
+    if value.len() != 6 || !value.chars().all(|c| c.is_ascii_hexdigit()) {
+        // Covers `auto` and malformed values
+        return None;
+    }
+    let value = value.to_ascii_uppercase();
+    (value != "000000").then_some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::docx_preprocess;
+    use std::io::{Cursor, Write};
+    use zip::write::SimpleFileOptions;
+    use zip::ZipWriter;
+
+    const NAMESPACE: &str = "http://schemas.openxmlformats.org/wordprocessingml/2006/main";
+
+    /// A minimal DOCX package whose body is `body`
+    fn docx_bytes(body: &str) -> Vec<u8> {
+        let parts = [
+            (
+                "[Content_Types].xml",
+                r#"<?xml version="1.0"?><Types xmlns="http://schemas.openxmlformats.org/package/2006/content-types"><Default Extension="rels" ContentType="application/vnd.openxmlformats-package.relationships+xml"/><Default Extension="xml" ContentType="application/xml"/><Override PartName="/word/document.xml" ContentType="application/vnd.openxmlformats-officedocument.wordprocessingml.document.main+xml"/></Types>"#.to_string(),
+            ),
+            (
+                "_rels/.rels",
+                r#"<?xml version="1.0"?><Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships"><Relationship Id="rId1" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/officeDocument" Target="word/document.xml"/></Relationships>"#.to_string(),
+            ),
+            (
+                "word/document.xml",
+                format!(
+                    r#"<?xml version="1.0"?><w:document xmlns:w="{}"><w:body>{}</w:body></w:document>"#,
+                    NAMESPACE, body
+                ),
+            ),
+        ];
+
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, content) in parts {
+            zip.start_file(name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(content.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    fn convert(body: &str, options: &ConvertOptions) -> String {
+        let (file, embedded) = docx_preprocess::load_docx(docx_bytes(body)).unwrap();
+        let docx = file.parse().unwrap();
+        docx_to_markdown(&docx, &ImageMap::new(), options, &embedded)
+    }
+
+    const TWO_LINE_HEADING: &str = r#"<w:p><w:pPr><w:pStyle w:val="Heading1"/></w:pPr><w:r><w:t>Part One</w:t></w:r><w:r><w:br/><w:t>The Beginning</w:t></w:r></w:p><w:p><w:r><w:t>Text</w:t></w:r></w:p>"#;
+
+    #[test]
+    fn heading_soft_return_becomes_space() {
+        let options = ConvertOptions {
+            strip_soft_returns_in_headings: true,
+            ..ConvertOptions::default()
+        };
+        let md = convert(TWO_LINE_HEADING, &options);
+        assert_eq!(md.lines().next(), Some("# Part One The Beginning"));
+    }
+
+    #[test]
+    fn heading_soft_return_kept_when_disabled() {
+        let md = convert(TWO_LINE_HEADING, &ConvertOptions::default());
+        assert!(md.starts_with("# Part One\nThe Beginning"));
+    }
+
+    #[test]
+    fn simple_hyperlink_field_keeps_its_text() {
+        let body = r#"<w:p><w:r><w:t xml:space="preserve">Visit </w:t></w:r><w:fldSimple w:instr=" HYPERLINK &quot;https://example.com&quot; "><w:r><w:t>the site</w:t></w:r></w:fldSimple><w:r><w:t>.</w:t></w:r></w:p>"#;
+        let md = convert(body, &ConvertOptions::default());
+        assert_eq!(md.trim(), "Visit [the site](https://example.com).");
+    }
+
+    #[test]
+    fn simple_pageref_field_drops_the_page_number() {
+        let body = r#"<w:p><w:r><w:t xml:space="preserve">See page </w:t></w:r><w:fldSimple w:instr=" PAGEREF _Ref123 \h "><w:r><w:t>12</w:t></w:r></w:fldSimple><w:r><w:t xml:space="preserve"> for details.</w:t></w:r></w:p>"#;
+        let md = convert(body, &ConvertOptions::default());
+        assert_eq!(md.trim(), "See page  for details.");
+    }
+}
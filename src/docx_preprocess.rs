@@ -0,0 +1,423 @@
+//! Raw `word/document.xml` rewrites applied before docx-rust parses the package,
+//! for elements its schema skips entirely.
+
+use crate::docx_chart::{self, Chart};
+use crate::docx_worksheet::{self, Worksheet};
+use crate::docx_xml::{
+    attribute, find_element, read_binary_part, read_part, relationship_target, tag_attribute,
+};
+use anyhow::Result;
+use docx_rust::DocxFile;
+use std::io::{Cursor, Read, Write};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+/// Placeholder prefix substituted for `<w:object>` elements; followed by the
+/// object's index and an `X` terminator
+pub const OBJECT_PLACEHOLDER: &str = "EPUB2MDOBJECT";
+
+/// Placeholder prefix substituted for drawings that hold a chart; followed by
+/// the chart's index and an `X` terminator
+pub const CHART_PLACEHOLDER: &str = "EPUB2MDCHART";
+
+/// Document-part gallery given to repeating-section item content controls
+/// (`w15:repeatingSectionItem`), whose marker docx-rust doesn't parse
+pub const REPEATING_ITEM_GALLERY: &str = "epub2md:repeatingSectionItem";
+
+/// Text substituted for checked and unchecked checkboxes (legacy `FORMCHECKBOX`
+/// fields and checkbox content controls), the glyphs Word shows by default
+pub const CHECKED_BOX: char = '☒';
+pub const UNCHECKED_BOX: char = '☐';
+
+const DOCUMENT_PART: &str = "word/document.xml";
+const DOCUMENT_RELS_PART: &str = "word/_rels/document.xml.rels";
+
+/// Content taken out of the document body before parsing, restored by the converter
+#[derive(Default)]
+pub struct EmbeddedContent {
+    pub objects: Vec<EmbeddedObject>,
+    /// Charts by placeholder index; `None` when the chart part couldn't be read
+    pub charts: Vec<Option<Chart>>,
+}
+
+/// An OLE object (`<w:object>`) removed from the document body
+pub struct EmbeddedObject {
+    /// OLE program identifier, e.g. `Excel.Sheet.12`
+    pub prog_id: Option<String>,
+    /// Relationship ID of the preview image Word stores beside the object
+    pub preview_rel: Option<String>,
+    /// Active sheet of an embedded Excel workbook (`word/embeddings/*.xlsx`)
+    pub worksheet: Option<Worksheet>,
+}
+
+/// Load a DOCX package, swapping each `<w:object>` and chart drawing (which
+/// docx-rust drops or can't follow) for placeholder text so the converter can
+/// mark where they were, merging drop-cap letters back into the paragraph they start,
+/// tagging repeating-section items so each instance can be kept apart, turning
+/// checkboxes into [`CHECKED_BOX`] / [`UNCHECKED_BOX`] text, and spelling simple
+/// fields out as complex ones
+pub fn load_docx(bytes: Vec<u8>) -> Result<(DocxFile, EmbeddedContent)> {
+    let (bytes, embedded) = match rewrite_package(&bytes) {
+        Some((repacked, embedded)) => (repacked, embedded),
+        // Not a readable package or nothing to rewrite: let docx-rust report errors
+        None => (bytes, EmbeddedContent::default()),
+    };
+
+    let file = DocxFile::from_reader(Cursor::new(bytes)).map_err(|e| anyhow::anyhow!("{}", e))?;
+    Ok((file, embedded))
+}
+
+fn rewrite_package(bytes: &[u8]) -> Option<(Vec<u8>, EmbeddedContent)> {
+    let mut archive = ZipArchive::new(Cursor::new(bytes)).ok()?;
+
+    let mut document = String::new();
+    archive
+        .by_name(DOCUMENT_PART)
+        .ok()?
+        .read_to_string(&mut document)
+        .ok()?;
+    let has_objects = document.contains("<w:object");
+    let has_drop_caps = document.contains("w:dropCap=");
+    let has_charts = document.contains("<c:chart");
+    let has_repeating = document.contains("<w15:repeatingSectionItem");
+    let has_checkboxes = document.contains("<w:checkBox") || document.contains("<w14:checkbox");
+    let has_simple_fields = document.contains("<w:fldSimple");
+    if !has_objects
+        && !has_drop_caps
+        && !has_charts
+        && !has_repeating
+        && !has_checkboxes
+        && !has_simple_fields
+    {
+        return None;
+    }
+
+    let (mut document, objects) = replace_objects(&mut archive, &document);
+    if has_drop_caps {
+        document = merge_drop_caps(&document);
+    }
+    if has_repeating {
+        document = tag_repeating_items(&document);
+    }
+    if has_checkboxes {
+        document = replace_checkboxes(&document);
+    }
+    if has_simple_fields {
+        document = unwrap_simple_fields(&document);
+    }
+    let mut charts = Vec::new();
+    if has_charts {
+        (document, charts) = replace_charts(&mut archive, &document);
+    }
+    let repacked = repack(&mut archive, &document).ok()?;
+    Some((repacked, EmbeddedContent { objects, charts }))
+}
+
+/// Replace every `<w:object>…</w:object>` with a `<w:t>` placeholder, reading
+/// the worksheet of embedded Excel workbooks from the part the object points at
+fn replace_objects(
+    archive: &mut ZipArchive<Cursor<&[u8]>>,
+    xml: &str,
+) -> (String, Vec<EmbeddedObject>) {
+    let rels = if xml.contains("<w:object") {
+        read_part(archive, DOCUMENT_RELS_PART).unwrap_or_default()
+    } else {
+        String::new()
+    };
+    let mut result = String::with_capacity(xml.len());
+    let mut objects = Vec::new();
+    let mut rest = xml;
+
+    while let Some(start) = find_element(rest, "<w:object") {
+        let Some(len) = rest[start..].find("</w:object>") else {
+            break;
+        };
+        let end = start + len + "</w:object>".len();
+        let element = &rest[start..end];
+
+        result.push_str(&rest[..start]);
+        result.push_str(&format!(
+            "<w:t>{}{}X</w:t>",
+            OBJECT_PLACEHOLDER,
+            objects.len()
+        ));
+        let worksheet = attribute(element, "<o:OLEObject", "r:id")
+            .and_then(|rel_id| relationship_target(&rels, &rel_id))
+            .filter(|target| target.to_ascii_lowercase().ends_with(".xlsx"))
+            .and_then(|target| read_binary_part(archive, &part_path(&target)))
+            .and_then(|bytes| docx_worksheet::read_worksheet(&bytes));
+        objects.push(EmbeddedObject {
+            prog_id: attribute(element, "<o:OLEObject", "ProgID"),
+            preview_rel: attribute(element, "<v:imagedata", "r:id"),
+            worksheet,
+        });
+        rest = &rest[end..];
+    }
+
+    result.push_str(rest);
+    (result, objects)
+}
+
+/// Replace every `<w:drawing>` holding a `<c:chart>` with a `<w:t>` placeholder,
+/// reading the chart's cached data from the part its relationship points at
+fn replace_charts(
+    archive: &mut ZipArchive<Cursor<&[u8]>>,
+    xml: &str,
+) -> (String, Vec<Option<Chart>>) {
+    let rels = read_part(archive, DOCUMENT_RELS_PART).unwrap_or_default();
+    let mut result = String::with_capacity(xml.len());
+    let mut charts = Vec::new();
+    let mut rest = xml;
+
+    while let Some(start) = find_element(rest, "<w:drawing") {
+        let Some(len) = rest[start..].find("</w:drawing>") else {
+            break;
+        };
+        let end = start + len + "</w:drawing>".len();
+        let element = &rest[start..end];
+
+        result.push_str(&rest[..start]);
+        match attribute(element, "<c:chart", "r:id") {
+            Some(rel_id) => {
+                let chart = relationship_target(&rels, &rel_id)
+                    .and_then(|target| read_part(archive, &part_path(&target)))
+                    .map(|chart_xml| docx_chart::parse_chart(&chart_xml));
+                result.push_str(&format!(
+                    "<w:t>{}{}X</w:t>",
+                    CHART_PLACEHOLDER,
+                    charts.len()
+                ));
+                charts.push(chart);
+            }
+            None => result.push_str(element),
+        }
+        rest = &rest[end..];
+    }
+
+    result.push_str(rest);
+    (result, charts)
+}
+
+/// Package path of a relationship target from `word/document.xml`
+fn part_path(target: &str) -> String {
+    if let Some(absolute) = target.strip_prefix('/') {
+        return absolute.to_string();
+    }
+    let mut parts = vec!["word"];
+    for segment in target.split('/') {
+        match segment {
+            ".." => {
+                parts.pop();
+            }
+            "." | "" => {}
+            other => parts.push(other),
+        }
+    }
+    parts.join("/")
+}
+
+/// Move the runs of each drop-cap paragraph (a `w:framePr` with `w:dropCap`, which
+/// Word uses to hold the enlarged first letter) to the start of the next paragraph
+fn merge_drop_caps(xml: &str) -> String {
+    let mut result = String::with_capacity(xml.len());
+    // Runs of drop caps waiting for the paragraph they belong to
+    let mut carried = String::new();
+    let mut rest = xml;
+
+    while let Some(start) = find_element(rest, "<w:p") {
+        let Some(open_len) = rest[start..].find('>') else {
+            break;
+        };
+        let open_end = start + open_len + 1;
+        if rest[..open_end].ends_with("/>") {
+            result.push_str(&rest[..open_end]);
+            rest = &rest[open_end..];
+            continue;
+        }
+        let Some(len) = rest[start..].find("</w:p>") else {
+            break;
+        };
+        let end = start + len + "</w:p>".len();
+        let paragraph = &rest[start..end];
+        let content_start = paragraph_content_start(paragraph, open_end - start);
+
+        result.push_str(&rest[..start]);
+        if is_drop_cap(&paragraph[..content_start]) {
+            carried.push_str(&paragraph[content_start..paragraph.len() - "</w:p>".len()]);
+        } else {
+            result.push_str(&paragraph[..content_start]);
+            result.push_str(&std::mem::take(&mut carried));
+            result.push_str(&paragraph[content_start..]);
+        }
+        rest = &rest[end..];
+    }
+
+    // A drop cap with no paragraph after it keeps its letter in a paragraph of its own
+    if !carried.is_empty() {
+        result.push_str(&format!("<w:p>{}</w:p>", carried));
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Rewrite each simple field (`w:fldSimple`, which docx-rust drops along with its
+/// result runs) as the equivalent complex field: `begin`, the `w:instr` as
+/// `w:instrText`, `separate`, the result runs and `end`. The converter then treats
+/// HYPERLINK, PAGEREF and TOC the same way whichever form Word saved
+fn unwrap_simple_fields(xml: &str) -> String {
+    let field_char = |kind: &str| format!("<w:r><w:fldChar w:fldCharType=\"{}\"/></w:r>", kind);
+    let mut result = String::with_capacity(xml.len());
+    let mut rest = xml;
+
+    while let Some(start) = find_element(rest, "<w:fldSimple") {
+        let Some(len) = rest[start..].find('>') else {
+            break;
+        };
+        let end = start + len + 1;
+        let tag = &rest[start..end];
+        // The attribute value is already escaped, as element text needs it to be
+        let instruction = tag_attribute(tag, "w:instr").unwrap_or_default();
+
+        result.push_str(&rest[..start]);
+        result.push_str(&field_char("begin"));
+        result.push_str(&format!(
+            "<w:r><w:instrText xml:space=\"preserve\">{}</w:instrText></w:r>",
+            instruction
+        ));
+        // A self-closing field has no result to show
+        result.push_str(&field_char(if tag.ends_with("/>") {
+            "end"
+        } else {
+            "separate"
+        }));
+        rest = &rest[end..];
+    }
+
+    result.push_str(rest);
+    result.replace("</w:fldSimple>", &field_char("end"))
+}
+
+/// Give each repeating-section item's `w:sdtPr` a `w:docPartGallery` of
+/// [`REPEATING_ITEM_GALLERY`], which docx-rust does parse
+fn tag_repeating_items(xml: &str) -> String {
+    xml.replace(
+        "<w15:repeatingSectionItem",
+        &format!(
+            "<w:docPartObj><w:docPartGallery w:val=\"{}\"/></w:docPartObj><w15:repeatingSectionItem",
+            REPEATING_ITEM_GALLERY
+        ),
+    )
+}
+
+/// Put checkbox glyph text in place of checkboxes, whose state docx-rust doesn't read.
+/// A legacy form field keeps its (result-less) field after the glyph; an inline
+/// checkbox content control is replaced by a run holding the glyph
+fn replace_checkboxes(xml: &str) -> String {
+    let mut result = String::with_capacity(xml.len());
+    let mut rest = xml;
+
+    loop {
+        let legacy = find_element(rest, "<w:checkBox");
+        let control = find_element(rest, "<w14:checkbox");
+        let (at, is_legacy) = match (legacy, control) {
+            (Some(l), Some(c)) if c < l => (c, false),
+            (Some(l), _) => (l, true),
+            (None, Some(c)) => (c, false),
+            (None, None) => break,
+        };
+        let (open, close) = if is_legacy {
+            ("<w:r", "</w:r>")
+        } else {
+            ("<w:sdt", "</w:sdt>")
+        };
+        let start = find_element_before(&rest[..at], open);
+        let end = rest[at..].find(close).map(|len| at + len + close.len());
+        let (Some(start), Some(end)) = (start, end) else {
+            result.push_str(&rest[..at + 1]);
+            rest = &rest[at + 1..];
+            continue;
+        };
+        let element = &rest[start..end];
+        // A control around whole paragraphs keeps them; their text has the glyph already
+        if !is_legacy && find_element(element, "<w:p").is_some() {
+            result.push_str(&rest[..at + 1]);
+            rest = &rest[at + 1..];
+            continue;
+        }
+
+        let glyph = if is_checked(element, is_legacy) {
+            CHECKED_BOX
+        } else {
+            UNCHECKED_BOX
+        };
+        result.push_str(&rest[..start]);
+        result.push_str(&format!("<w:r><w:t>{}</w:t></w:r>", glyph));
+        if is_legacy {
+            result.push_str("<w:r><w:fldChar w:fldCharType=\"begin\"/></w:r>");
+        }
+        rest = &rest[end..];
+    }
+
+    result.push_str(rest);
+    result
+}
+
+/// State of a checkbox: `w:checked` (on by its presence) or else `w:default` for a
+/// form field, `w14:checked` for a content control
+fn is_checked(element: &str, legacy: bool) -> bool {
+    let on = |value: &str| matches!(value, "1" | "true" | "on");
+    if !legacy {
+        return attribute(element, "<w14:checked", "w14:val").is_some_and(|v| on(&v));
+    }
+    if find_element(element, "<w:checked").is_some() {
+        return attribute(element, "<w:checked", "w:val").is_none_or(|v| on(&v));
+    }
+    attribute(element, "<w:default", "w:val").is_some_and(|v| on(&v))
+}
+
+/// Offset in a paragraph where its runs start, after `<w:pPr>` if present
+fn paragraph_content_start(paragraph: &str, open_end: usize) -> usize {
+    let after_open = &paragraph[open_end..];
+    if after_open.trim_start().starts_with("<w:pPr") {
+        if let Some(i) = after_open.find("</w:pPr>") {
+            return open_end + i + "</w:pPr>".len();
+        }
+    }
+    open_end
+}
+
+/// True for paragraph properties with a drop-cap frame (`w:dropCap="drop"` or `"margin"`)
+fn is_drop_cap(properties: &str) -> bool {
+    attribute(properties, "<w:framePr", "w:dropCap").is_some_and(|value| value != "none")
+}
+
+/// Start of the last `<tag` element in `xml`, not a longer tag name
+fn find_element_before(xml: &str, tag: &str) -> Option<usize> {
+    let mut end = xml.len();
+    while let Some(start) = xml[..end].rfind(tag) {
+        if find_element(&xml[start..], tag) == Some(0) {
+            return Some(start);
+        }
+        end = start;
+    }
+    None
+}
+
+/// Copy the package into a new zip with `word/document.xml` replaced
+fn repack(archive: &mut ZipArchive<Cursor<&[u8]>>, document: &str) -> Result<Vec<u8>> {
+    let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+
+    for i in 0..archive.len() {
+        let entry = archive.by_index_raw(i)?;
+        if entry.name() == DOCUMENT_PART {
+            drop(entry);
+            writer.start_file(DOCUMENT_PART, options)?;
+            writer.write_all(document.as_bytes())?;
+        } else {
+            writer.raw_copy_file(entry)?;
+        }
+    }
+
+    Ok(writer.finish()?.into_inner())
+}
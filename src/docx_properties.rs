@@ -0,0 +1,115 @@
+//! DOCX custom document properties (`docProps/custom.xml`) and document
+//! variables (`w:docVar` in settings.xml), which docx-rust doesn't read.
+//! Enterprise templates keep classification, version or project codes there.
+
+use crate::docx_xml::{read_part, tag_attribute};
+use crate::html_preprocess::decode_entities;
+use std::collections::HashMap;
+use std::io::Cursor;
+use zip::ZipArchive;
+
+const CUSTOM_PART: &str = "docProps/custom.xml";
+const SETTINGS_PART: &str = "word/settings.xml";
+
+/// Custom properties and document variables by name; a custom property wins
+/// over a variable of the same name. Unreadable parts are skipped
+pub fn read_properties(bytes: &[u8]) -> HashMap<String, String> {
+    let Ok(mut archive) = ZipArchive::new(Cursor::new(bytes)) else {
+        return HashMap::new();
+    };
+
+    let mut properties = HashMap::new();
+    if let Some(settings) = read_part(&mut archive, SETTINGS_PART) {
+        properties.extend(document_variables(&settings));
+    }
+    if let Some(custom) = read_part(&mut archive, CUSTOM_PART) {
+        properties.extend(custom_properties(&custom));
+    }
+    properties
+}
+
+/// The non-blank property called `name`, ignoring case. An exact-case match
+/// wins; otherwise the first match in name order, so hash order never decides
+pub fn find_property<'a>(
+    properties: &'a HashMap<String, String>,
+    name: &str,
+) -> Option<(&'a str, &'a str)> {
+    let name = name.trim();
+    properties
+        .iter()
+        .filter(|(key, value)| key.eq_ignore_ascii_case(name) && !value.trim().is_empty())
+        .min_by_key(|(key, _)| (key.as_str() != name, key.as_str()))
+        .map(|(key, value)| (key.as_str(), value.as_str()))
+}
+
+/// `<property name="…"><vt:lpwstr>value</vt:lpwstr></property>` entries; the
+/// value is the text of the typed child, whatever its type
+fn custom_properties(xml: &str) -> Vec<(String, String)> {
+    let mut properties = Vec::new();
+    let mut rest = xml;
+
+    while let Some(start) = rest.find("<property ") {
+        rest = &rest[start..];
+        let Some(open_len) = rest.find('>') else {
+            break;
+        };
+        let open = &rest[..open_len];
+        let Some(end) = rest.find("</property>") else {
+            break;
+        };
+        let content = rest.get(open_len + 1..end).unwrap_or_default();
+        rest = &rest[end + "</property>".len()..];
+
+        let Some(name) = tag_attribute(open, "name") else {
+            continue;
+        };
+        let value = content
+            .find('>')
+            .and_then(|i| {
+                let inner = &content[i + 1..];
+                inner.find("</").map(|j| &inner[..j])
+            })
+            .unwrap_or_default();
+        properties.push((decode_entities(&name), decode_entities(value.trim())));
+    }
+
+    properties
+}
+
+/// `<w:docVar w:name="…" w:val="…"/>` entries
+fn document_variables(xml: &str) -> Vec<(String, String)> {
+    xml.match_indices("<w:docVar ")
+        .filter_map(|(start, _)| {
+            let element = &xml[start..];
+            let tag = &element[..element.find('>')?];
+            let name = tag_attribute(tag, "w:name")?;
+            let value = tag_attribute(tag, "w:val").unwrap_or_default();
+            Some((decode_entities(&name), decode_entities(&value)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exact_case_property_wins_over_other_cases() {
+        let properties: HashMap<String, String> = [
+            ("project", "lower"),
+            ("Project", "exact"),
+            ("PROJECT", "upper"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        assert_eq!(
+            find_property(&properties, "Project"),
+            Some(("Project", "exact"))
+        );
+        assert_eq!(
+            find_property(&properties, "proJect"),
+            Some(("PROJECT", "upper"))
+        );
+    }
+}
@@ -1,23 +1,47 @@
 use crate::docx_markdown;
+use crate::docx_preprocess::{self, EmbeddedContent};
+use crate::docx_properties;
+use crate::docx_revisions::{self, Revision};
 use crate::image::ImageMap;
 use crate::markdown;
-use crate::reader::{BookReader, Chapter, ImageResource, Metadata};
+use crate::reader::{self, BookReader, Chapter, ConvertOptions, ImageResource, Metadata};
 use anyhow::{Context, Result};
 use docx_rust::DocxFile;
+use std::collections::HashMap;
+use std::fs;
 use std::path::Path;
 
 pub struct DocxData {
     /// DocxFile owns the raw data; Docx borrows from it.
     /// We store the file so it lives long enough, then parse on demand.
     file: DocxFile,
+    /// OLE objects and charts replaced by placeholders before parsing
+    embedded: EmbeddedContent,
+    /// Custom document properties and document variables
+    properties: HashMap<String, String>,
+    /// Tracked insertions and deletions, for `--changelog`
+    revisions: Vec<Revision>,
 }
 
 impl DocxData {
     pub fn open(path: &Path) -> Result<Self> {
-        let file = DocxFile::from_file(path)
-            .map_err(|e| anyhow::anyhow!("{}", e))
-            .with_context(|| format!("Failed to open DOCX: {}", path.display()))?;
-        Ok(Self { file })
+        let bytes =
+            fs::read(path).with_context(|| format!("Failed to read: {}", path.display()))?;
+        Self::from_bytes(bytes, path)
+    }
+
+    /// Load a DOCX already in memory; `name` is only used in error messages
+    pub fn from_bytes(bytes: Vec<u8>, name: &Path) -> Result<Self> {
+        let properties = docx_properties::read_properties(&bytes);
+        let revisions = docx_revisions::read_revisions(&bytes);
+        let (file, embedded) = docx_preprocess::load_docx(bytes)
+            .with_context(|| format!("Failed to open DOCX: {}", name.display()))?;
+        Ok(Self {
+            file,
+            embedded,
+            properties,
+            revisions,
+        })
     }
 
     fn parse(&self) -> Result<docx_rust::Docx<'_>> {
@@ -28,19 +52,82 @@ impl DocxData {
     }
 }
 
+/// `--split-by-heading`: one chapter per heading of `level` or shallower (plus any
+/// text before the first), each standing for a `section-NN` document. Links to an
+/// anchor in another chapter point at that document, which the converter resolves
+/// to the chapter's output file
+fn split_chapters(
+    docx: &docx_rust::Docx,
+    md: &str,
+    level: u8,
+    options: &ConvertOptions,
+    on_chapter: &dyn Fn(usize, usize),
+) -> Vec<Chapter> {
+    let parts = markdown::split_at_headings(md, level);
+    // Parts without text are dropped, so part and chapter numbers can differ
+    let mut chapter_of = HashMap::new();
+    for (part, content) in parts.iter().enumerate() {
+        if !content.trim().is_empty() {
+            chapter_of.insert(part, chapter_of.len());
+        }
+    }
+    let href = |chapter: usize| format!("section-{:02}", chapter + 1);
+    let sections = docx_markdown::anchor_sections(docx, options, level);
+
+    parts
+        .iter()
+        .enumerate()
+        .filter_map(|(part, content)| {
+            let chapter = *chapter_of.get(&part)?;
+            let content = format!("{}\n", content.trim());
+            let content = markdown::retarget_links(&content, |target| {
+                let anchor = target.strip_prefix('#')?;
+                let other = chapter_of.get(sections.get(anchor)?)?;
+                (*other != chapter).then(|| format!("{}#{}", href(*other), anchor))
+            });
+            on_chapter(chapter + 1, chapter_of.len());
+            Some(Chapter {
+                title: markdown::leading_heading(&content).map(str::to_string),
+                content,
+                landmark: None,
+                source_href: Some(href(chapter)),
+                source_idref: None,
+            })
+        })
+        .collect()
+}
+
 impl BookReader for DocxData {
-    fn chapters(&self) -> Result<Vec<Chapter>> {
+    fn chapters(
+        &self,
+        image_map: &ImageMap,
+        options: &ConvertOptions,
+        on_chapter: &dyn Fn(usize, usize),
+    ) -> Result<Vec<Chapter>> {
         let docx = self.parse()?;
 
-        // Convert the DOCX body to markdown (using empty image map — images already extracted)
-        let md = docx_markdown::docx_to_markdown(&docx, &ImageMap::new());
-        let cleaned = markdown::clean_markdown(&md);
+        // Image references resolve through the relationship target to the extracted path
+        let mut md = docx_markdown::docx_to_markdown(&docx, image_map, options, &self.embedded);
+        if options.changelog && !self.revisions.is_empty() {
+            md.push_str("\n\n");
+            md.push_str(&docx_revisions::render_changelog(&self.revisions));
+        }
+        let cleaned = markdown::clean_markdown(&md, options.convert_entities);
 
-        // DOCX is a single continuous document — treat as one chapter
-        Ok(vec![Chapter {
-            title: None,
-            content: cleaned,
-        }])
+        match options.split_by_heading {
+            Some(level) => Ok(split_chapters(&docx, &cleaned, level, options, on_chapter)),
+            // DOCX is a single continuous document — treat as one chapter
+            None => {
+                on_chapter(1, 1);
+                Ok(vec![Chapter {
+                    title: None,
+                    content: cleaned,
+                    landmark: None,
+                    source_href: None,
+                    source_idref: None,
+                }])
+            }
+        }
     }
 
     fn images(&self) -> Result<Vec<ImageResource>> {
@@ -67,28 +154,43 @@ impl BookReader for DocxData {
                     publisher: None,
                     language: None,
                     description: None,
+                    date: None,
+                    rights: None,
+                    primary_authors: None,
+                    custom_properties: self.properties.clone(),
                 }
             }
         };
 
         // Core is an enum with CoreNamespace and CoreNoNamespace variants
         // Both have the same fields, just different XML namespace handling
-        let (title, creator, language, description) = match &docx.core {
+        let (title, creator, language, description, created) = match &docx.core {
             Some(docx_rust::core::Core::CoreNamespace(c)) => (
                 c.title.as_deref().map(|s| s.to_string()),
                 c.creator.as_deref().map(|s| s.to_string()),
                 c.language.as_deref().map(|s| s.to_string()),
                 c.description.as_deref().map(|s| s.to_string()),
+                c.created.as_deref().map(|s| s.to_string()),
             ),
             Some(docx_rust::core::Core::CoreNoNamespace(c)) => (
                 c.title.as_deref().map(|s| s.to_string()),
                 c.creator.as_deref().map(|s| s.to_string()),
                 c.language.as_deref().map(|s| s.to_string()),
                 c.description.as_deref().map(|s| s.to_string()),
+                c.created.as_deref().map(|s| s.to_string()),
             ),
-            None => (None, None, None, None),
+            None => (None, None, None, None, None),
         };
 
+        // settings.xml names the document's default editing language
+        let language = language.filter(|l| !l.trim().is_empty()).or_else(|| {
+            docx.settings
+                .as_ref()
+                .and_then(|s| s.theme_font_lang.as_ref())
+                .and_then(|lang| lang.val.as_deref())
+                .map(|l| l.to_string())
+        });
+
         // App is also an enum with two namespace variants
         let company = match &docx.app {
             Some(docx_rust::app::App::AppNoApNamespace(a)) => {
@@ -102,10 +204,26 @@ impl BookReader for DocxData {
 
         Metadata {
             title,
-            authors: creator.map(|a| vec![a]).unwrap_or_default(),
+            // Word separates several authors with semicolons
+            authors: reader::dedupe_authors(
+                creator
+                    .iter()
+                    .flat_map(|c| c.split(';'))
+                    .map(|a| a.to_string()),
+            ),
             publisher: company,
             language,
             description,
+            // `dcterms:created` is a full timestamp; the day is enough for a header
+            date: created.map(|c| c.split('T').next().unwrap_or_default().to_string()),
+            // Core properties have no rights field; templates often add one
+            rights: ["rights", "copyright"].iter().find_map(|wanted| {
+                docx_properties::find_property(&self.properties, wanted)
+                    .map(|(_, value)| value.to_string())
+            }),
+            // Core properties have no creator roles
+            primary_authors: None,
+            custom_properties: self.properties.clone(),
         }
     }
 }
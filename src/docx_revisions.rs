@@ -0,0 +1,179 @@
+//! DOCX tracked changes (`w:ins` / `w:del` in document.xml) with their author and
+//! date, which docx-rust doesn't read. `--changelog` lists them in a trailing
+//! Revision History section.
+
+use crate::docx_xml::{find_element, read_part, tag_attribute};
+use crate::html_preprocess::decode_entities;
+use std::io::Cursor;
+use zip::ZipArchive;
+
+const DOCUMENT_PART: &str = "word/document.xml";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RevisionKind {
+    Insertion,
+    Deletion,
+}
+
+/// One tracked change, or several adjacent ones by the same author at the same time
+pub struct Revision {
+    pub kind: RevisionKind,
+    pub author: Option<String>,
+    /// `w:date` as written, e.g. `2024-03-02T11:30:00Z`
+    pub date: Option<String>,
+    pub text: String,
+}
+
+/// Tracked insertions and deletions in document order. Revisions without text,
+/// such as inserted paragraph marks, are skipped; an unreadable part yields none
+pub fn read_revisions(bytes: &[u8]) -> Vec<Revision> {
+    let Ok(mut archive) = ZipArchive::new(Cursor::new(bytes)) else {
+        return Vec::new();
+    };
+    read_part(&mut archive, DOCUMENT_PART)
+        .map(|xml| revisions(&xml))
+        .unwrap_or_default()
+}
+
+fn revisions(xml: &str) -> Vec<Revision> {
+    let mut revisions: Vec<Revision> = Vec::new();
+    let mut pos = 0;
+    // End of the previous revision's element
+    let mut last_end = 0;
+
+    while let Some((start, kind)) = next_revision(&xml[pos..]) {
+        let element = &xml[pos + start..];
+        let Some(open_len) = element.find('>') else {
+            break;
+        };
+        let open = &element[..open_len];
+        pos += start + open_len + 1;
+        if open.ends_with('/') {
+            continue;
+        }
+
+        let close = match kind {
+            RevisionKind::Insertion => "</w:ins>",
+            RevisionKind::Deletion => "</w:del>",
+        };
+        let Some(content_len) = element[open_len + 1..].find(close) else {
+            break;
+        };
+        let content = &element[open_len + 1..open_len + 1 + content_len];
+        let gap = &xml[last_end..pos - open_len - 1];
+        pos += content_len + close.len();
+
+        let text_tag = match kind {
+            RevisionKind::Insertion => "w:t",
+            RevisionKind::Deletion => "w:delText",
+        };
+        let text = element_text(content, text_tag);
+        if text.trim().is_empty() {
+            continue;
+        }
+        let author = tag_attribute(open, "w:author").map(|a| decode_entities(&a));
+        let date = tag_attribute(open, "w:date");
+
+        // Word splits one edit at every run and paragraph boundary
+        let adjacent = !has_element(gap, "w:t") && !has_element(gap, "w:delText");
+        match revisions.last_mut() {
+            Some(last)
+                if adjacent && last.kind == kind && last.author == author && last.date == date =>
+            {
+                if has_element(gap, "w:p") {
+                    last.text.push(' ');
+                }
+                last.text.push_str(&text);
+            }
+            _ => revisions.push(Revision {
+                kind,
+                author,
+                date,
+                text,
+            }),
+        }
+        last_end = pos;
+    }
+
+    revisions
+}
+
+/// Whether `xml` contains a `<tag>` or `<tag …>` element
+fn has_element(xml: &str, tag: &str) -> bool {
+    find_element(xml, &format!("<{}", tag)).is_some()
+}
+
+/// Offset of the next `<w:ins>` or `<w:del>` element
+fn next_revision(xml: &str) -> Option<(usize, RevisionKind)> {
+    let insertion = find_element(xml, "<w:ins").map(|i| (i, RevisionKind::Insertion));
+    let deletion = find_element(xml, "<w:del").map(|i| (i, RevisionKind::Deletion));
+    match (insertion, deletion) {
+        (Some(a), Some(b)) => Some(if a.0 < b.0 { a } else { b }),
+        (a, b) => a.or(b),
+    }
+}
+
+/// Concatenated text of the `<tag>` elements in `xml`, with `w:tab` as a space
+fn element_text(xml: &str, tag: &str) -> String {
+    let open = format!("<{}", tag);
+    let close = format!("</{}>", tag);
+    let mut text = String::new();
+    let mut rest = xml;
+
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start..];
+        if rest.starts_with("<w:tab/>") {
+            text.push(' ');
+        } else if rest.starts_with(&open)
+            && matches!(rest.as_bytes().get(open.len()), Some(b' ' | b'>'))
+        {
+            let Some(open_len) = rest.find('>') else {
+                break;
+            };
+            let Some(end) = rest.find(&close) else {
+                break;
+            };
+            if rest.as_bytes()[open_len - 1] != b'/' && open_len < end {
+                text.push_str(&decode_entities(&rest[open_len + 1..end]));
+            }
+            rest = &rest[end + close.len()..];
+            continue;
+        }
+        rest = &rest[1..];
+    }
+
+    text
+}
+
+/// `## Revision History`: one list item per change, in document order
+pub fn render_changelog(revisions: &[Revision]) -> String {
+    let mut out = String::from("## Revision History\n\n");
+
+    for revision in revisions {
+        let author = revision.author.as_deref().unwrap_or("Unknown author");
+        let text = revision
+            .text
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ");
+        let change = match revision.kind {
+            RevisionKind::Insertion => format!("inserted “{}”", text),
+            RevisionKind::Deletion => format!("deleted ~~{}~~", text),
+        };
+        out.push_str(&format!("- **{}** {}", author, change));
+        if let Some(date) = revision.date.as_deref() {
+            out.push_str(&format!(" ({})", display_date(date)));
+        }
+        out.push('\n');
+    }
+
+    out
+}
+
+/// `2024-03-02T11:30:00Z` → `2024-03-02 11:30`
+fn display_date(date: &str) -> String {
+    match date.split_once('T') {
+        Some((day, time)) => format!("{} {}", day, time.get(..5).unwrap_or(time)),
+        None => date.to_string(),
+    }
+}
@@ -0,0 +1,99 @@
+//! Excel worksheets embedded in DOCX files as OLE objects (`word/embeddings/*.xlsx`).
+//! Word only shows a preview image of them; the workbook package itself holds the
+//! cell values, read here with calamine.
+
+use crate::docx_chart::cell;
+use crate::docx_xml::{attribute, read_part};
+use calamine::{open_workbook_from_rs, Data, Reader, SheetType, SheetVisible, Xlsx};
+use std::io::Cursor;
+use zip::ZipArchive;
+
+const WORKBOOK_PART: &str = "xl/workbook.xml";
+
+/// Largest used range converted; bigger sheets keep only their top-left corner
+const MAX_ROWS: u32 = 500;
+const MAX_COLUMNS: u32 = 50;
+
+/// The used range of a workbook's active worksheet
+#[derive(Debug, Default)]
+pub struct Worksheet {
+    pub name: Option<String>,
+    /// Cell values row by row, as stored: numbers and dates are not formatted
+    pub rows: Vec<Vec<String>>,
+}
+
+/// Read the worksheet Word shows for an xlsx package: the active one, or else the
+/// first visible one. `None` when the bytes aren't a workbook or the sheet is empty
+pub fn read_worksheet(bytes: &[u8]) -> Option<Worksheet> {
+    let mut workbook: Xlsx<_> = open_workbook_from_rs(Cursor::new(bytes)).ok()?;
+    let sheets = workbook.sheets_metadata();
+    let name = active_tab(bytes)
+        .and_then(|tab| sheets.get(tab))
+        .or_else(|| {
+            sheets.iter().find(|sheet| {
+                sheet.typ == SheetType::WorkSheet && sheet.visible == SheetVisible::Visible
+            })
+        })?
+        .name
+        .clone();
+    let range = workbook.worksheet_range(&name).ok()?;
+
+    let (top, left) = range.start()?;
+    let (bottom, right) = range.end()?;
+    let range = range.range(
+        (top, left),
+        (
+            bottom.min(top + MAX_ROWS - 1),
+            right.min(left + MAX_COLUMNS - 1),
+        ),
+    );
+    let rows: Vec<Vec<String>> = range
+        .rows()
+        .map(|row| row.iter().map(cell_text).collect())
+        .collect();
+
+    let has_values = rows.iter().flatten().any(|value| !value.trim().is_empty());
+    has_values.then_some(Worksheet {
+        name: Some(name),
+        rows,
+    })
+}
+
+/// `activeTab` of the workbook view, which calamine doesn't expose
+fn active_tab(bytes: &[u8]) -> Option<usize> {
+    let mut archive = ZipArchive::new(Cursor::new(bytes)).ok()?;
+    let workbook = read_part(&mut archive, WORKBOOK_PART)?;
+    attribute(&workbook, "<workbookView", "activeTab")?
+        .parse()
+        .ok()
+}
+
+/// A cell's value as Excel shows it unformatted
+fn cell_text(value: &Data) -> String {
+    match value {
+        Data::Bool(true) => "TRUE".to_string(),
+        Data::Bool(false) => "FALSE".to_string(),
+        value => value.to_string(),
+    }
+}
+
+/// Render a worksheet as a caption and a table whose first row is the header
+pub fn worksheet_table(sheet: &Worksheet) -> Option<String> {
+    let (header, body) = sheet.rows.split_first()?;
+
+    let mut md = match sheet.name {
+        Some(ref name) => format!("*Worksheet: {}*\n\n", cell(name)),
+        None => "*Worksheet*\n\n".to_string(),
+    };
+    let row = |cells: &[String]| {
+        let cells: Vec<String> = cells.iter().map(|c| cell(c)).collect();
+        format!("| {} |\n", cells.join(" | "))
+    };
+    md.push_str(&row(header));
+    md.push_str(&format!("|{}\n", " --- |".repeat(header.len())));
+    for cells in body {
+        md.push_str(&row(cells));
+    }
+
+    Some(md)
+}
@@ -0,0 +1,69 @@
+//! Shared helpers for reading raw DOCX package parts and scanning their XML, for
+//! the parts and elements docx-rust doesn't read.
+
+use std::io::{Cursor, Read};
+use zip::ZipArchive;
+
+/// Text of a package part; `None` when it's missing or unreadable
+pub(crate) fn read_part(archive: &mut ZipArchive<Cursor<&[u8]>>, name: &str) -> Option<String> {
+    let mut content = String::new();
+    archive
+        .by_name(name)
+        .ok()?
+        .read_to_string(&mut content)
+        .ok()?;
+    Some(content)
+}
+
+/// Bytes of a package part, e.g. an embedded workbook
+pub(crate) fn read_binary_part(
+    archive: &mut ZipArchive<Cursor<&[u8]>>,
+    name: &str,
+) -> Option<Vec<u8>> {
+    let mut content = Vec::new();
+    archive.by_name(name).ok()?.read_to_end(&mut content).ok()?;
+    Some(content)
+}
+
+/// `Target` of the relationship with the given `Id`
+pub(crate) fn relationship_target(rels: &str, id: &str) -> Option<String> {
+    let pattern = format!(" Id=\"{}\"", id);
+    let mut rest = rels;
+    while let Some(start) = find_element(rest, "<Relationship") {
+        let end = start + rest[start..].find('>')? + 1;
+        let element = &rest[start..end];
+        if element.contains(&pattern) {
+            return tag_attribute(element, "Target");
+        }
+        rest = &rest[end..];
+    }
+    None
+}
+
+/// Find `<tag` followed by whitespace, `/` or `>`, not a longer tag name
+pub(crate) fn find_element(xml: &str, tag: &str) -> Option<usize> {
+    let mut offset = 0;
+    while let Some(i) = xml[offset..].find(tag) {
+        let start = offset + i;
+        let next = xml[start + tag.len()..].chars().next();
+        if matches!(next, Some(c) if c.is_whitespace() || c == '>' || c == '/') {
+            return Some(start);
+        }
+        offset = start + tag.len();
+    }
+    None
+}
+
+/// Value of `name="…"` on the first `tag` element inside `xml`
+pub(crate) fn attribute(xml: &str, tag: &str, name: &str) -> Option<String> {
+    let start = find_element(xml, tag)?;
+    tag_attribute(&xml[start..start + xml[start..].find('>')?], name)
+}
+
+/// Value of `name="…"` in an opening tag
+pub(crate) fn tag_attribute(tag: &str, name: &str) -> Option<String> {
+    let pattern = format!(" {}=\"", name);
+    let start = tag.find(&pattern)? + pattern.len();
+    let len = tag[start..].find('"')?;
+    Some(tag[start..start + len].to_string())
+}
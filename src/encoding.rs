@@ -0,0 +1,104 @@
+//! Text decoding for EPUB documents that aren't UTF-8. Older books sometimes
+//! ship ISO-8859-1 / Windows-1252 XHTML, often with a missing or wrong declaration.
+
+/// How a document's bytes were turned into text
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decoding {
+    Utf8,
+    Utf16,
+    Windows1252,
+    /// Invalid UTF-8 in an unsupported or contradicting encoding; bad bytes were replaced
+    LossyUtf8,
+}
+
+/// Decode a document: a BOM wins, then valid UTF-8 (whatever the declaration
+/// says), then the XML declaration or `<meta charset>`. Undeclared non-UTF-8
+/// text is assumed to be Windows-1252, the usual legacy encoding.
+pub fn decode_document(bytes: &[u8]) -> (String, Decoding) {
+    if let Some(rest) = bytes.strip_prefix(b"\xEF\xBB\xBF") {
+        return (String::from_utf8_lossy(rest).into_owned(), Decoding::Utf8);
+    }
+    if let Some(text) = decode_utf16(bytes) {
+        return (text, Decoding::Utf16);
+    }
+    if let Ok(text) = std::str::from_utf8(bytes) {
+        return (text.to_string(), Decoding::Utf8);
+    }
+
+    match declared_encoding(bytes).as_deref() {
+        None | Some("iso-8859-1" | "latin1" | "l1" | "windows-1252" | "cp1252" | "us-ascii") => {
+            (decode_windows_1252(bytes), Decoding::Windows1252)
+        }
+        Some(_) => (
+            String::from_utf8_lossy(bytes).into_owned(),
+            Decoding::LossyUtf8,
+        ),
+    }
+}
+
+/// UTF-16 text with a byte order mark
+fn decode_utf16(bytes: &[u8]) -> Option<String> {
+    let from_bytes = if bytes.starts_with(b"\xFF\xFE") {
+        u16::from_le_bytes
+    } else if bytes.starts_with(b"\xFE\xFF") {
+        u16::from_be_bytes
+    } else {
+        return None;
+    };
+    let units: Vec<u16> = bytes[2..]
+        .chunks_exact(2)
+        .map(|pair| from_bytes([pair[0], pair[1]]))
+        .collect();
+    Some(String::from_utf16_lossy(&units))
+}
+
+/// Lowercased encoding label from the XML declaration or a `<meta>` charset
+fn declared_encoding(bytes: &[u8]) -> Option<String> {
+    // Declarations are ASCII and sit at the top of the document
+    let head = String::from_utf8_lossy(&bytes[..bytes.len().min(2048)]).to_ascii_lowercase();
+
+    let declaration = head.find("<?xml").and_then(|start| {
+        head[start..]
+            .find("?>")
+            .map(|end| &head[start..start + end])
+    });
+    let label = declaration
+        .and_then(|decl| value_after(decl, "encoding="))
+        .or_else(|| value_after(&head, "charset="))?;
+    Some(label.trim().to_string()).filter(|l| !l.is_empty())
+}
+
+/// Quoted or bare value following `key` (`encoding="x"`, `charset=x`)
+fn value_after<'a>(text: &'a str, key: &str) -> Option<&'a str> {
+    let value = &text[text.find(key)? + key.len()..];
+    match value.chars().next()? {
+        quote @ ('"' | '\'') => {
+            let value = &value[1..];
+            Some(&value[..value.find(quote)?])
+        }
+        _ => {
+            let end = value
+                .find(|c: char| c == '"' || c == '\'' || c == ';' || c == '>' || c.is_whitespace())
+                .unwrap_or(value.len());
+            Some(&value[..end])
+        }
+    }
+}
+
+/// Windows-1252 is ISO-8859-1 with printable characters in 0x80–0x9F
+fn decode_windows_1252(bytes: &[u8]) -> String {
+    const HIGH: [char; 32] = [
+        '\u{20ac}', '\u{81}', '\u{201a}', '\u{192}', '\u{201e}', '\u{2026}', '\u{2020}',
+        '\u{2021}', '\u{2c6}', '\u{2030}', '\u{160}', '\u{2039}', '\u{152}', '\u{8d}', '\u{17d}',
+        '\u{8f}', '\u{90}', '\u{2018}', '\u{2019}', '\u{201c}', '\u{201d}', '\u{2022}', '\u{2013}',
+        '\u{2014}', '\u{2dc}', '\u{2122}', '\u{161}', '\u{203a}', '\u{153}', '\u{9d}', '\u{17e}',
+        '\u{178}',
+    ];
+    bytes
+        .iter()
+        .map(|&b| match b {
+            0x80..=0x9f => HIGH[(b - 0x80) as usize],
+            _ => b as char,
+        })
+        .collect()
+}
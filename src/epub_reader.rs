@@ -1,9 +1,15 @@
+use crate::css::{self, ClassEmphasis};
+use crate::encoding::{self, Decoding};
+use crate::html_output::{self, HtmlSection};
+use crate::html_preprocess::{self, file_name, percent_decode};
 use crate::image::ImageMap;
 use crate::markdown;
-use crate::reader::{BookReader, Chapter, ImageResource, Metadata};
+use crate::reader::{self, BookReader, Chapter, ConvertOptions, ImageResource, Metadata, NavEntry};
 use anyhow::{Context, Result};
 use rbook::prelude::*;
 use rbook::Epub;
+use std::collections::{HashMap, HashSet};
+use std::io::Cursor;
 use std::path::Path;
 
 pub struct EpubData {
@@ -19,13 +25,34 @@ impl EpubData {
         Ok(Self { epub })
     }
 
-    fn raw_chapters(&self) -> Result<Vec<RawChapter>> {
+    /// Load an EPUB already in memory; `name` is only used in error messages
+    pub fn from_bytes(bytes: Vec<u8>, name: &Path) -> Result<Self> {
+        let epub = Epub::options()
+            .strict(false)
+            .read(Cursor::new(bytes))
+            .with_context(|| format!("Failed to open EPUB: {}", name.display()))?;
+        Ok(Self { epub })
+    }
+
+    fn raw_chapters(&self, options: &ConvertOptions) -> Result<Vec<RawChapter>> {
         let mut chapters = Vec::new();
-        let mut reader = self.epub.reader();
 
-        while let Some(result) = reader.read_next() {
-            let data = result.context("Failed to read chapter content")?;
-            let html_content = data.content().to_string();
+        for spine_entry in self.epub.spine().entries() {
+            let manifest_entry = spine_entry.manifest_entry().with_context(|| {
+                format!(
+                    "Failed to read chapter content: spine item `{}` is not in the manifest",
+                    spine_entry.idref()
+                )
+            })?;
+
+            // The EPUB 3 nav document duplicates the generated table of contents
+            if is_nav_document(&manifest_entry) && !options.keep_nav {
+                continue;
+            }
+
+            let html_content = read_document(&manifest_entry)?;
+            let href = manifest_entry.href().path().as_str().to_string();
+            let idref = spine_entry.idref().to_string();
 
             // Skip empty or near-empty content
             if html_content.trim().is_empty() {
@@ -34,6 +61,54 @@ impl EpubData {
 
             chapters.push(RawChapter {
                 title: None,
+                href,
+                idref,
+                html_content,
+            });
+        }
+
+        if chapters.is_empty() {
+            chapters = self.raw_chapters_from_manifest(options)?;
+        }
+
+        if !options.keep_manual_toc {
+            skip_manual_toc(&mut chapters);
+        }
+
+        Ok(chapters)
+    }
+
+    /// Fallback for malformed EPUBs with an empty or missing spine:
+    /// read every XHTML document listed in the manifest instead
+    fn raw_chapters_from_manifest(&self, options: &ConvertOptions) -> Result<Vec<RawChapter>> {
+        let mut entries: Vec<_> = self
+            .epub
+            .manifest()
+            .readable_content()
+            .filter(|entry| options.keep_nav || !is_nav_document(entry))
+            .collect();
+        if entries.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        eprintln!("Warning: spine is empty or unreadable, falling back to manifest order");
+
+        // rbook does not retain manifest declaration order, so sort by href
+        // to keep output stable (most EPUBs name files in reading order)
+        entries.sort_by(|a, b| a.href().as_str().cmp(b.href().as_str()));
+
+        let mut chapters = Vec::new();
+        for entry in entries {
+            let html_content = read_document(&entry)?;
+
+            if html_content.trim().is_empty() {
+                continue;
+            }
+
+            chapters.push(RawChapter {
+                title: None,
+                href: entry.href().path().as_str().to_string(),
+                idref: entry.id().to_string(),
                 html_content,
             });
         }
@@ -41,27 +116,226 @@ impl EpubData {
         Ok(chapters)
     }
 
+    /// True for fixed-layout books: `rendition:layout` is `pre-paginated` in the
+    /// package metadata, or every spine item overrides it to pre-paginated
+    pub fn is_fixed_layout(&self) -> bool {
+        let global = self
+            .epub
+            .metadata()
+            .by_property("rendition:layout")
+            .next()
+            .is_some_and(|entry| entry.value().trim() == "pre-paginated");
+        if global {
+            return true;
+        }
+
+        let spine = self.epub.spine();
+        !spine.is_empty()
+            && spine.entries().all(|entry| {
+                entry
+                    .properties()
+                    .has_property("rendition:layout-pre-paginated")
+            })
+    }
+
+    /// Map document hrefs to their landmark type (`cover`, `toc`, `bodymatter`, …)
+    /// from the EPUB 3 nav `landmarks`, or the EPUB 2 `guide` as a fallback
+    fn landmarks(&self) -> HashMap<String, String> {
+        let mut landmarks = HashMap::new();
+        let Some(root) = self.epub.toc().landmarks() else {
+            return landmarks;
+        };
+
+        for entry in root.children().flatten() {
+            let Some(href) = entry.href() else {
+                continue;
+            };
+            let kind = match entry.kind().as_str() {
+                // EPUB 2 guide names the start of the main text "text"
+                "text" => "bodymatter",
+                other => other,
+            };
+            // Keep the first landmark when several point at one document
+            landmarks
+                .entry(href.path().as_str().to_string())
+                .or_insert_with(|| kind.to_string());
+        }
+
+        landmarks
+    }
+
+    /// Print page positions from the EPUB 3 nav `page-list` (or the NCX `pageList`):
+    /// for each document href, the fragment and label of the pages in it, in order
+    fn page_list(&self) -> HashMap<String, Vec<(Option<String>, String)>> {
+        let mut pages: HashMap<String, Vec<_>> = HashMap::new();
+        let Some(root) = self.epub.toc().page_list() else {
+            return pages;
+        };
+
+        for entry in root.children().flatten() {
+            let Some(href) = entry.href() else {
+                continue;
+            };
+            pages
+                .entry(href.path().as_str().to_string())
+                .or_default()
+                .push((
+                    href.fragment().map(|f| f.to_string()),
+                    entry.label().trim().to_string(),
+                ));
+        }
+
+        pages
+    }
+
+    /// The XHTML document of the book an `<iframe>` / `<object>` reference points
+    /// at, matched by filename like image references. External URLs aren't fetched,
+    /// and documents in `chapters` are left out: they are converted on their own
+    fn embedded_document(&self, src: &str, chapters: &HashSet<&str>) -> Option<String> {
+        if src.contains("://") || src.starts_with("data:") {
+            return None;
+        }
+        let name = percent_decode(file_name(src));
+        let entry = self
+            .epub
+            .manifest()
+            .readable_content()
+            .find(|entry| percent_decode(file_name(entry.href().as_str())) == name)?;
+        if chapters.contains(entry.href().path().as_str()) {
+            return None;
+        }
+        read_document(&entry).ok()
+    }
+
+    /// Class emphasis from every stylesheet in the manifest, in href order
+    fn stylesheet_emphasis(&self) -> Result<ClassEmphasis> {
+        let mut styles: Vec<_> = self.epub.manifest().styles().collect();
+        styles.sort_by(|a, b| a.href().as_str().cmp(b.href().as_str()));
+
+        let mut classes = ClassEmphasis::new();
+        for entry in styles {
+            let css = entry
+                .read_str()
+                .with_context(|| format!("Failed to read stylesheet: {}", entry.href().as_str()))?;
+            css::read_class_emphasis(&css, &mut classes);
+        }
+        Ok(classes)
+    }
+
+    /// Clean raw chapter HTML for the single-HTML-file serializer, calling
+    /// `on_chapter` with each section's number and the section count once it's cleaned
+    pub fn html_sections(
+        &self,
+        image_map: &ImageMap,
+        options: &ConvertOptions,
+        on_chapter: &dyn Fn(usize, usize),
+    ) -> Result<Vec<HtmlSection>> {
+        let raw = self.raw_chapters(options)?;
+        let total = raw.len();
+        Ok(raw
+            .into_iter()
+            .enumerate()
+            .map(|(i, raw_ch)| {
+                let section = HtmlSection {
+                    title: raw_ch.title,
+                    href: raw_ch.href,
+                    body: html_output::clean_body(
+                        &raw_ch.html_content,
+                        image_map,
+                        options.html_sanitize,
+                    ),
+                };
+                on_chapter(i + 1, total);
+                section
+            })
+            .collect())
+    }
+}
+
+impl BookReader for EpubData {
     /// Convert raw HTML chapters to markdown with image path rewriting
-    pub fn convert_chapters(&self, image_map: &ImageMap) -> Result<Vec<Chapter>> {
-        let raw = self.raw_chapters()?;
+    fn chapters(
+        &self,
+        image_map: &ImageMap,
+        options: &ConvertOptions,
+        on_chapter: &dyn Fn(usize, usize),
+    ) -> Result<Vec<Chapter>> {
+        let raw = self.raw_chapters(options)?;
+        let landmarks = self.landmarks();
+        let page_list = if options.page_anchors.is_some() {
+            self.page_list()
+        } else {
+            HashMap::new()
+        };
+        let stylesheet_emphasis = if options.css_emphasis {
+            self.stylesheet_emphasis()?
+        } else {
+            ClassEmphasis::new()
+        };
+        let spine_hrefs: HashSet<&str> = raw.iter().map(|ch| ch.href.as_str()).collect();
         let mut chapters = Vec::new();
 
         for raw_ch in &raw {
-            let md_content = markdown::html_to_markdown(&raw_ch.html_content, image_map);
+            let html = if options.css_emphasis {
+                // A chapter's own <style> rules take precedence over the book's stylesheets
+                let mut classes = stylesheet_emphasis.clone();
+                css::read_class_emphasis(
+                    &html_preprocess::inline_styles(&raw_ch.html_content),
+                    &mut classes,
+                );
+                html_preprocess::apply_class_emphasis(&raw_ch.html_content, &classes)
+            } else {
+                raw_ch.html_content.clone()
+            };
+            let html = html_preprocess::replace_embedded(&html, &|src| {
+                if options.inline_embedded {
+                    self.embedded_document(src, &spine_hrefs)
+                } else {
+                    None
+                }
+            });
+            let md_content = match (options.page_anchors, page_list.get(&raw_ch.href)) {
+                (Some(style), Some(pages)) => {
+                    let targets: Vec<_> = pages
+                        .iter()
+                        .enumerate()
+                        .map(|(i, (fragment, _))| (fragment.as_deref(), i))
+                        .collect();
+                    let labels: Vec<_> = pages.iter().map(|(_, label)| label.clone()).collect();
+                    let html = html_preprocess::mark_page_breaks(
+                        &html,
+                        &targets,
+                        markdown::PAGE_PLACEHOLDER,
+                    );
+                    let md = markdown::html_to_markdown(&html, &raw_ch.href, image_map, options);
+                    markdown::restore_page_anchors(&md, &labels, style)
+                }
+                _ => markdown::html_to_markdown(&html, &raw_ch.href, image_map, options),
+            };
             chapters.push(Chapter {
                 title: raw_ch.title.clone(),
                 content: md_content,
+                landmark: landmarks.get(&raw_ch.href).cloned(),
+                source_href: Some(raw_ch.href.clone()),
+                source_idref: Some(raw_ch.idref.clone()),
             });
+            on_chapter(chapters.len(), raw.len());
         }
 
-        Ok(chapters)
-    }
-}
+        // Chapters match the raw documents one to one until body matter is selected
+        let cover = options
+            .keep_cover_as_first_chapter
+            .then(|| cover_page(&raw, &landmarks, self.cover_href().as_deref()))
+            .flatten()
+            .map(|i| chapters.remove(i));
+        if options.body_matter_only {
+            chapters = select_body_matter(chapters);
+        }
+        if let Some(cover) = cover {
+            chapters.insert(0, cover);
+        }
 
-impl BookReader for EpubData {
-    fn chapters(&self) -> Result<Vec<Chapter>> {
-        // When called without an image map, use an empty one
-        self.convert_chapters(&ImageMap::new())
+        Ok(chapters)
     }
 
     fn images(&self) -> Result<Vec<ImageResource>> {
@@ -87,14 +361,80 @@ impl BookReader for EpubData {
         Ok(images)
     }
 
+    fn media(&self) -> Result<Vec<ImageResource>> {
+        let manifest = self.epub.manifest();
+        let mut media = Vec::new();
+        for entry in manifest.audio().chain(manifest.video()) {
+            let href = entry
+                .resource()
+                .key()
+                .value()
+                .unwrap_or("unknown")
+                .to_string();
+
+            let bytes = entry
+                .read_bytes()
+                .with_context(|| format!("Failed to read media file: {}", href))?;
+
+            media.push(ImageResource {
+                original_href: href,
+                data: bytes,
+            });
+        }
+
+        Ok(media)
+    }
+
+    fn nav(&self) -> Vec<NavEntry> {
+        let Some(root) = self.epub.toc().contents() else {
+            return Vec::new();
+        };
+        root.children()
+            .flatten()
+            .map(|entry| NavEntry {
+                // The root sits at depth 0, so its children start at 1
+                depth: entry.depth().saturating_sub(1),
+                title: entry.label().trim().to_string(),
+                href: entry.href().map(|href| href.path().as_str().to_string()),
+            })
+            .collect()
+    }
+
+    fn cover_href(&self) -> Option<String> {
+        self.epub
+            .manifest()
+            .cover_image()
+            .and_then(|entry| entry.resource().key().value().map(|v| v.to_string()))
+    }
+
+    fn cover_image(&self) -> Result<Option<ImageResource>> {
+        let Some(entry) = self.epub.manifest().cover_image() else {
+            return Ok(None);
+        };
+        let href = entry
+            .resource()
+            .key()
+            .value()
+            .unwrap_or("unknown")
+            .to_string();
+        let bytes = entry
+            .read_bytes()
+            .with_context(|| format!("Failed to read cover image: {}", href))?;
+        Ok(Some(ImageResource {
+            original_href: href,
+            data: bytes,
+        }))
+    }
+
     fn metadata(&self) -> Metadata {
         use rbook::prelude::Metadata as RbookMetadata;
         let meta = self.epub.metadata();
+        let has_roles = RbookMetadata::creators(&meta).any(|c| c.main_role().is_some());
         Metadata {
             title: RbookMetadata::title(&meta).map(|t| t.value().to_string()),
-            authors: RbookMetadata::creators(&meta)
-                .map(|c| c.value().to_string())
-                .collect(),
+            authors: reader::dedupe_authors(
+                RbookMetadata::creators(&meta).map(|c| c.value().to_string()),
+            ),
             publisher: RbookMetadata::publishers(&meta)
                 .next()
                 .map(|p| p.value().to_string()),
@@ -104,12 +444,100 @@ impl BookReader for EpubData {
             description: RbookMetadata::descriptions(&meta)
                 .next()
                 .map(|d| d.value().to_string()),
+            date: RbookMetadata::publication_date(&meta).map(|d| d.as_str().to_string()),
+            rights: meta
+                .by_property("dc:rights")
+                .next()
+                .map(|r| r.value().to_string()),
+            primary_authors: has_roles.then(|| {
+                reader::dedupe_authors(
+                    RbookMetadata::creators(&meta)
+                        .filter(|c| c.main_role().is_some_and(|role| role.code() == "aut"))
+                        .map(|c| c.value().to_string()),
+                )
+            }),
+            custom_properties: HashMap::new(),
         }
     }
 }
 
+/// Drop a hand-written contents page: the first chapter with any text (so a
+/// cover image page is looked past) when it is mostly links to other documents
+fn skip_manual_toc(chapters: &mut Vec<RawChapter>) {
+    let first_text = chapters
+        .iter()
+        .position(|ch| html_preprocess::has_visible_text(&ch.html_content));
+    if let Some(i) =
+        first_text.filter(|&i| html_preprocess::is_link_list(&chapters[i].html_content))
+    {
+        chapters.remove(i);
+    }
+}
+
+/// Index of the cover page: the document the `cover` landmark points at, or else the
+/// first document without text that shows the cover image
+fn cover_page(
+    chapters: &[RawChapter],
+    landmarks: &HashMap<String, String>,
+    cover_image: Option<&str>,
+) -> Option<usize> {
+    chapters
+        .iter()
+        .position(|ch| landmarks.get(&ch.href).is_some_and(|kind| kind == "cover"))
+        .or_else(|| {
+            let image = html_preprocess::file_name(cover_image?);
+            chapters.iter().position(|ch| {
+                !html_preprocess::has_visible_text(&ch.html_content)
+                    && ch.html_content.contains(image)
+            })
+        })
+}
+
+/// Keep chapters from the `bodymatter` landmark up to the first `backmatter` one.
+/// Books without a bodymatter landmark are returned unchanged.
+fn select_body_matter(chapters: Vec<Chapter>) -> Vec<Chapter> {
+    let has_landmark = |c: &Chapter, kind: &str| c.landmark.as_deref() == Some(kind);
+
+    if !chapters.iter().any(|c| has_landmark(c, "bodymatter")) {
+        eprintln!("Warning: no bodymatter landmark found, keeping all chapters");
+        return chapters;
+    }
+
+    chapters
+        .into_iter()
+        .skip_while(|c| !has_landmark(c, "bodymatter"))
+        .take_while(|c| !has_landmark(c, "backmatter"))
+        .collect()
+}
+
+/// Read an XHTML document as text, transcoding legacy encodings to UTF-8
+fn read_document(entry: &rbook::epub::manifest::EpubManifestEntry) -> Result<String> {
+    let href = entry.href().as_str();
+    let bytes = entry
+        .read_bytes()
+        .with_context(|| format!("Failed to read document: {}", href))?;
+
+    let (text, decoding) = encoding::decode_document(&bytes);
+    if decoding == Decoding::LossyUtf8 {
+        eprintln!(
+            "Warning: {} is not valid UTF-8 and its declared encoding is unsupported; \
+             invalid bytes were replaced",
+            href
+        );
+    }
+    Ok(text)
+}
+
+fn is_nav_document(entry: &rbook::epub::manifest::EpubManifestEntry) -> bool {
+    entry.properties().has_property("nav")
+}
+
 /// Internal raw chapter before markdown conversion
 struct RawChapter {
     title: Option<String>,
+    /// Absolute manifest href (without fragment)
+    href: String,
+    /// Spine `idref`, or the manifest `id` when read in manifest order
+    idref: String,
     html_content: String,
 }
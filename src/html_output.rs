@@ -0,0 +1,275 @@
+use crate::html_preprocess::{self, extract_body, file_name, percent_decode, remove_elements};
+use crate::image::ImageMap;
+use crate::reader::Metadata;
+use anyhow::{Context, Result};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// Reading typography inlined with `--with-css`
+const STYLESHEET: &str = "\
+body { max-width: 40em; margin: 2em auto; padding: 0 1em; font-family: Georgia, \"Times New Roman\", serif; font-size: 1.1em; line-height: 1.6; color: #222; background: #fdfdfb; }
+h1, h2, h3, h4, h5, h6 { font-family: \"Helvetica Neue\", Arial, sans-serif; line-height: 1.25; }
+.title-block { text-align: center; margin-bottom: 3em; padding-bottom: 2em; border-bottom: 1px solid #ccc; }
+.title-block .author { font-size: 1.2em; font-style: italic; }
+.title-block .publisher, .title-block .description { color: #555; }
+article.chapter + article.chapter { margin-top: 3em; padding-top: 2em; border-top: 1px solid #eee; }
+img { max-width: 100%; height: auto; }
+blockquote { margin-left: 0; padding-left: 1em; border-left: 3px solid #ccc; color: #555; }
+pre, code { font-family: Menlo, Consolas, monospace; font-size: 0.9em; }
+pre { overflow-x: auto; padding: 0.75em; background: #f4f4f4; }
+table { border-collapse: collapse; }
+td, th { padding: 0.3em 0.6em; border: 1px solid #ccc; }
+@media print { body { max-width: none; background: none; } }
+";
+
+/// One chapter's cleaned `<body>` markup, ready to be wrapped in a `<section>` or `<article>`
+pub struct HtmlSection {
+    pub title: Option<String>,
+    /// Manifest href of the source document, for linking between chapters
+    pub href: String,
+    pub body: String,
+}
+
+/// Extract the `<body>` contents of an XHTML document, drop scripts/styles (and with
+/// `sanitize` event handlers and `javascript:` URLs), and point image references at
+/// the extracted `images/` paths
+pub fn clean_body(html: &str, image_map: &ImageMap, sanitize: bool) -> String {
+    let body = extract_body(html);
+    let body = if sanitize {
+        html_preprocess::sanitize(body)
+    } else {
+        let body = remove_elements(body, "script");
+        remove_elements(&body, "style")
+    };
+    rewrite_resource_refs(&body, image_map).trim().to_string()
+}
+
+/// Serialize the book as one HTML document. `styled` inlines a stylesheet, opens
+/// with a title block and wraps the chapters in `<main>` / `<article>` elements
+pub fn render_html(meta: &Metadata, sections: &[HtmlSection], styled: bool) -> String {
+    let mut out = String::new();
+
+    out.push_str("<!DOCTYPE html>\n");
+    match meta.language.as_deref().filter(|l| !l.trim().is_empty()) {
+        Some(lang) => out.push_str(&format!("<html lang=\"{}\">\n", escape(lang))),
+        None => out.push_str("<html>\n"),
+    }
+    out.push_str("<head>\n<meta charset=\"utf-8\">\n");
+
+    if let Some(title) = meta.title.as_deref().filter(|t| !t.trim().is_empty()) {
+        out.push_str(&format!("<title>{}</title>\n", escape(title)));
+    }
+    let authors: Vec<_> = meta
+        .authors
+        .iter()
+        .filter(|a| !a.trim().is_empty())
+        .map(|a| a.as_str())
+        .collect();
+    if !authors.is_empty() {
+        out.push_str(&format!(
+            "<meta name=\"author\" content=\"{}\">\n",
+            escape(&authors.join(", "))
+        ));
+    }
+    if let Some(publisher) = meta.publisher.as_deref().filter(|p| !p.trim().is_empty()) {
+        out.push_str(&format!(
+            "<meta name=\"publisher\" content=\"{}\">\n",
+            escape(publisher)
+        ));
+    }
+    if let Some(description) = meta.description.as_deref().filter(|d| !d.trim().is_empty()) {
+        out.push_str(&format!(
+            "<meta name=\"description\" content=\"{}\">\n",
+            escape(description)
+        ));
+    }
+    if styled {
+        out.push_str(&format!("<style>\n{}</style>\n", STYLESHEET));
+    }
+    out.push_str("</head>\n<body>\n");
+
+    let element = if styled {
+        out.push_str(&title_block(meta, &authors));
+        out.push_str("<main>\n");
+        "article"
+    } else {
+        "section"
+    };
+    let section_ids: HashMap<_, String> = sections
+        .iter()
+        .enumerate()
+        .map(|(i, section)| (percent_decode(file_name(&section.href)), section_id(i)))
+        .collect();
+    for (i, section) in sections.iter().enumerate() {
+        out.push_str(&format!("<{} id=\"{}\"", element, section_id(i)));
+        if styled {
+            out.push_str(" class=\"chapter\"");
+        }
+        if let Some(ref title) = section.title {
+            out.push_str(&format!(" title=\"{}\"", escape(title)));
+        }
+        out.push_str(">\n");
+        out.push_str(&link_sections(&section.body, &section_ids));
+        out.push_str(&format!("\n</{}>\n", element));
+    }
+    if styled {
+        out.push_str("</main>\n");
+    }
+
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+fn section_id(index: usize) -> String {
+    format!("chapter-{:02}", index + 1)
+}
+
+/// Point links at another chapter's source document into the single file:
+/// `chapter2.xhtml#note` becomes `#note`, a bare `chapter2.xhtml` the chapter's section
+fn link_sections(body: &str, section_ids: &HashMap<Cow<'_, str>, String>) -> String {
+    let mut result = String::with_capacity(body.len());
+    let mut rest = body;
+
+    while let Some(pos) = find_ref_attr(rest) {
+        let (attr_len, quote) = pos.1;
+        let value_start = pos.0 + attr_len;
+        result.push_str(&rest[..value_start]);
+        rest = &rest[value_start..];
+
+        let Some(value_len) = rest.find(quote) else {
+            break;
+        };
+        let value = &rest[..value_len];
+        let (document, fragment) = match value.split_once('#') {
+            Some((document, fragment)) => (document, Some(fragment)),
+            None => (value, None),
+        };
+        let section = (!document.is_empty() && !document.contains("://"))
+            .then(|| section_ids.get(&percent_decode(file_name(document))))
+            .flatten();
+        match (section, fragment) {
+            (Some(_), Some(fragment)) => result.push_str(&format!("#{}", fragment)),
+            (Some(id), None) => result.push_str(&format!("#{}", id)),
+            (None, _) => result.push_str(value),
+        }
+        rest = &rest[value_len..];
+    }
+
+    result.push_str(rest);
+    result
+}
+
+/// `<header>` showing the title, authors, publisher and description
+fn title_block(meta: &Metadata, authors: &[&str]) -> String {
+    let mut block = String::from("<header class=\"title-block\">\n");
+    if let Some(title) = meta.title.as_deref().filter(|t| !t.trim().is_empty()) {
+        block.push_str(&format!("<h1 class=\"title\">{}</h1>\n", escape(title)));
+    }
+    if !authors.is_empty() {
+        block.push_str(&format!(
+            "<p class=\"author\">{}</p>\n",
+            escape(&authors.join(", "))
+        ));
+    }
+    if let Some(publisher) = meta.publisher.as_deref().filter(|p| !p.trim().is_empty()) {
+        block.push_str(&format!(
+            "<p class=\"publisher\">{}</p>\n",
+            escape(publisher)
+        ));
+    }
+    if let Some(description) = meta.description.as_deref().filter(|d| !d.trim().is_empty()) {
+        block.push_str(&format!(
+            "<p class=\"description\">{}</p>\n",
+            escape(description)
+        ));
+    }
+    block.push_str("</header>\n");
+    block
+}
+
+pub fn write_html(
+    output_path: &Path,
+    meta: &Metadata,
+    sections: &[HtmlSection],
+    styled: bool,
+) -> Result<()> {
+    if let Some(parent) = output_path.parent() {
+        if !parent.as_os_str().is_empty() {
+            fs::create_dir_all(parent)?;
+        }
+    }
+
+    fs::write(output_path, render_html(meta, sections, styled))
+        .with_context(|| format!("Failed to write output file: {}", output_path.display()))?;
+
+    Ok(())
+}
+
+/// Rewrite `src="..."` / `href="..."` values whose filename matches an extracted image
+fn rewrite_resource_refs(html: &str, image_map: &ImageMap) -> String {
+    let by_filename: HashMap<_, &str> = image_map
+        .iter()
+        .map(|(original, replacement)| {
+            let name = percent_decode(file_name(original));
+            (name, replacement.as_str())
+        })
+        .collect();
+
+    let mut result = String::with_capacity(html.len());
+    let mut rest = html;
+
+    while let Some(pos) = find_ref_attr(rest) {
+        let (attr_len, quote) = pos.1;
+        let value_start = pos.0 + attr_len;
+        result.push_str(&rest[..value_start]);
+        rest = &rest[value_start..];
+
+        let Some(value_len) = rest.find(quote) else {
+            break;
+        };
+        let value = &rest[..value_len];
+        match by_filename.get(&percent_decode(file_name(value))) {
+            Some(replacement) => result.push_str(replacement),
+            None => result.push_str(value),
+        }
+        rest = &rest[value_len..];
+    }
+
+    result.push_str(rest);
+    result
+}
+
+/// Find the next `src=`/`href=` attribute; returns its offset, length and quote char
+fn find_ref_attr(html: &str) -> Option<(usize, (usize, char))> {
+    ["src=\"", "src='", "href=\"", "href='"]
+        .iter()
+        .filter_map(|attr| {
+            let quote = attr.chars().last()?;
+            html.find(attr).map(|i| (i, (attr.len(), quote)))
+        })
+        .min_by_key(|(i, _)| *i)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn links_between_chapters_stay_in_the_document() {
+        let section_ids = HashMap::from([(Cow::from("c2.xhtml"), "chapter-02".to_string())]);
+        let body = r#"<a href="c2.xhtml#note">note</a> <a href="../Text/c2.xhtml">two</a> <a href="https://example.com/c2.xhtml">web</a> <a href="other.xhtml#x">other</a>"#;
+        assert_eq!(
+            link_sections(body, &section_ids),
+            r##"<a href="#note">note</a> <a href="#chapter-02">two</a> <a href="https://example.com/c2.xhtml">web</a> <a href="other.xhtml#x">other</a>"##
+        );
+    }
+}
@@ -0,0 +1,1215 @@
+//! HTML rewrites applied to EPUB chapter markup before html2md conversion,
+//! for elements html2md drops or renders poorly
+
+use crate::css::{ClassEmphasis, Emphasis};
+use std::borrow::Cow;
+use std::collections::HashSet;
+
+/// Replace `<q>` elements with explicit quotation marks. Glyphs follow the
+/// document's `lang`/`xml:lang`; without one, ASCII quotes are used.
+pub fn replace_inline_quotes(html: &str) -> String {
+    let lower = html.to_ascii_lowercase();
+    if !lower.contains("<q") {
+        return html.to_string();
+    }
+
+    let (outer, inner) = quote_marks(document_language(&lower).as_deref());
+    let mut result = String::with_capacity(html.len());
+    let mut pos = 0;
+    let mut depth = 0usize;
+
+    while let Some(offset) = lower[pos..].find('<') {
+        let start = pos + offset;
+        let rest = &lower[start..];
+
+        let (replacement, tag_len) = if is_tag(rest, "<q") {
+            let Some(end) = rest.find('>') else {
+                break;
+            };
+            let marks = if depth.is_multiple_of(2) {
+                outer
+            } else {
+                inner
+            };
+            depth += 1;
+            (marks.0, end + 1)
+        } else if rest.starts_with("</q>") {
+            depth = depth.saturating_sub(1);
+            let marks = if depth.is_multiple_of(2) {
+                outer
+            } else {
+                inner
+            };
+            (marks.1, "</q>".len())
+        } else {
+            result.push_str(&html[pos..start + 1]);
+            pos = start + 1;
+            continue;
+        };
+
+        result.push_str(&html[pos..start]);
+        result.push_str(replacement);
+        pos = start + tag_len;
+    }
+
+    result.push_str(&html[pos..]);
+    result
+}
+
+/// Rewrite `<var>` and `<samp>` as `<code>` and `<dfn>` as `<em>`, which html2md
+/// renders, outside `<pre>`. Elements inside an inline code span stay as they are,
+/// since code spans can't nest
+pub fn replace_semantic_elements(html: &str) -> String {
+    let lower = html.to_ascii_lowercase();
+    if !["<var", "<samp", "<dfn"]
+        .iter()
+        .any(|tag| lower.contains(tag))
+    {
+        return html.to_string();
+    }
+
+    let mut result = String::with_capacity(html.len());
+    let mut pre_depth = 0usize;
+    // Open `<code>`, `<var>` and `<samp>` elements, and whether each was rewritten
+    let mut code_spans: Vec<bool> = Vec::new();
+    let mut pos = 0;
+
+    while let Some(offset) = lower[pos..].find('<') {
+        let start = pos + offset;
+        let rest = &lower[start..];
+        let Some(end) = rest.find('>').map(|i| start + i + 1) else {
+            break;
+        };
+
+        result.push_str(&html[pos..start]);
+        let replacement = if is_tag(rest, "<pre") {
+            pre_depth += 1;
+            None
+        } else if rest.starts_with("</pre") {
+            pre_depth = pre_depth.saturating_sub(1);
+            None
+        } else if is_tag(rest, "<code") {
+            code_spans.push(false);
+            None
+        } else if is_tag(rest, "<var") || is_tag(rest, "<samp") {
+            let rewrite = pre_depth == 0 && code_spans.is_empty();
+            code_spans.push(rewrite);
+            rewrite.then_some("<code>")
+        } else if is_tag(rest, "</code") || is_tag(rest, "</var") || is_tag(rest, "</samp") {
+            code_spans
+                .pop()
+                .filter(|&rewritten| rewritten)
+                .map(|_| "</code>")
+        } else if is_tag(rest, "<dfn") && pre_depth == 0 {
+            Some("<em>")
+        } else if is_tag(rest, "</dfn") && pre_depth == 0 {
+            Some("</em>")
+        } else {
+            None
+        };
+
+        result.push_str(replacement.unwrap_or(&html[start..end]));
+        pos = end;
+    }
+
+    result.push_str(&html[pos..]);
+    result
+}
+
+/// Replace `<br>` elements inside `<h1>`…`<h6>` with a space, so a heading with a
+/// manual line break stays on one markdown line
+pub fn flatten_heading_breaks(html: &str) -> String {
+    let lower = html.to_ascii_lowercase();
+    let mut result = String::with_capacity(html.len());
+    let mut in_heading = false;
+    let mut pos = 0;
+
+    while let Some(offset) = lower[pos..].find('<') {
+        let start = pos + offset;
+        let rest = &lower[start..];
+        let Some(end) = rest.find('>').map(|i| start + i + 1) else {
+            break;
+        };
+
+        result.push_str(&html[pos..start]);
+        if is_heading_tag(rest, "<") {
+            in_heading = true;
+        } else if is_heading_tag(rest, "</") {
+            in_heading = false;
+        }
+
+        if in_heading && is_tag(rest, "<br") {
+            // A trailing break would leave html2md a space before the closing tag
+            if !is_heading_tag(lower[end..].trim_start(), "</") {
+                result.push(' ');
+            }
+        } else {
+            result.push_str(&html[start..end]);
+        }
+        pos = end;
+    }
+
+    result.push_str(&html[pos..]);
+    result
+}
+
+/// Whether `rest` starts with an `<h1>`…`<h6>` tag; `open` is `<` or `</`
+fn is_heading_tag(rest: &str, open: &str) -> bool {
+    (1..=6).any(|level| is_tag(rest, &format!("{}h{}", open, level)))
+}
+
+/// Replace `<br>` elements outside `<pre>` and tables with `marker`, so html2md
+/// keeps them on one line for the caller to turn into hard line breaks
+pub fn mark_line_breaks(html: &str, marker: &str) -> String {
+    let lower = html.to_ascii_lowercase();
+    let mut result = String::with_capacity(html.len());
+    let mut pre_depth = 0usize;
+    let mut table_depth = 0usize;
+    let mut pos = 0;
+
+    while let Some(offset) = lower[pos..].find('<') {
+        let start = pos + offset;
+        let rest = &lower[start..];
+        let Some(end) = rest.find('>').map(|i| start + i + 1) else {
+            break;
+        };
+
+        result.push_str(&html[pos..start]);
+        if is_tag(rest, "<pre") {
+            pre_depth += 1;
+        } else if rest.starts_with("</pre") {
+            pre_depth = pre_depth.saturating_sub(1);
+        } else if is_tag(rest, "<table") {
+            table_depth += 1;
+        } else if rest.starts_with("</table") {
+            table_depth = table_depth.saturating_sub(1);
+        }
+
+        if is_tag(rest, "<br") && pre_depth == 0 && table_depth == 0 {
+            result.push_str(marker);
+        } else {
+            result.push_str(&html[start..end]);
+        }
+        pos = end;
+    }
+
+    result.push_str(&html[pos..]);
+    result
+}
+
+/// Replace `<ins>` / `<del>` tags outside `<pre>` and `<code>` with `marker` and a
+/// code (`0`/`1` opening/closing `<ins>`, `2`/`3` for `<del>`) ending in `X`, as
+/// html2md drops insertions and would strike deletions its own way
+pub fn mark_edits(html: &str, marker: &str) -> String {
+    let lower = html.to_ascii_lowercase();
+    if !lower.contains("<ins") && !lower.contains("<del") {
+        return html.to_string();
+    }
+
+    let mut result = String::with_capacity(html.len());
+    let mut code_depth = 0usize;
+    let mut pos = 0;
+
+    while let Some(offset) = lower[pos..].find('<') {
+        let start = pos + offset;
+        let rest = &lower[start..];
+        let Some(end) = rest.find('>').map(|i| start + i + 1) else {
+            break;
+        };
+
+        result.push_str(&html[pos..start]);
+        if is_tag(rest, "<pre") || is_tag(rest, "<code") {
+            code_depth += 1;
+        } else if rest.starts_with("</pre") || rest.starts_with("</code") {
+            code_depth = code_depth.saturating_sub(1);
+        }
+
+        let code = ["<ins", "</ins", "<del", "</del"]
+            .iter()
+            .position(|tag| is_tag(rest, tag));
+        match code {
+            Some(code) if code_depth == 0 => {
+                result.push_str(&format!("{}{}X", marker, code));
+            }
+            _ => result.push_str(&html[start..end]),
+        }
+        pos = end;
+    }
+
+    result.push_str(&html[pos..]);
+    result
+}
+
+/// Insert `marker` followed by the page's index and an `X` terminator before each
+/// element whose `id` is a page target's fragment, or in a paragraph of its own at
+/// the start of the body for a target without one. Targets whose element isn't
+/// found are left out
+pub fn mark_page_breaks(html: &str, targets: &[(Option<&str>, usize)], marker: &str) -> String {
+    let mut inserts: Vec<(usize, bool, usize)> = targets
+        .iter()
+        .filter_map(|&(fragment, index)| {
+            let at = match fragment {
+                Some(id) => ["id=\"", "id='"].iter().find_map(|attr| {
+                    let quote = &attr[3..];
+                    let value = html.find(&format!(" {}{}{}", attr, id, quote))?;
+                    html[..value].rfind('<')
+                })?,
+                None => {
+                    let lower = html.to_ascii_lowercase();
+                    let body = lower.find("<body")?;
+                    body + lower[body..].find('>')? + 1
+                }
+            };
+            Some((at, fragment.is_none(), index))
+        })
+        .collect();
+    inserts.sort();
+
+    let mut result = String::with_capacity(html.len() + inserts.len() * 16);
+    let mut pos = 0;
+    for (at, own_paragraph, index) in inserts {
+        result.push_str(&html[pos..at]);
+        if own_paragraph {
+            result.push_str(&format!("<p>{}{}X</p>", marker, index));
+        } else {
+            result.push_str(&format!("{}{}X", marker, index));
+        }
+        pos = at;
+    }
+    result.push_str(&html[pos..]);
+    result
+}
+
+/// Class names and `epub:type` values that mark an element as verse
+const VERSE_CLASSES: [&str; 3] = ["poem", "verse", "stanza"];
+const VERSE_TYPES: [&str; 2] = ["z3998:verse", "z3998:poem"];
+
+/// Keep the lines of verse apart. Inside elements marked as verse (`class="poem"`,
+/// `class="verse"`, `epub:type="z3998:verse"`, …), `<br>`s and the ends of line
+/// elements (`class="line"`, `epub:type="z3998:line"`) become `marker`; block line
+/// elements turn into spans so a stanza stays one paragraph. Consecutive verse
+/// paragraphs (`<p class="verse">` per line) are joined the same way
+pub fn mark_verse(html: &str, marker: &str) -> String {
+    let lower = html.to_ascii_lowercase();
+    if !VERSE_CLASSES
+        .iter()
+        .chain(&VERSE_TYPES)
+        .any(|v| lower.contains(v))
+    {
+        return html.to_string();
+    }
+
+    let mut result = String::with_capacity(html.len());
+    // Name and nesting depth of the open verse element and of the open line element
+    let mut verse: Option<(String, usize)> = None;
+    let mut line: Option<(String, usize, bool)> = None;
+    // Length of `result` before the `</p>` that closed the last verse paragraph
+    let mut joinable: Option<usize> = None;
+    let mut pos = 0;
+
+    while let Some(offset) = lower[pos..].find('<') {
+        let start = pos + offset;
+        let rest = &lower[start..];
+        let Some(end) = rest.find('>').map(|i| start + i + 1) else {
+            break;
+        };
+        result.push_str(&html[pos..start]);
+        pos = end;
+
+        let tag = &html[start..end];
+        let name = tag_name(&lower[start..end]);
+        let closing = rest.starts_with("</");
+        let self_closing = lower[start..end].ends_with("/>");
+
+        let Some((verse_name, verse_depth)) = verse.as_mut() else {
+            if !closing && !self_closing && is_verse(tag) {
+                // A verse paragraph right after another continues its stanza
+                let follows = joinable.filter(|&len| result[len..].trim() == "</p>");
+                match follows {
+                    Some(len) if name == "p" => {
+                        result.truncate(len);
+                        result.push_str(marker);
+                    }
+                    _ => result.push_str(tag),
+                }
+                verse = Some((name.to_string(), 1));
+            } else {
+                result.push_str(tag);
+            }
+            continue;
+        };
+
+        if name == verse_name && !self_closing {
+            if closing {
+                *verse_depth -= 1;
+            } else {
+                *verse_depth += 1;
+            }
+            if *verse_depth == 0 {
+                joinable = (name == "p").then_some(result.len());
+                result.push_str(tag);
+                verse = None;
+                line = None;
+                continue;
+            }
+        }
+
+        if name == "br" {
+            result.push_str(marker);
+            continue;
+        }
+
+        match line.as_mut() {
+            Some((line_name, line_depth, block)) if name == line_name && !self_closing => {
+                if closing {
+                    *line_depth -= 1;
+                } else {
+                    *line_depth += 1;
+                }
+                if *line_depth > 0 {
+                    result.push_str(tag);
+                } else {
+                    result.push_str(if *block { "</span>" } else { tag });
+                    result.push_str(marker);
+                    line = None;
+                }
+            }
+            None if !closing && !self_closing && is_verse_line(tag) => {
+                let block = matches!(name, "p" | "div");
+                if block {
+                    result.push_str("<span>");
+                } else {
+                    result.push_str(tag);
+                }
+                line = Some((name.to_string(), 1, block));
+            }
+            _ => result.push_str(tag),
+        }
+    }
+
+    result.push_str(&html[pos..]);
+    result
+}
+
+/// Lowercase element name of an opening or closing tag
+fn tag_name(tag: &str) -> &str {
+    let name = tag.trim_start_matches('<').trim_start_matches('/');
+    let len = name
+        .find(|c: char| c.is_whitespace() || c == '>' || c == '/')
+        .unwrap_or(name.len());
+    &name[..len]
+}
+
+/// True for an opening tag marked as a poem, stanza or verse paragraph
+fn is_verse(tag: &str) -> bool {
+    has_token(tag, "class", &VERSE_CLASSES) || has_token(tag, "epub:type", &VERSE_TYPES)
+}
+
+/// True for an opening tag marked as one line of verse; inside a poem, a verse
+/// paragraph is a line too
+fn is_verse_line(tag: &str) -> bool {
+    has_token(tag, "class", &["line"])
+        || has_token(tag, "epub:type", &["z3998:line"])
+        || (tag_name(&tag.to_ascii_lowercase()) == "p" && has_token(tag, "class", &["verse"]))
+}
+
+/// True when the whitespace-separated attribute value contains one of `tokens`
+fn has_token(tag: &str, attribute: &str, tokens: &[&str]) -> bool {
+    attribute_value(tag, attribute).is_some_and(|value| {
+        value
+            .split_whitespace()
+            .any(|token| tokens.iter().any(|t| token.eq_ignore_ascii_case(t)))
+    })
+}
+
+/// Fill paragraphs with no visible content (whitespace, `&nbsp;`, `<br/>`)
+/// with `marker` so they survive conversion
+pub fn mark_empty_paragraphs(html: &str, marker: &str) -> String {
+    let lower = html.to_ascii_lowercase();
+    let mut result = String::with_capacity(html.len());
+    let mut pos = 0;
+
+    while let Some(offset) = lower[pos..].find("<p") {
+        let start = pos + offset;
+        let rest = &lower[start..];
+        let (Some(open_len), true) = (rest.find('>'), is_tag(rest, "<p")) else {
+            result.push_str(&html[pos..start + 2]);
+            pos = start + 2;
+            continue;
+        };
+        let content_start = start + open_len + 1;
+
+        // Self-closing <p/> has no content at all
+        let self_closing = lower[..content_start].ends_with("/>");
+        let content_end = if self_closing {
+            Some(content_start)
+        } else {
+            lower[content_start..]
+                .find("</p>")
+                .map(|i| content_start + i)
+        };
+        let Some(content_end) = content_end else {
+            break;
+        };
+
+        let inner = strip_tags(&html[content_start..content_end]).replace("&nbsp;", "");
+        let inner = decode_entities(&inner);
+        if inner.chars().all(|c| c.is_whitespace()) {
+            result.push_str(&html[pos..start]);
+            result.push_str(&format!("<p>{}</p>", marker));
+            pos = if self_closing {
+                content_start
+            } else {
+                content_end + "</p>".len()
+            };
+        } else {
+            result.push_str(&html[pos..content_start]);
+            pos = content_start;
+        }
+    }
+
+    result.push_str(&html[pos..]);
+    result
+}
+
+/// Wrap the content of `<span class="…">` elements whose classes the stylesheets
+/// make bold or italic in `<strong>` / `<em>`, which html2md understands
+pub fn apply_class_emphasis(html: &str, classes: &ClassEmphasis) -> String {
+    if classes.is_empty() {
+        return html.to_string();
+    }
+
+    let lower = html.to_ascii_lowercase();
+    let mut result = String::with_capacity(html.len());
+    let mut pos = 0;
+    // Closing tags to emit before each open span's `</span>`
+    let mut closers: Vec<&str> = Vec::new();
+
+    while let Some(offset) = lower[pos..].find('<') {
+        let start = pos + offset;
+        let rest = &lower[start..];
+
+        if is_tag(rest, "<span") {
+            let Some(end) = rest.find('>').map(|i| start + i + 1) else {
+                break;
+            };
+            result.push_str(&html[pos..end]);
+            pos = end;
+            if lower[..end].ends_with("/>") {
+                continue;
+            }
+
+            let emphasis = span_emphasis(&html[start..end], classes);
+            let (bold, italic) = (emphasis.bold == Some(true), emphasis.italic == Some(true));
+            match (bold, italic) {
+                (true, true) => result.push_str("<strong><em>"),
+                (true, false) => result.push_str("<strong>"),
+                (false, true) => result.push_str("<em>"),
+                (false, false) => {}
+            }
+            closers.push(match (bold, italic) {
+                (true, true) => "</em></strong>",
+                (true, false) => "</strong>",
+                (false, true) => "</em>",
+                (false, false) => "",
+            });
+        } else if rest.starts_with("</span") {
+            result.push_str(&html[pos..start]);
+            result.push_str(closers.pop().unwrap_or_default());
+            result.push('<');
+            pos = start + 1;
+        } else {
+            result.push_str(&html[pos..start + 1]);
+            pos = start + 1;
+        }
+    }
+
+    result.push_str(&html[pos..]);
+    result
+}
+
+/// Spell out `<abbr title="…">` as `ABBR (title)` on its first use in the document
+/// and unwrap `<abbr>` / `<time>` elements to their display text
+pub fn expand_abbreviations(html: &str) -> String {
+    let lower = html.to_ascii_lowercase();
+    let mut result = String::with_capacity(html.len());
+    let mut expanded = HashSet::new();
+    let mut pos = 0;
+
+    while let Some(offset) = lower[pos..].find('<') {
+        let start = pos + offset;
+        let rest = &lower[start..];
+
+        if is_tag(rest, "<abbr") {
+            let Some(open_end) = rest.find('>').map(|i| start + i + 1) else {
+                break;
+            };
+            let Some(close) = lower[open_end..].find("</abbr>").map(|i| open_end + i) else {
+                break;
+            };
+
+            let text = &html[open_end..close];
+            result.push_str(&html[pos..start]);
+            result.push_str(text);
+            let title = attribute_value(&html[start..open_end], "title")
+                .map(str::trim)
+                .filter(|t| !t.is_empty());
+            if let Some(title) = title {
+                if expanded.insert(strip_tags(text).trim().to_string()) {
+                    result.push_str(&format!(" ({})", title));
+                }
+            }
+            pos = close + "</abbr>".len();
+        } else if is_tag(rest, "<time") || rest.starts_with("</time>") {
+            let Some(end) = rest.find('>').map(|i| start + i + 1) else {
+                break;
+            };
+            result.push_str(&html[pos..start]);
+            pos = end;
+        } else {
+            result.push_str(&html[pos..start + 1]);
+            pos = start + 1;
+        }
+    }
+
+    result.push_str(&html[pos..]);
+    result
+}
+
+/// An audio or video file referenced by a `<audio>` / `<video>` element
+pub struct MediaFile {
+    /// `Audio` or `Video`
+    pub kind: &'static str,
+    /// Reference as written in the chapter, relative to it
+    pub src: String,
+}
+
+/// Replace `<audio>` and `<video>` elements, which html2md drops, with the
+/// video's poster image and a caption naming the media type and files.
+/// Returns the rewritten HTML and every file the elements reference.
+pub fn replace_media(html: &str) -> (String, Vec<MediaFile>) {
+    let lower = html.to_ascii_lowercase();
+    let mut result = String::with_capacity(html.len());
+    let mut files = Vec::new();
+    let mut pos = 0;
+
+    while let Some(offset) = lower[pos..].find('<') {
+        let start = pos + offset;
+        let rest = &lower[start..];
+        let Some((tag, kind)) = [("audio", "Audio"), ("video", "Video")]
+            .into_iter()
+            .find(|(tag, _)| is_tag(rest, &format!("<{}", tag)))
+        else {
+            result.push_str(&html[pos..start + 1]);
+            pos = start + 1;
+            continue;
+        };
+
+        let Some(open_end) = rest.find('>').map(|i| start + i + 1) else {
+            break;
+        };
+        let closing = format!("</{}>", tag);
+        let (inner, end) = if lower[..open_end].ends_with("/>") {
+            (open_end..open_end, open_end)
+        } else {
+            match lower[open_end..].find(&closing).map(|i| open_end + i) {
+                Some(close) => (open_end..close, close + closing.len()),
+                None => break,
+            }
+        };
+
+        let open_tag = &html[start..open_end];
+        let mut sources: Vec<&str> = attribute_value(open_tag, "src").into_iter().collect();
+        let mut inner_pos = inner.start;
+        while let Some(i) = lower[inner_pos..inner.end].find("<source") {
+            let source_start = inner_pos + i;
+            let Some(source_end) = lower[source_start..].find('>') else {
+                break;
+            };
+            let source_tag = &html[source_start..source_start + source_end + 1];
+            sources.extend(attribute_value(source_tag, "src"));
+            inner_pos = source_start + source_end + 1;
+        }
+
+        result.push_str(&html[pos..start]);
+        if let Some(poster) = attribute_value(open_tag, "poster") {
+            result.push_str(&format!(
+                "<p><img src=\"{}\" alt=\"{} poster\"/></p>",
+                poster, kind
+            ));
+        }
+        let names: Vec<&str> = sources.iter().map(|s| file_name(s)).collect();
+        if names.is_empty() {
+            result.push_str(&format!("<p><em>{}</em></p>", kind));
+        } else {
+            result.push_str(&format!("<p><em>{}: {}</em></p>", kind, names.join(", ")));
+        }
+        files.extend(sources.into_iter().map(|src| MediaFile {
+            kind,
+            src: src.to_string(),
+        }));
+        pos = end;
+    }
+
+    result.push_str(&html[pos..]);
+    (result, files)
+}
+
+/// Replace `<iframe>`, `<object>` and `<embed>` elements, which html2md drops or
+/// passes through, with an `[embedded content: src]` note. `inline` may return the
+/// HTML of a referenced document in the book to splice in instead; its own embedded
+/// elements are only noted. An object's fallback content is kept after the note
+pub fn replace_embedded(html: &str, inline: &dyn Fn(&str) -> Option<String>) -> String {
+    let lower = html.to_ascii_lowercase();
+    let mut result = String::with_capacity(html.len());
+    let mut pos = 0;
+
+    while let Some(offset) = lower[pos..].find('<') {
+        let start = pos + offset;
+        let rest = &lower[start..];
+        let Some(tag) = ["iframe", "object", "embed"]
+            .into_iter()
+            .find(|tag| is_tag(rest, &format!("<{}", tag)))
+        else {
+            result.push_str(&html[pos..start + 1]);
+            pos = start + 1;
+            continue;
+        };
+
+        let Some(open_end) = rest.find('>').map(|i| start + i + 1) else {
+            break;
+        };
+        let closing = format!("</{}>", tag);
+        // `<embed>` has no content or closing tag
+        let (inner, end) = if tag == "embed" || lower[..open_end].ends_with("/>") {
+            (open_end..open_end, open_end)
+        } else {
+            match lower[open_end..].find(&closing).map(|i| open_end + i) {
+                Some(close) => (open_end..close, close + closing.len()),
+                None => break,
+            }
+        };
+
+        let open_tag = &html[start..open_end];
+        let src = attribute_value(open_tag, if tag == "object" { "data" } else { "src" })
+            .map(str::trim)
+            .filter(|src| !src.is_empty());
+
+        result.push_str(&html[pos..start]);
+        match src.and_then(inline) {
+            Some(document) => {
+                result.push_str("<div>");
+                result.push_str(&replace_embedded(extract_body(&document), &|_| None));
+                result.push_str("</div>");
+            }
+            None => {
+                match src {
+                    Some(src) => result.push_str(&format!(
+                        "<p>[embedded content: {}]</p>",
+                        src.replace('<', "&lt;")
+                    )),
+                    None => result.push_str("<p>[embedded content]</p>"),
+                }
+                if tag == "object" {
+                    result.push_str(&html[inner]);
+                }
+            }
+        }
+        pos = end;
+    }
+
+    result.push_str(&html[pos..]);
+    result
+}
+
+/// Drop `<script>` and `<style>` elements, `on*` event handler attributes and
+/// `javascript:` / `vbscript:` URLs, so that HTML kept in the output can't run code
+pub fn sanitize(html: &str) -> String {
+    let html = remove_elements(&remove_elements(html, "script"), "style");
+    let mut result = String::with_capacity(html.len());
+    let mut rest = html.as_str();
+
+    while let Some(start) = rest.find('<') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let opening = rest[1..].starts_with(|c: char| c.is_ascii_alphabetic());
+        match tag_end(rest).filter(|_| opening) {
+            Some(end) => {
+                result.push_str(&sanitize_tag(&rest[..end]));
+                rest = &rest[end..];
+            }
+            None => {
+                result.push('<');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    result.push_str(rest);
+    result
+}
+
+/// Length of the tag at the start of `html` through its `>`, skipping quoted values
+fn tag_end(html: &str) -> Option<usize> {
+    let mut quote = None;
+    for (i, c) in html.char_indices() {
+        match (c, quote) {
+            ('"' | '\'', None) => quote = Some(c),
+            (c, Some(q)) if c == q => quote = None,
+            ('>', None) => return Some(i + 1),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// An opening tag without its unsafe attributes
+fn sanitize_tag(tag: &str) -> String {
+    let inner = &tag[1..tag.len() - 1];
+    let name_len = inner
+        .find(|c: char| c.is_whitespace() || c == '/')
+        .unwrap_or(inner.len());
+    let mut result = format!("<{}", &inner[..name_len]);
+    let mut rest = &inner[name_len..];
+
+    loop {
+        let attr = rest.trim_start();
+        let name_len = attr
+            .find(|c: char| c.is_whitespace() || c == '=' || c == '/')
+            .unwrap_or(attr.len());
+        if name_len == 0 {
+            // End of the tag, or a `/` of a self-closing tag
+            result.push_str(rest);
+            break;
+        }
+
+        let (value, attr_len) = match attr[name_len..].trim_start().strip_prefix('=') {
+            Some(after) => {
+                let after = after.trim_start();
+                let offset = attr.len() - after.len();
+                match after.chars().next() {
+                    Some(q @ ('"' | '\'')) => match after[1..].find(q) {
+                        Some(close) => (&after[1..close + 1], offset + close + 2),
+                        None => (&after[1..], attr.len()),
+                    },
+                    _ => {
+                        let len = after.find(char::is_whitespace).unwrap_or(after.len());
+                        (&after[..len], offset + len)
+                    }
+                }
+            }
+            None => ("", name_len),
+        };
+
+        if !is_unsafe_attribute(&attr[..name_len], value) {
+            result.push_str(&rest[..rest.len() - attr.len() + attr_len]);
+        }
+        rest = &attr[attr_len..];
+    }
+
+    result.push('>');
+    result
+}
+
+fn is_unsafe_attribute(name: &str, value: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    if name.starts_with("on") {
+        return true;
+    }
+    let url = matches!(
+        name.as_str(),
+        "href" | "src" | "xlink:href" | "action" | "formaction" | "data" | "poster"
+    );
+    // Browsers ignore whitespace and control characters inside the scheme
+    let scheme: String = decode_entities(value)
+        .chars()
+        .filter(|c| !c.is_whitespace() && !c.is_control())
+        .take(11)
+        .collect::<String>()
+        .to_ascii_lowercase();
+    url && (scheme.starts_with("javascript:") || scheme.starts_with("vbscript:"))
+}
+
+/// Remove every `<tag ...>...</tag>` element (case-insensitive)
+pub fn remove_elements(html: &str, tag: &str) -> String {
+    let lower = html.to_ascii_lowercase();
+    let open = format!("<{}", tag);
+    let close = format!("</{}>", tag);
+    let mut result = String::with_capacity(html.len());
+    let mut pos = 0;
+
+    while let Some(offset) = lower[pos..].find(&open) {
+        let start = pos + offset;
+        result.push_str(&html[pos..start]);
+        pos = match lower[start..].find(&close) {
+            Some(end) => start + end + close.len(),
+            None => html.len(),
+        };
+    }
+
+    result.push_str(&html[pos..]);
+    result
+}
+
+/// Last path segment of a reference, without query or fragment
+pub fn file_name(src: &str) -> &str {
+    let path = src.split(['#', '?']).next().unwrap_or(src);
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// Resolve a reference found in the document at `base` (a manifest href such as
+/// `/OEBPS/Text/c1.xhtml`) to the path it points at, decoded and without query or
+/// fragment, so it can be compared with manifest hrefs
+pub fn resolve_href(base: &str, src: &str) -> String {
+    let path = percent_decode(src.split(['#', '?']).next().unwrap_or(src));
+    let base = percent_decode(base);
+    let mut parts: Vec<&str> = if path.starts_with('/') {
+        Vec::new()
+    } else {
+        base.split('/').collect()
+    };
+    // The document's own name
+    parts.pop();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    format!("/{}", parts.join("/").trim_start_matches('/'))
+}
+
+/// Decode `%XX` escapes in an href (`my%20figure.png` → `my figure.png`). Manifests
+/// and documents don't agree on whether hrefs are encoded, so both sides of a
+/// comparison are decoded. Malformed escapes are kept as they are
+pub fn percent_decode(href: &str) -> Cow<'_, str> {
+    if !href.contains('%') {
+        return Cow::Borrowed(href);
+    }
+
+    let bytes = href.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escape = (bytes[i] == b'%')
+            .then(|| href.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escape {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    Cow::Owned(String::from_utf8_lossy(&decoded).into_owned())
+}
+
+/// Combined emphasis of a span's classes, from its opening tag
+fn span_emphasis(tag: &str, classes: &ClassEmphasis) -> Emphasis {
+    let mut emphasis = Emphasis::default();
+    let Some(value) = attribute_value(tag, "class") else {
+        return emphasis;
+    };
+
+    for class in value.split_whitespace() {
+        if let Some(class_emphasis) = classes.get(class) {
+            emphasis.bold = class_emphasis.bold.or(emphasis.bold);
+            emphasis.italic = class_emphasis.italic.or(emphasis.italic);
+        }
+    }
+    emphasis
+}
+
+fn attribute_value<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let lower = tag.to_ascii_lowercase();
+    let pattern = format!(" {}=", name);
+    let value_start = lower.find(&pattern)? + pattern.len();
+    let quote = tag[value_start..]
+        .chars()
+        .next()
+        .filter(|c| *c == '"' || *c == '\'')?;
+    let value = &tag[value_start + 1..];
+    Some(&value[..value.find(quote)?])
+}
+
+/// True when a document is mostly internal link text, like a hand-written table
+/// of contents: at least three links to other documents making up 70% of the
+/// visible body text
+pub fn is_link_list(html: &str) -> bool {
+    let body = body_of(html);
+    let lower = body.to_ascii_lowercase();
+    let visible = |fragment: &str| {
+        decode_entities(&strip_tags(fragment))
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .count()
+    };
+
+    let mut links = 0;
+    let mut link_chars = 0;
+    let mut pos = 0;
+    while let Some(offset) = lower[pos..].find("<a") {
+        let start = pos + offset;
+        let rest = &lower[start..];
+        let (true, Some(open_len)) = (is_tag(rest, "<a"), rest.find('>')) else {
+            pos = start + 2;
+            continue;
+        };
+        let Some(close) = rest.find("</a>").filter(|close| *close > open_len) else {
+            break;
+        };
+        let internal = attribute_value(&body[start..start + open_len + 1], "href")
+            .is_some_and(|href| !href.contains("://") && !href.starts_with("mailto:"));
+        if internal {
+            links += 1;
+            link_chars += visible(&body[start + open_len + 1..start + close]);
+        }
+        pos = start + close + "</a>".len();
+    }
+
+    links >= 3 && link_chars * 10 >= visible(body) * 7
+}
+
+/// True when a document's body has any text (not just images or whitespace)
+pub fn has_visible_text(html: &str) -> bool {
+    !decode_entities(&strip_tags(body_of(html)))
+        .trim()
+        .is_empty()
+}
+
+/// Contents of a document's `<body>`, or the whole text when it has none
+pub fn extract_body(html: &str) -> &str {
+    let lower = html.to_ascii_lowercase();
+    let start = lower
+        .find("<body")
+        .and_then(|i| lower[i..].find('>').map(|j| i + j + 1));
+    let end = lower.rfind("</body>");
+
+    match (start, end) {
+        (Some(start), Some(end)) if start <= end => &html[start..end],
+        _ => html,
+    }
+}
+
+/// A document from its `<body` tag on; `<head>` holds the invisible `<title>`
+fn body_of(html: &str) -> &str {
+    let start = html.to_ascii_lowercase().find("<body").unwrap_or(0);
+    &html[start..]
+}
+
+/// Contents of the `<style>` elements in a document
+pub fn inline_styles(html: &str) -> String {
+    let lower = html.to_ascii_lowercase();
+    let mut css = String::new();
+    let mut pos = 0;
+
+    while let Some(offset) = lower[pos..].find("<style") {
+        let start = pos + offset;
+        let Some(open_end) = lower[start..].find('>').map(|i| start + i + 1) else {
+            break;
+        };
+        let Some(close) = lower[open_end..].find("</style>").map(|i| open_end + i) else {
+            break;
+        };
+        css.push_str(&html[open_end..close]);
+        css.push('\n');
+        pos = close;
+    }
+
+    css
+}
+
+/// Language from the root element's `xml:lang` or `lang` attribute
+fn document_language(lower_html: &str) -> Option<String> {
+    let html_start = lower_html.find("<html")?;
+    let tag_end = html_start + lower_html[html_start..].find('>')?;
+    let tag = &lower_html[html_start..tag_end];
+
+    ["xml:lang=", " lang="].iter().find_map(|attr| {
+        let value = &tag[tag.find(attr)? + attr.len()..];
+        let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+        let value = &value[1..];
+        Some(value[..value.find(quote)?].to_string())
+    })
+}
+
+type QuotePair = (&'static str, &'static str);
+
+/// Primary and nested quotation marks for a language tag
+fn quote_marks(lang: Option<&str>) -> (QuotePair, QuotePair) {
+    let primary = lang
+        .and_then(|l| l.split(['-', '_']).next())
+        .unwrap_or_default();
+
+    match primary {
+        "en" | "nl" | "pt" | "tr" | "ko" | "zh" => {
+            (("\u{201c}", "\u{201d}"), ("\u{2018}", "\u{2019}"))
+        }
+        "de" | "cs" | "sk" | "da" => (("\u{201e}", "\u{201c}"), ("\u{201a}", "\u{2018}")),
+        "fr" => (("\u{ab}\u{a0}", "\u{a0}\u{bb}"), ("\u{201c}", "\u{201d}")),
+        "es" | "it" | "ru" | "uk" | "ca" | "el" | "no" | "nb" => {
+            (("\u{ab}", "\u{bb}"), ("\u{201c}", "\u{201d}"))
+        }
+        "pl" | "hu" | "ro" => (("\u{201e}", "\u{201d}"), ("\u{ab}", "\u{bb}")),
+        "sv" | "fi" => (("\u{201d}", "\u{201d}"), ("\u{2019}", "\u{2019}")),
+        "ja" => (("\u{300c}", "\u{300d}"), ("\u{300e}", "\u{300f}")),
+        _ => (("\"", "\""), ("'", "'")),
+    }
+}
+
+/// Match an opening tag name exactly (`<code>` or `<code ...>`, not `<codex>`)
+pub fn is_tag(rest: &str, tag: &str) -> bool {
+    rest.starts_with(tag)
+        && rest[tag.len()..]
+            .chars()
+            .next()
+            .is_some_and(|c| c == '>' || c == '/' || c.is_whitespace())
+}
+
+pub fn strip_tags(html: &str) -> String {
+    let mut result = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            c if !in_tag => result.push(c),
+            _ => {}
+        }
+    }
+    result
+}
+
+/// Decode the named and numeric character references that appear in EPUB XHTML
+pub fn decode_entities(text: &str) -> String {
+    decode_entities_except(text, &[])
+}
+
+/// Decode character references, leaving the named entities in `keep` encoded
+pub fn decode_entities_except(text: &str, keep: &[&str]) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(amp) = rest.find('&') {
+        result.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+
+        let decoded = rest.find(';').filter(|&semi| semi <= 10).and_then(|semi| {
+            let entity = &rest[1..semi];
+            if keep.contains(&entity) {
+                return None;
+            }
+            let c = match entity {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "nbsp" => Some('\u{a0}'),
+                "ndash" => Some('\u{2013}'),
+                "mdash" => Some('\u{2014}'),
+                "lsquo" => Some('\u{2018}'),
+                "rsquo" => Some('\u{2019}'),
+                "ldquo" => Some('\u{201c}'),
+                "rdquo" => Some('\u{201d}'),
+                "hellip" => Some('\u{2026}'),
+                "copy" => Some('\u{a9}'),
+                "reg" => Some('\u{ae}'),
+                "trade" => Some('\u{2122}'),
+                _ => entity
+                    .strip_prefix("#x")
+                    .or_else(|| entity.strip_prefix("#X"))
+                    .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                    .or_else(|| entity.strip_prefix('#').and_then(|d| d.parse().ok()))
+                    .and_then(char::from_u32),
+            };
+            c.map(|c| (c, semi))
+        });
+
+        match decoded {
+            Some((c, semi)) => {
+                result.push(c);
+                rest = &rest[semi + 1..];
+            }
+            None => {
+                result.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    result.push_str(rest);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_href_follows_relative_paths() {
+        let base = "/OEBPS/Text/c1.xhtml";
+        assert_eq!(
+            resolve_href(base, "../Images/my%20figure.png#x"),
+            "/OEBPS/Images/my figure.png"
+        );
+        assert_eq!(resolve_href(base, "./c2.xhtml"), "/OEBPS/Text/c2.xhtml");
+        assert_eq!(resolve_href(base, "/OEBPS/a.png"), "/OEBPS/a.png");
+        assert_eq!(resolve_href("", "OEBPS/a.png"), "/OEBPS/a.png");
+    }
+
+    #[test]
+    fn heading_breaks_become_spaces() {
+        assert_eq!(
+            flatten_heading_breaks("<h1>Part One<br/>The Beginning</h1>"),
+            "<h1>Part One The Beginning</h1>"
+        );
+        assert_eq!(
+            flatten_heading_breaks("<H2 class=\"t\">A<BR>B</H2>"),
+            "<H2 class=\"t\">A B</H2>"
+        );
+    }
+
+    #[test]
+    fn trailing_heading_break_is_dropped() {
+        assert_eq!(flatten_heading_breaks("<h3>C<br/></h3>"), "<h3>C</h3>");
+        assert_eq!(flatten_heading_breaks("<h3>C<br />\n</h3>"), "<h3>C\n</h3>");
+    }
+
+    #[test]
+    fn breaks_outside_headings_are_kept() {
+        let html = "<h1>Title</h1><p>line<br/>two</p><header>x<br/>y</header>";
+        assert_eq!(flatten_heading_breaks(html), html);
+    }
+
+    #[test]
+    fn percent_decode_decodes_escapes() {
+        assert_eq!(
+            percent_decode("Images/my%20figure.png"),
+            "Images/my figure.png"
+        );
+        assert_eq!(percent_decode("caf%C3%A9.png"), "café.png");
+    }
+
+    #[test]
+    fn percent_decode_borrows_unencoded_hrefs() {
+        assert!(matches!(
+            percent_decode("my figure.png"),
+            Cow::Borrowed("my figure.png")
+        ));
+    }
+
+    #[test]
+    fn percent_decode_keeps_malformed_escapes() {
+        assert_eq!(percent_decode("100%.png"), "100%.png");
+        assert_eq!(percent_decode("a%zzb%2"), "a%zzb%2");
+    }
+}
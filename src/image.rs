@@ -1,42 +1,521 @@
-use crate::reader::BookReader;
-use anyhow::Result;
-use std::collections::HashMap;
+use crate::html_preprocess::{file_name, percent_decode};
+use crate::json::json_string;
+use crate::reader::{BookReader, ImageResource};
+use anyhow::{Context, Result};
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 /// Maps original image hrefs to their new relative paths in output
 pub type ImageMap = HashMap<String, String>;
 
-pub fn extract_images(reader: &dyn BookReader, output_dir: &Path) -> Result<ImageMap> {
-    let images_dir = output_dir.join("images");
-    let images = reader.images()?;
+/// Written in place of images skipped for exceeding a [`SizeLimit`]
+const PLACEHOLDER_FILENAME: &str = "image-omitted.svg";
+const PLACEHOLDER_HREF: &str = "epub2md:image-omitted";
+const PLACEHOLDER_SVG: &str = concat!(
+    r##"<svg xmlns="http://www.w3.org/2000/svg" width="320" height="80" viewBox="0 0 320 80">"##,
+    r##"<rect width="320" height="80" fill="#eeeeee" stroke="#999999"/>"##,
+    r##"<text x="160" y="45" font-family="sans-serif" font-size="14" text-anchor="middle" "##,
+    r##"fill="#666666">Image omitted (too large)</text></svg>"##,
+    "\n"
+);
 
-    if images.is_empty() {
-        return Ok(ImageMap::new());
+/// Maximum size of an extracted image file
+#[derive(Debug, Clone, Copy)]
+pub struct SizeLimit {
+    pub max_bytes: u64,
+    /// Keep oversized images and only warn about them
+    pub warn_only: bool,
+}
+
+/// One image file as written, for `--output-per-image-manifest`
+pub struct ImageInfo {
+    /// Original hrefs that resolve to this file; several share the placeholder for
+    /// oversized images
+    pub hrefs: Vec<String>,
+    /// Path relative to the output directory, as in the [`ImageMap`]
+    pub path: String,
+    /// `png`, `jpeg`, `gif`, `webp`, `bmp` or `svg` from the file's signature,
+    /// otherwise the extension
+    pub format: String,
+    /// Width and height in pixels, when the header gives them
+    pub dimensions: Option<(u32, u32)>,
+    pub bytes: usize,
+}
+
+/// Write images to `output_dir/images/`, or directly into `output_dir` when `flat`.
+/// Images matching an `ignore` pattern and spacer images are left out.
+/// `on_written` is called with the number of files to write after each one is written
+pub fn extract_images(
+    reader: &dyn BookReader,
+    output_dir: &Path,
+    flat: bool,
+    limit: Option<SizeLimit>,
+    ignore: &[String],
+    concurrency: usize,
+    on_written: &(dyn Fn(usize) + Sync),
+) -> Result<(ImageMap, Vec<ImageInfo>)> {
+    let mut images = reader.images()?;
+    let ignored = ignored_images(&images, ignore);
+    images.retain(|img| !ignored.contains(&img.original_href));
+    let mut image_map = plan_images(&images, flat);
+    if let Some(limit) = limit {
+        apply_size_limit(&mut images, &mut image_map, limit);
     }
+    let written = write_images(&images, &image_map, output_dir, concurrency, on_written)?;
+    Ok((image_map, written))
+}
 
-    fs::create_dir_all(&images_dir)?;
+/// Hrefs of decorative images to leave out: those matching one of the glob
+/// `patterns` and 1×1 spacer GIFs/PNGs
+pub fn ignored_images(images: &[ImageResource], patterns: &[String]) -> HashSet<String> {
+    images
+        .iter()
+        .filter(|img| {
+            is_spacer(&img.data)
+                || patterns
+                    .iter()
+                    .any(|pattern| href_matches(&img.original_href, pattern))
+        })
+        .map(|img| img.original_href.clone())
+        .collect()
+}
 
-    let mut image_map = ImageMap::new();
+/// Match a glob against the whole href or any trailing part of its path, so
+/// `images/rule-*.png` and `*.gif` both match `/OEBPS/images/rule-1.png`-style hrefs
+fn href_matches(href: &str, pattern: &str) -> bool {
+    let href = percent_decode(href).to_ascii_lowercase();
+    let pattern = pattern.to_ascii_lowercase();
+    glob_match(&pattern, &href)
+        || href
+            .match_indices('/')
+            .any(|(i, _)| glob_match(&pattern, &href[i + 1..]))
+}
+
+/// `*` matches any run of characters (including `/`), `?` any single character
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position after the last `*` and the text position it is currently absorbing up to
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((after_star, absorbed)) => {
+                    p = after_star;
+                    t = absorbed + 1;
+                    star = Some((after_star, absorbed + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// True for 1×1 GIF or PNG images, which books use as spacers
+fn is_spacer(data: &[u8]) -> bool {
+    matches!(signature_format(data), Some("gif" | "png")) && dimensions(data) == Some((1, 1))
+}
+
+/// Image format recognized from the first bytes of the file
+fn signature_format(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("png")
+    } else if data.starts_with(b"\xff\xd8\xff") {
+        Some("jpeg")
+    } else if data.starts_with(b"GIF8") {
+        Some("gif")
+    } else if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP") {
+        Some("webp")
+    } else if data.starts_with(b"BM") && data.len() >= 26 {
+        Some("bmp")
+    } else {
+        let head = String::from_utf8_lossy(&data[..data.len().min(1024)]).to_ascii_lowercase();
+        head.contains("<svg").then_some("svg")
+    }
+}
 
-    for img in &images {
-        let filename = clean_filename(&img.original_href);
-        let dest = images_dir.join(&filename);
+/// Width and height from a PNG, GIF, JPEG, WebP or BMP header
+fn dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    let be16 = |i: usize| Some(u16::from_be_bytes([*data.get(i)?, *data.get(i + 1)?]) as u32);
+    let le16 = |i: usize| Some(u16::from_le_bytes([*data.get(i)?, *data.get(i + 1)?]) as u32);
+    let le24 = |i: usize| Some(le16(i)? | (*data.get(i + 2)? as u32) << 16);
+    let be32 = |i: usize| Some(be16(i)? << 16 | be16(i + 2)?);
+    let le32 = |i: usize| Some(le16(i)? | le16(i + 2)? << 16);
 
-        fs::write(&dest, &img.data)?;
+    match signature_format(data)? {
+        "png" => Some((be32(16)?, be32(20)?)),
+        "gif" => Some((le16(6)?, le16(8)?)),
+        "bmp" => Some((le32(18)?, (le32(22)? as i32).unsigned_abs())),
+        "webp" => match data.get(12..16)? {
+            b"VP8 " => Some((le16(26)? & 0x3fff, le16(28)? & 0x3fff)),
+            b"VP8L" => {
+                let bits = le32(21)?;
+                Some(((bits & 0x3fff) + 1, (bits >> 14 & 0x3fff) + 1))
+            }
+            b"VP8X" => Some((le24(24)? + 1, le24(27)? + 1)),
+            _ => None,
+        },
+        "jpeg" => {
+            // Walk the marker segments to the first start-of-frame
+            let mut pos = 2;
+            loop {
+                if *data.get(pos)? != 0xff {
+                    return None;
+                }
+                let marker = *data.get(pos + 1)?;
+                if (0xc0..=0xcf).contains(&marker) && ![0xc4, 0xc8, 0xcc].contains(&marker) {
+                    return Some((be16(pos + 7)?, be16(pos + 5)?));
+                }
+                pos += 2 + be16(pos + 2)? as usize;
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Warn about images over the limit and, unless only warning, drop them and point
+/// their references at a shared placeholder image planned beside them
+pub fn apply_size_limit(
+    images: &mut Vec<ImageResource>,
+    image_map: &mut ImageMap,
+    limit: SizeLimit,
+) {
+    let mut placeholder_path = None;
 
-        image_map.insert(
-            img.original_href.clone(),
-            format!("images/{}", filename),
+    images.retain(|img| {
+        let size = img.data.len() as u64;
+        if size <= limit.max_bytes {
+            return true;
+        }
+        if limit.warn_only {
+            eprintln!(
+                "Warning: image {} is {} bytes, over the {}-byte limit",
+                img.original_href, size, limit.max_bytes
+            );
+            return true;
+        }
+        eprintln!(
+            "Warning: skipping image {} ({} bytes, over the {}-byte limit)",
+            img.original_href, size, limit.max_bytes
         );
+        if let Some(planned) = image_map.get_mut(&img.original_href) {
+            let path = placeholder_path.get_or_insert_with(|| {
+                match Path::new(planned.as_str())
+                    .parent()
+                    .filter(|p| !p.as_os_str().is_empty())
+                {
+                    Some(dir) => format!("{}/{}", dir.to_string_lossy(), PLACEHOLDER_FILENAME),
+                    None => PLACEHOLDER_FILENAME.to_string(),
+                }
+            });
+            *planned = path.clone();
+        }
+        false
+    });
+
+    if let Some(path) = placeholder_path {
+        image_map.insert(PLACEHOLDER_HREF.to_string(), path);
+        images.push(ImageResource {
+            original_href: PLACEHOLDER_HREF.to_string(),
+            data: PLACEHOLDER_SVG.as_bytes().to_vec(),
+        });
+    }
+}
+
+/// Decide the output path of every image (`images/<name>`, or `<name>` when
+/// `flat`) without writing anything
+pub fn plan_images(images: &[ImageResource], flat: bool) -> ImageMap {
+    let mut image_map = ImageMap::new();
+    let mut used = HashSet::new();
+
+    for img in images {
+        let mut filename = clean_filename(&img.original_href);
+        if flat {
+            // Images share the directory with chapter files and README.md
+            filename = unique_flat_filename(&filename, &mut used);
+        }
+
+        let relative = if flat {
+            filename
+        } else {
+            format!("images/{}", filename)
+        };
+        image_map.insert(img.original_href.clone(), relative);
+    }
+
+    image_map
+}
+
+/// Rename planned images to `image-001.ext`, `image-002.ext`, … following the
+/// order their paths first appear in `references`; unreferenced images come last
+pub fn number_images(image_map: &ImageMap, references: &[String]) -> ImageMap {
+    let mut by_path: HashMap<&str, &str> = image_map
+        .iter()
+        .map(|(href, path)| (path.as_str(), href.as_str()))
+        .collect();
+
+    let mut ordered = Vec::new();
+    for reference in references {
+        if let Some(href) = by_path.remove(reference.as_str()) {
+            ordered.push(href);
+        }
+    }
+    // Sort the rest so numbering doesn't depend on reader iteration order
+    let mut unreferenced: Vec<_> = by_path.into_values().collect();
+    unreferenced.sort();
+    ordered.extend(unreferenced);
+
+    ordered
+        .into_iter()
+        .enumerate()
+        .map(|(i, href)| {
+            let path = Path::new(&image_map[href]);
+            let mut filename = format!("image-{:03}", i + 1);
+            if let Some(ext) = path.extension() {
+                filename.push('.');
+                filename.push_str(&ext.to_string_lossy());
+            }
+            let numbered = match path.parent().filter(|p| !p.as_os_str().is_empty()) {
+                Some(dir) => format!("{}/{}", dir.to_string_lossy(), filename),
+                None => filename,
+            };
+            (href.to_string(), numbered)
+        })
+        .collect()
+}
+
+/// Number of files `write_images` writes for these images
+pub fn write_count(images: &[ImageResource], image_map: &ImageMap) -> usize {
+    images
+        .iter()
+        .filter_map(|img| image_map.get(&img.original_href))
+        .collect::<HashSet<_>>()
+        .len()
+}
+
+/// Write images under `output_dir` at the relative paths planned in `image_map`,
+/// spread over up to `concurrency` threads. Returns the files written
+pub fn write_images(
+    images: &[ImageResource],
+    image_map: &ImageMap,
+    output_dir: &Path,
+    concurrency: usize,
+    on_written: &(dyn Fn(usize) + Sync),
+) -> Result<Vec<ImageInfo>> {
+    // Images planned at the same path are written once, the last one winning as
+    // it would writing them in order
+    let mut jobs: Vec<(PathBuf, &[u8])> = Vec::new();
+    let mut relatives = Vec::new();
+    let mut index = HashMap::new();
+    for img in images {
+        let Some(relative) = image_map.get(&img.original_href) else {
+            continue;
+        };
+        let dest = output_dir.join(relative);
+        match index.get(&dest) {
+            Some(&i) => jobs[i] = (dest, img.data.as_slice()),
+            None => {
+                index.insert(dest.clone(), jobs.len());
+                jobs.push((dest, img.data.as_slice()));
+                relatives.push(relative);
+            }
+        }
+    }
+    let mut hrefs: HashMap<&str, Vec<String>> = HashMap::new();
+    for (href, relative) in image_map {
+        if href != PLACEHOLDER_HREF {
+            hrefs.entry(relative).or_default().push(href.clone());
+        }
     }
+    let written = jobs
+        .iter()
+        .zip(relatives)
+        .map(|((_, data), relative)| ImageInfo {
+            hrefs: hrefs.remove(relative.as_str()).unwrap_or_default(),
+            path: relative.clone(),
+            format: signature_format(data)
+                .map(str::to_string)
+                .or_else(|| {
+                    Path::new(relative)
+                        .extension()
+                        .map(|ext| ext.to_string_lossy().to_lowercase())
+                })
+                .unwrap_or_else(|| "unknown".to_string()),
+            dimensions: dimensions(data),
+            bytes: data.len(),
+        })
+        .collect();
 
-    Ok(image_map)
+    let dirs: HashSet<&Path> = jobs.iter().filter_map(|(dest, _)| dest.parent()).collect();
+    for dir in dirs {
+        fs::create_dir_all(dir)?;
+    }
+
+    let total = jobs.len();
+    let write = |jobs: &[(PathBuf, &[u8])]| -> Result<()> {
+        for (dest, data) in jobs {
+            fs::write(dest, data)
+                .with_context(|| format!("Failed to write image: {}", dest.display()))?;
+            on_written(total);
+        }
+        Ok(())
+    };
+
+    let threads = concurrency.clamp(1, jobs.len().max(1));
+    if threads == 1 {
+        write(&jobs)?;
+        return Ok(written);
+    }
+    let chunk = jobs.len().div_ceil(threads);
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = jobs
+            .chunks(chunk)
+            .map(|jobs| scope.spawn(|| write(jobs)))
+            .collect();
+        handles
+            .into_iter()
+            .try_for_each(|handle| handle.join().expect("image writer thread panicked"))
+    })?;
+    Ok(written)
 }
 
+/// `images.json`: every original href with the path, format, size and bytes of
+/// the file it was written to, sorted by href
+pub fn manifest_json(written: &[ImageInfo]) -> String {
+    let mut entries: Vec<(&str, &ImageInfo)> = written
+        .iter()
+        .flat_map(|info| info.hrefs.iter().map(move |href| (href.as_str(), info)))
+        .collect();
+    entries.sort_by(|a, b| (a.0, &a.1.path).cmp(&(b.0, &b.1.path)));
+
+    let objects: Vec<String> = entries
+        .into_iter()
+        .map(|(href, info)| {
+            let (width, height) = info.dimensions.map_or_else(
+                || ("null".to_string(), "null".to_string()),
+                |(w, h)| (w.to_string(), h.to_string()),
+            );
+            format!(
+                "  {{\"href\":{},\"path\":{},\"format\":{},\"width\":{},\"height\":{},\"bytes\":{}}}",
+                json_string(href),
+                json_string(&info.path),
+                json_string(&info.format),
+                width,
+                height,
+                info.bytes
+            )
+        })
+        .collect();
+
+    if objects.is_empty() {
+        return "[]\n".to_string();
+    }
+    format!("[\n{}\n]\n", objects.join(",\n"))
+}
+
+/// Threads for writing images when `--image-concurrency` isn't given: one per
+/// core, up to eight
+pub fn default_concurrency() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get().min(8))
+}
+
+/// Decoded filename of an href, with whitespace (which would end a markdown link
+/// target) replaced by underscores
 fn clean_filename(href: &str) -> String {
-    Path::new(href)
-        .file_name()
-        .map(|f| f.to_string_lossy().to_string())
-        .unwrap_or_else(|| "image.bin".to_string())
+    let name = percent_decode(file_name(href));
+    if name.is_empty() {
+        return "image.bin".to_string();
+    }
+    name.split(char::is_whitespace)
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("_")
+}
+
+/// Avoid clobbering `.md` output files and other images in a flat output directory
+fn unique_flat_filename(filename: &str, used: &mut HashSet<String>) -> String {
+    let path = Path::new(filename);
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_else(|| "image".to_string());
+    let ext = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    let stem = if ext.eq_ignore_ascii_case("md") {
+        format!("image-{}", stem)
+    } else {
+        stem
+    };
+    let build = |suffix: usize| match (ext.is_empty(), suffix) {
+        (true, 0) => stem.clone(),
+        (true, n) => format!("{}-{}", stem, n),
+        (false, 0) => format!("{}.{}", stem, ext),
+        (false, n) => format!("{}-{}.{}", stem, n, ext),
+    };
+
+    let mut suffix = 0;
+    let mut candidate = build(suffix);
+    while used.contains(&candidate.to_lowercase()) {
+        suffix += 1;
+        candidate = build(suffix);
+    }
+    used.insert(candidate.to_lowercase());
+    candidate
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::markdown;
+    use crate::reader::ConvertOptions;
+
+    fn image(href: &str) -> ImageResource {
+        ImageResource {
+            original_href: href.to_string(),
+            data: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn clean_filename_decodes_and_replaces_spaces() {
+        assert_eq!(clean_filename("Images/my%20figure.png"), "my_figure.png");
+        assert_eq!(clean_filename("Images/my figure.png"), "my_figure.png");
+        assert_eq!(clean_filename("Images/plain.png"), "plain.png");
+        assert_eq!(clean_filename("Images/"), "image.bin");
+    }
+
+    #[test]
+    fn encoded_manifest_href_matches_spaced_reference() {
+        let map = plan_images(&[image("Images/my%20figure.png")], false);
+        assert_eq!(map["Images/my%20figure.png"], "images/my_figure.png");
+
+        let html = r#"<p><img src="Images/my figure.png" alt="Figure"/></p>"#;
+        let md = markdown::html_to_markdown(html, "c1.xhtml", &map, &ConvertOptions::default());
+        assert_eq!(md.trim(), "![Figure](images/my_figure.png)");
+    }
+
+    #[test]
+    fn spaced_manifest_href_matches_encoded_reference() {
+        let map = plan_images(&[image("Images/my figure.png")], false);
+        assert_eq!(map["Images/my figure.png"], "images/my_figure.png");
+
+        let html = r#"<p><img src="Images/my%20figure.png" alt="Figure"/></p>"#;
+        let md = markdown::html_to_markdown(html, "c1.xhtml", &map, &ConvertOptions::default());
+        assert_eq!(md.trim(), "![Figure](images/my_figure.png)");
+    }
 }
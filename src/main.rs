@@ -1,17 +1,32 @@
+mod archive;
+mod batch;
+mod catalog;
 mod cli;
+mod config;
 mod converter;
+mod css;
+mod docx_chart;
 mod docx_markdown;
+mod docx_preprocess;
+mod docx_properties;
 mod docx_reader;
+mod docx_revisions;
+mod docx_worksheet;
+mod docx_xml;
+mod encoding;
 mod epub_reader;
+mod html_output;
+mod html_preprocess;
 mod image;
+mod json;
 mod markdown;
 mod metadata;
 mod reader;
+mod validate;
 
 use anyhow::Result;
-use clap::Parser;
 
 fn main() -> Result<()> {
-    let cli = cli::Cli::parse();
+    let cli = config::parse_args()?;
     converter::convert(&cli)
 }
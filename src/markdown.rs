@@ -1,41 +1,1852 @@
+use crate::cli::{EditMarkup, HeadingAnchors, PageAnchorStyle};
+use crate::html_preprocess::{
+    self, decode_entities, decode_entities_except, is_tag, strip_tags, MediaFile,
+};
 use crate::image::ImageMap;
+use crate::reader::ConvertOptions;
+use pulldown_cmark::{Event, Options, Parser, Tag, TagEnd};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::path::Path;
 
-pub fn html_to_markdown(html: &str, image_map: &ImageMap) -> String {
-    let mut md = html2md::parse_html(html);
+/// Placeholder prefix for inline code spans protected from html2md
+const CODE_PLACEHOLDER: &str = "EPUB2MDINLINECODE";
+
+/// Placeholder prefix for `<sup>`/`<sub>` elements protected from html2md
+const SCRIPT_PLACEHOLDER: &str = "EPUB2MDSCRIPT";
+
+/// Placeholder for empty `<p>` elements, which html2md would otherwise drop
+const EMPTY_PARAGRAPH_PLACEHOLDER: &str = "EPUB2MDEMPTYPARAGRAPH";
+
+/// Placeholder for `<br>` line breaks kept with `--preserve-line-breaks`
+const LINE_BREAK_PLACEHOLDER: &str = "EPUB2MDLINEBREAK";
+
+/// Placeholder for `<ins>` / `<del>` tags, followed by a code and an `X` terminator
+const EDIT_PLACEHOLDER: &str = "EPUB2MDEDIT";
+
+/// Placeholder prefix for EPUB `page-list` positions, followed by the page's index
+/// and an `X` terminator
+pub const PAGE_PLACEHOLDER: &str = "EPUB2MDPAGE";
+
+/// Placeholders for characters html2md leaves unescaped in text, with their escapes
+const ESCAPE_PLACEHOLDERS: [(char, &str, &str); 3] = [
+    ('[', "EPUB2MDLBRACKET", "\\["),
+    (']', "EPUB2MDRBRACKET", "\\]"),
+    ('`', "EPUB2MDBACKTICK", "\\`"),
+];
+
+/// Spacer emitted for intentionally empty paragraphs; unlike a blank line it
+/// survives the blank-line collapsing in `clean_markdown`
+pub const EMPTY_PARAGRAPH: &str = "&nbsp;";
+
+/// Convert a chapter's HTML; `href` is the chapter document's manifest href, which
+/// its relative references resolve against
+pub fn html_to_markdown(
+    html: &str,
+    href: &str,
+    image_map: &ImageMap,
+    options: &ConvertOptions,
+) -> String {
+    let mut html = if options.html_sanitize {
+        html_preprocess::sanitize(html)
+    } else {
+        html.to_string()
+    };
+    html = html_preprocess::replace_inline_quotes(&html);
+    html = html_preprocess::replace_semantic_elements(&html);
+    if options.expand_abbr {
+        html = html_preprocess::expand_abbreviations(&html);
+    }
+    if options.keep_empty_paragraphs {
+        html = html_preprocess::mark_empty_paragraphs(&html, EMPTY_PARAGRAPH_PLACEHOLDER);
+    }
+    if options.strip_soft_returns_in_headings {
+        html = html_preprocess::flatten_heading_breaks(&html);
+    }
+    if options.preserve_line_breaks {
+        html = html_preprocess::mark_line_breaks(&html, LINE_BREAK_PLACEHOLDER);
+    }
+    html = html_preprocess::mark_verse(&html, LINE_BREAK_PLACEHOLDER);
+    html = html_preprocess::mark_edits(&html, EDIT_PLACEHOLDER);
+    let (html, media) = html_preprocess::replace_media(&html);
+    let (html, code_spans) = protect_inline_code(&html);
+    let (mut html, scripts) = protect_scripts(&html);
+    if options.escape_markdown {
+        html = protect_literal_brackets(&html);
+    }
+    let mut md = html2md::parse_html(&html);
+    md = restore_scripts(&md, &scripts);
+    md = restore_inline_code(&md, &code_spans);
+    if options.escape_markdown {
+        for (_, placeholder, escaped) in ESCAPE_PLACEHOLDERS {
+            md = md.replace(placeholder, escaped);
+        }
+    }
+    md = md.replace(EMPTY_PARAGRAPH_PLACEHOLDER, EMPTY_PARAGRAPH);
+    if md.contains(EDIT_PLACEHOLDER) {
+        md = restore_edits(&md, options.edit_markup);
+    }
+    // Verse lines are marked even without --preserve-line-breaks
+    if md.contains(LINE_BREAK_PLACEHOLDER) {
+        md = restore_line_breaks(&md);
+    }
+    if !media.is_empty() {
+        md.push_str(&media_note(&media, image_map));
+    }
+
+    if !options.ignored_images.is_empty() {
+        md = remove_ignored_images(&md, href, &options.ignored_images);
+    }
 
     // Rewrite image paths from EPUB-internal paths to extracted paths
-    for (original, replacement) in image_map {
-        md = rewrite_image_path(&md, original, replacement);
+    md = rewrite_image_paths(&md, image_map);
+
+    if options.image_alt_from_filename {
+        md = fill_empty_image_alt(&md);
+    }
+
+    if options.image_placeholder {
+        md = replace_unresolved_images(&md, image_map);
+    }
+
+    if let Some(ref token) = options.cell_break {
+        md = replace_table_breaks(&md, token);
+    }
+
+    if options.merge_adjacent_formatting {
+        md = merge_adjacent_emphasis(&md);
+    }
+
+    if options.trim_footnote_markers {
+        md = trim_footnote_markers(&md);
+    }
+
+    clean_markdown(&md, options.convert_entities)
+}
+
+/// Turn `<ins>` / `<del>` placeholders into `==…==` / `~~…~~` or back into the
+/// elements. Empty insertions are dropped, as a bare `====` line is a setext heading
+fn restore_edits(md: &str, style: EditMarkup) -> String {
+    let tags = match style {
+        EditMarkup::Markdown => ["==", "==", "~~", "~~"],
+        EditMarkup::Html => ["<ins>", "</ins>", "<del>", "</del>"],
+    };
+    let placeholder = |code: usize| format!("{}{}X", EDIT_PLACEHOLDER, code);
+
+    let mut md = md
+        .replace(&format!("{}{}", placeholder(0), placeholder(1)), "")
+        .replace(&format!("{}{}", placeholder(2), placeholder(3)), "");
+    for (code, tag) in tags.iter().enumerate() {
+        md = md.replace(&placeholder(code), tag);
+    }
+    md
+}
+
+/// Turn line-break placeholders into backslash hard breaks (trailing spaces would
+/// be trimmed by `clean_markdown`). Breaks at the start or end of a paragraph are
+/// dropped, since they can't separate anything
+fn restore_line_breaks(md: &str) -> String {
+    let lines: Vec<&str> = md.lines().collect();
+    let mut result = Vec::with_capacity(lines.len());
+
+    for (i, line) in lines.iter().enumerate() {
+        let mut line = line.trim();
+        let after_blank = i == 0 || lines[i - 1].trim().is_empty();
+        let before_blank = lines.get(i + 1).is_none_or(|next| next.trim().is_empty());
+        while let Some(rest) = line
+            .strip_prefix(LINE_BREAK_PLACEHOLDER)
+            .filter(|_| after_blank)
+        {
+            line = rest.trim_start();
+        }
+        while let Some(rest) = line
+            .strip_suffix(LINE_BREAK_PLACEHOLDER)
+            .filter(|_| before_blank)
+        {
+            line = rest.trim_end();
+        }
+        let indent = &lines[i][..lines[i].len() - lines[i].trim_start().len()];
+        result.push(format!(
+            "{}{}",
+            indent,
+            hard_line_breaks(&line.replace(LINE_BREAK_PLACEHOLDER, "\n"))
+        ));
+    }
+
+    let mut result = result.join("\n");
+    if md.ends_with('\n') {
+        result.push('\n');
+    }
+    result
+}
+
+/// Join the lines of one paragraph with backslash hard line breaks
+pub fn hard_line_breaks(text: &str) -> String {
+    text.split('\n')
+        .map(str::trim)
+        .collect::<Vec<_>>()
+        .join("\\\n")
+}
+
+/// Join sibling emphasis spans of the same kind and delimiter that touch or are
+/// separated only by spaces: `**foo** **bar**` → `**foo bar**`, `*foo**bar*` →
+/// `*foobar*`. Spans are found with a CommonMark parser, so code, literal asterisks
+/// and spans split by punctuation or line breaks are left alone
+fn merge_adjacent_emphasis(md: &str) -> String {
+    let options = Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TABLES;
+    // Closed spans as (kind, range, parent span), and the stack of open ones
+    let mut spans: Vec<(TagEnd, Range<usize>, Option<usize>)> = Vec::new();
+    let mut open: Vec<usize> = Vec::new();
+    // Byte ranges of the delimiters to drop
+    let mut removals: Vec<Range<usize>> = Vec::new();
+
+    for (event, range) in Parser::new_ext(md, options).into_offset_iter() {
+        let kind = match event {
+            Event::Start(Tag::Emphasis) => Some(TagEnd::Emphasis),
+            Event::Start(Tag::Strong) => Some(TagEnd::Strong),
+            Event::Start(Tag::Strikethrough) => Some(TagEnd::Strikethrough),
+            Event::Start(_) => None,
+            Event::End(TagEnd::Emphasis | TagEnd::Strong | TagEnd::Strikethrough) => {
+                open.pop();
+                continue;
+            }
+            // `<em>a</em><em>b</em>` comes out as `*a**b*`, which parses as one
+            // emphasis with a literal `**` inside
+            Event::Text(_) => {
+                let in_emphasis = open.last().is_some_and(|&i| {
+                    spans[i].0 == TagEnd::Emphasis && md[spans[i].1.start..].starts_with('*')
+                });
+                if in_emphasis {
+                    removals.extend(joined_emphasis(md, range));
+                }
+                continue;
+            }
+            _ => continue,
+        };
+        let Some(kind) = kind else { continue };
+
+        let parent = open.last().copied();
+        // The previous sibling is the last span closed under the same parent
+        if let Some(prev) = spans
+            .iter()
+            .rev()
+            .find(|(_, r, p)| *p == parent && r.end <= range.start)
+        {
+            if let Some(delimiter) = mergeable(md, prev, &kind, &range) {
+                removals.push(prev.1.end - delimiter..prev.1.end);
+                removals.push(range.start..range.start + delimiter);
+            }
+        }
+        open.push(spans.len());
+        spans.push((kind, range, parent));
+    }
+
+    removals.sort_by_key(|r| r.start);
+    removals.dedup();
+    let mut result = md.to_string();
+    for removal in removals.into_iter().rev() {
+        result.replace_range(removal, "");
     }
+    result
+}
 
-    clean_markdown(&md)
+/// `**` runs in a text range with a word character on both sides, where two
+/// single-`*` emphasis spans meet. The parser may hand each `*` over as its own
+/// text, so runs are measured in the whole document
+fn joined_emphasis(md: &str, range: Range<usize>) -> Vec<Range<usize>> {
+    md[range.clone()]
+        .match_indices('*')
+        .filter_map(|(i, _)| {
+            let at = range.start + i;
+            let start = md[..at].trim_end_matches('*').len();
+            let end = md.len() - md[at..].trim_start_matches('*').len();
+            let before = md[..start].chars().next_back();
+            let after = md[end..].chars().next();
+            let word = |c: Option<char>| c.is_some_and(|c| !c.is_whitespace());
+            (end - start == 2 && word(before) && word(after)).then_some(start..end)
+        })
+        .collect()
 }
 
-fn rewrite_image_path(md: &str, original: &str, replacement: &str) -> String {
-    let mut result = md.replace(original, replacement);
+/// Delimiter length when span `prev` and the span at `next` can be merged: same
+/// kind, same delimiter, and nothing but spaces between them
+fn mergeable(
+    md: &str,
+    prev: &(TagEnd, Range<usize>, Option<usize>),
+    kind: &TagEnd,
+    next: &Range<usize>,
+) -> Option<usize> {
+    let (prev_kind, prev_range, _) = prev;
+    if prev_kind != kind || !md[prev_range.end..next.start].chars().all(|c| c == ' ') {
+        return None;
+    }
+    let delimiter = match kind {
+        TagEnd::Emphasis => 1,
+        TagEnd::Strong => 2,
+        _ => md[next.start..].chars().take_while(|&c| c == '~').count(),
+    };
+    let closer = md.get(prev_range.end - delimiter..prev_range.end)?;
+    let opener = md.get(next.start..next.start + delimiter)?;
+    let first = opener.chars().next()?;
+    (closer == opener
+        && opener.chars().all(|c| c == first)
+        && md[prev_range.start..].starts_with(opener))
+    .then_some(delimiter)
+}
+
+/// Swap inline `<code>` elements (outside `<pre>`) for placeholders so html2md
+/// can't drop or mis-fence them; returns the rewritten HTML and the code texts
+fn protect_inline_code(html: &str) -> (String, Vec<String>) {
+    let lower = html.to_ascii_lowercase();
+    let mut result = String::with_capacity(html.len());
+    let mut spans = Vec::new();
+    let mut pos = 0;
+    let mut pre_depth = 0usize;
+
+    while let Some(offset) = lower[pos..].find('<') {
+        let start = pos + offset;
+        let rest = &lower[start..];
 
-    // Also try matching just the filename portion, since EPUB refs
-    // can use varying relative paths (../Images/fig.png vs Images/fig.png vs fig.png)
-    if let Some(filename) = std::path::Path::new(original).file_name() {
-        let filename_str = filename.to_string_lossy();
-        // Only replace if it's inside a markdown image/link pattern to avoid false positives
-        let patterns = [
-            format!("]({})", filename_str),
-            format!("\"{}\"", filename_str),
-        ];
-        for pattern in &patterns {
-            if result.contains(pattern.as_str()) {
-                let new_pattern = pattern.replace(filename_str.as_ref(), replacement);
-                result = result.replace(pattern.as_str(), &new_pattern);
+        if is_tag(rest, "<pre") {
+            pre_depth += 1;
+        } else if rest.starts_with("</pre") {
+            pre_depth = pre_depth.saturating_sub(1);
+        } else if pre_depth == 0 && is_tag(rest, "<code") {
+            let Some(open_end) = rest.find('>').map(|i| start + i + 1) else {
+                break;
+            };
+            let Some(close) = lower[open_end..].find("</code>").map(|i| open_end + i) else {
+                break;
+            };
+
+            let text = decode_entities(&strip_tags(&html[open_end..close]));
+            result.push_str(&html[pos..start]);
+            if !text.is_empty() {
+                result.push_str(&format!("{}{}X", CODE_PLACEHOLDER, spans.len()));
+                spans.push(text);
             }
+            pos = close + "</code>".len();
+            continue;
         }
+
+        result.push_str(&html[pos..start + 1]);
+        pos = start + 1;
     }
 
+    result.push_str(&html[pos..]);
+    (result, spans)
+}
+
+/// Swap `<sup>`/`<sub>` elements (outside `<pre>`) for placeholders. html2md passes
+/// them through verbatim, attributes and unconverted inner HTML included, so their
+/// content is converted separately and kept as bare `<sup>…</sup>` inline HTML
+fn protect_scripts(html: &str) -> (String, Vec<String>) {
+    let lower = html.to_ascii_lowercase();
+    let mut result = String::with_capacity(html.len());
+    let mut scripts = Vec::new();
+    let mut pos = 0;
+    let mut pre_depth = 0usize;
+
+    while let Some(offset) = lower[pos..].find('<') {
+        let start = pos + offset;
+        let rest = &lower[start..];
+
+        if is_tag(rest, "<pre") {
+            pre_depth += 1;
+        } else if rest.starts_with("</pre") {
+            pre_depth = pre_depth.saturating_sub(1);
+        } else if let Some(tag) = ["sup", "sub"]
+            .into_iter()
+            .find(|tag| pre_depth == 0 && is_tag(rest, &format!("<{}", tag)))
+        {
+            let close_tag = format!("</{}>", tag);
+            let Some(open_end) = rest.find('>').map(|i| start + i + 1) else {
+                break;
+            };
+            let Some(close) = lower[open_end..].find(&close_tag).map(|i| open_end + i) else {
+                break;
+            };
+
+            let inner = html2md::parse_html(&html[open_end..close]);
+            let inner = inner.split_whitespace().collect::<Vec<_>>().join(" ");
+            result.push_str(&html[pos..start]);
+            if !inner.is_empty() {
+                result.push_str(&format!("{}{}X", SCRIPT_PLACEHOLDER, scripts.len()));
+                scripts.push(format!("<{}>{}{}", tag, inner, close_tag));
+            }
+            pos = close + close_tag.len();
+            continue;
+        }
+
+        result.push_str(&html[pos..start + 1]);
+        pos = start + 1;
+    }
+
+    result.push_str(&html[pos..]);
+    (result, scripts)
+}
+
+fn restore_scripts(md: &str, scripts: &[String]) -> String {
+    let mut result = md.to_string();
+    // The `X` terminator keeps placeholder 1 from matching inside placeholder 10
+    for (i, script) in scripts.iter().enumerate() {
+        result = result.replace(&format!("{}{}X", SCRIPT_PLACEHOLDER, i), script);
+    }
     result
 }
 
-pub fn clean_markdown(md: &str) -> String {
+/// Replace the `<br/>` runs html2md writes inside table cells (for `<br>` and
+/// paragraph breaks alike) with `token`
+fn replace_table_breaks(md: &str, token: &str) -> String {
+    let mut lines: Vec<String> = Vec::new();
+    for line in md.lines() {
+        if !line.trim_start().starts_with('|') || !line.contains("<br/>") {
+            lines.push(line.to_string());
+            continue;
+        }
+
+        let mut result = String::with_capacity(line.len());
+        let mut rest = line;
+        while let Some(i) = rest.find("<br/>") {
+            result.push_str(rest[..i].trim_end_matches(' '));
+            result.push_str(token);
+            rest = &rest[i..];
+            while let Some(after) = rest.strip_prefix("<br/>") {
+                rest = after;
+            }
+        }
+        result.push_str(rest);
+        lines.push(result);
+    }
+
+    let mut result = lines.join("\n");
+    if md.ends_with('\n') {
+        result.push('\n');
+    }
+    result
+}
+
+/// Swap `[`, `]` and backticks in text outside `<pre>` for placeholders that are
+/// restored escaped; html2md already escapes `*`, `_`, `\\` and leading `#`
+fn protect_literal_brackets(html: &str) -> String {
+    let lower = html.to_ascii_lowercase();
+    let mut result = String::with_capacity(html.len());
+    let mut pre_depth = 0usize;
+    let mut pos = 0;
+
+    while let Some(offset) = lower[pos..].find('<') {
+        let start = pos + offset;
+        let text = &html[pos..start];
+        if pre_depth == 0 {
+            result.push_str(&replace_literal_brackets(text));
+        } else {
+            result.push_str(text);
+        }
+
+        let rest = &lower[start..];
+        if is_tag(rest, "<pre") {
+            pre_depth += 1;
+        } else if rest.starts_with("</pre") {
+            pre_depth = pre_depth.saturating_sub(1);
+        }
+        let end = rest.find('>').map_or(html.len(), |i| start + i + 1);
+        result.push_str(&html[start..end]);
+        pos = end;
+    }
+
+    result.push_str(&replace_literal_brackets(&html[pos..]));
+    result
+}
+
+fn replace_literal_brackets(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars() {
+        match ESCAPE_PLACEHOLDERS.iter().find(|(ch, _, _)| *ch == c) {
+            Some((_, placeholder, _)) => result.push_str(placeholder),
+            None => result.push(c),
+        }
+    }
+    result
+}
+
+/// Backslash-escape characters that markdown would read as formatting
+pub fn escape_markdown(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '*' | '_' | '#' | '[' | ']' | '`') {
+            result.push('\\');
+        }
+        result.push(c);
+    }
+    result
+}
+
+/// Replace inline code placeholders with CommonMark code spans
+fn restore_inline_code(md: &str, spans: &[String]) -> String {
     let mut result = md.to_string();
+    // The `X` terminator keeps placeholder 1 from matching inside placeholder 10
+    for (i, code) in spans.iter().enumerate() {
+        let placeholder = format!("{}{}X", CODE_PLACEHOLDER, i);
+        result = result.replace(&placeholder, &code_span(code));
+    }
+    result
+}
+
+/// Fence code with one more backtick than its longest backtick run,
+/// padding with spaces when the content starts or ends with a backtick
+fn code_span(code: &str) -> String {
+    let code = code.replace('\n', " ");
+    let mut longest = 0;
+    let mut current = 0;
+    for c in code.chars() {
+        if c == '`' {
+            current += 1;
+            longest = longest.max(current);
+        } else {
+            current = 0;
+        }
+    }
+
+    let fence = "`".repeat(longest + 1);
+    if code.starts_with('`') || code.ends_with('`') {
+        format!("{} {} {}", fence, code, fence)
+    } else {
+        format!("{}{}{}", fence, code, fence)
+    }
+}
+
+/// Placeholder alt text from an image path: `images/fig_12.png` → `fig 12`
+pub fn alt_from_filename(path: &str) -> String {
+    Path::new(path)
+        .file_stem()
+        .map(|s| s.to_string_lossy().replace(['_', '-'], " "))
+        .unwrap_or_default()
+}
+
+/// Give `![](path)` images an alt text derived from their filename
+fn fill_empty_image_alt(md: &str) -> String {
+    let mut result = String::with_capacity(md.len());
+    let mut rest = md;
+
+    while let Some(start) = rest.find("![](") {
+        let path_start = start + "![](".len();
+        let Some(path_len) = rest[path_start..].find(')') else {
+            break;
+        };
+        let path = &rest[path_start..path_start + path_len];
+        result.push_str(&rest[..start]);
+        result.push_str(&format!("![{}]({})", alt_from_filename(path), path));
+        rest = &rest[path_start + path_len + 1..];
+    }
+
+    result.push_str(rest);
+    result
+}
+
+/// Targets of all `![alt](path)` images in a markdown document, in order
+pub fn image_references(md: &str) -> Vec<String> {
+    let mut refs = Vec::new();
+    let mut rest = md;
+
+    while let Some(start) = rest.find("![") {
+        rest = &rest[start + "![".len()..];
+        let Some(alt_len) = rest.find("](") else {
+            break;
+        };
+        rest = &rest[alt_len + "](".len()..];
+        let Some(path_len) = rest.find(')') else {
+            break;
+        };
+        let target = &rest[..path_len];
+        refs.push(
+            target
+                .split_whitespace()
+                .next()
+                .unwrap_or_default()
+                .to_string(),
+        );
+        rest = &rest[path_len + 1..];
+    }
+
+    refs
+}
+
+/// Text stand-in for an image that was not extracted
+pub fn image_placeholder(alt: &str) -> String {
+    let alt = alt.trim();
+    if alt.is_empty() {
+        "[image]".to_string()
+    } else {
+        format!("[image: {}]", alt)
+    }
+}
+
+/// Drop `![alt](path)` images that point at an ignored image. Paths are resolved
+/// against the chapter's `href`, so another image with the same filename stays
+fn remove_ignored_images(md: &str, href: &str, ignored: &HashSet<String>) -> String {
+    let ignored: HashSet<_> = ignored
+        .iter()
+        .map(|original| html_preprocess::resolve_href("", original))
+        .collect();
+    let mut result = String::with_capacity(md.len());
+    let mut rest = md;
+
+    while let Some(start) = rest.find("![") {
+        let alt_start = start + "![".len();
+        let Some(alt_len) = rest[alt_start..].find("](") else {
+            break;
+        };
+        let path_start = alt_start + alt_len + "](".len();
+        let Some(path_len) = rest[path_start..].find(')') else {
+            break;
+        };
+
+        let target = &rest[path_start..path_start + path_len];
+        let path = target.split_whitespace().next().unwrap_or_default();
+        result.push_str(&rest[..start]);
+        if !ignored.contains(&html_preprocess::resolve_href(href, path)) {
+            result.push_str(&rest[start..path_start + path_len + 1]);
+        }
+        rest = &rest[path_start + path_len + 1..];
+    }
+
+    result.push_str(rest);
+    result
+}
+
+/// Swap `![alt](path)` images whose path is not an extracted image for a placeholder
+fn replace_unresolved_images(md: &str, image_map: &ImageMap) -> String {
+    let extracted: HashSet<&str> = image_map.values().map(|p| p.as_str()).collect();
+    let mut result = String::with_capacity(md.len());
+    let mut rest = md;
+
+    while let Some(start) = rest.find("![") {
+        let alt_start = start + "![".len();
+        let Some(alt_len) = rest[alt_start..].find("](") else {
+            break;
+        };
+        let path_start = alt_start + alt_len + "](".len();
+        let Some(path_len) = rest[path_start..].find(')') else {
+            break;
+        };
+
+        let alt = &rest[alt_start..alt_start + alt_len];
+        let target = &rest[path_start..path_start + path_len];
+        let path = target.split_whitespace().next().unwrap_or_default();
+
+        result.push_str(&rest[..start]);
+        if extracted.contains(path) {
+            result.push_str(&rest[start..path_start + path_len + 1]);
+        } else {
+            result.push_str(&image_placeholder(alt));
+        }
+        rest = &rest[path_start + path_len + 1..];
+    }
+
+    result.push_str(rest);
+    result
+}
+
+/// Rewrite markdown link targets and quoted `src` values that point at an
+/// extracted image. EPUB refs use varying relative paths (../Images/fig.png vs
+/// Images/fig.png vs fig.png), so targets are matched on their filename
+fn rewrite_image_paths(md: &str, image_map: &ImageMap) -> String {
+    if image_map.is_empty() {
+        return md.to_string();
+    }
+
+    let by_filename: HashMap<_, &str> = image_map
+        .iter()
+        .map(|(original, replacement)| (target_filename(original), replacement.as_str()))
+        .collect();
+
+    let mut result = String::with_capacity(md.len());
+    let mut rest = md;
+
+    while let Some((start, opener, closers)) = next_link_target(rest) {
+        let value_start = start + opener.len();
+        result.push_str(&rest[..value_start]);
+        rest = &rest[value_start..];
+
+        let end = rest.find(closers).unwrap_or(rest.len());
+        let target = &rest[..end];
+        match by_filename.get(&target_filename(target)) {
+            Some(replacement) => result.push_str(replacement),
+            None => result.push_str(target),
+        }
+        rest = &rest[end..];
+    }
+
+    result.push_str(rest);
+    result
+}
+
+/// Find the next `](` or `src="` target opener, with the characters that end its value
+fn next_link_target(md: &str) -> Option<(usize, &'static str, &'static [char])> {
+    const OPENERS: [(&str, &[char]); 2] = [("](", &[')', ' ']), ("src=\"", &['"'])];
+    OPENERS
+        .iter()
+        .filter_map(|&(opener, closers)| md.find(opener).map(|i| (i, opener, closers)))
+        .min_by_key(|(i, _, _)| *i)
+}
+
+/// Decoded filename of a link target, for matching references against image hrefs
+fn target_filename(target: &str) -> Cow<'_, str> {
+    html_preprocess::percent_decode(html_preprocess::file_name(target))
+}
+
+/// Obsidian-safe note name for a title: characters Obsidian forbids in note
+/// names (`*"\\/<>:|?#^[]`) are dropped and whitespace is collapsed
+pub fn note_name(title: &str) -> String {
+    let kept: String = title
+        .chars()
+        .filter(|c| {
+            !matches!(
+                c,
+                '*' | '"' | '\\' | '/' | '<' | '>' | ':' | '|' | '?' | '#' | '^' | '[' | ']'
+            )
+        })
+        .collect();
+    kept.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .trim_start_matches('.')
+        .to_string()
+}
+
+/// `[[note]]`, or `[[note|text]]` when the link text differs from the note name
+pub fn wikilink(note: &str, text: &str) -> String {
+    let text = text.trim().replace('|', "-");
+    if text.is_empty() || text == note {
+        format!("[[{}]]", note)
+    } else {
+        format!("[[{}|{}]]", note, text)
+    }
+}
+
+/// Rewrite the target of every `[text](target)` link (not images) for which
+/// `retarget` returns a replacement
+pub fn retarget_links(md: &str, retarget: impl Fn(&str) -> Option<String>) -> String {
+    let mut result = String::with_capacity(md.len());
+    let mut rest = md;
+
+    while let Some(open) = rest.find("](") {
+        let is_image = rest[..open]
+            .rfind('[')
+            .is_some_and(|start| rest[..start].ends_with('!'));
+        let target_start = open + "](".len();
+        let target_len = rest[target_start..]
+            .find([')', ' ', '\n'])
+            .unwrap_or(rest.len() - target_start);
+        let target = &rest[target_start..target_start + target_len];
+        result.push_str(&rest[..target_start]);
+        match retarget(target).filter(|_| !is_image) {
+            Some(replacement) => result.push_str(&replacement),
+            None => result.push_str(target),
+        }
+        rest = &rest[target_start + target_len..];
+    }
+
+    result.push_str(rest);
+    result
+}
+
+/// Split markdown before every heading of `max_level` or shallower (outside code
+/// fences). The first part holds whatever precedes the first such heading
+pub fn split_at_headings(md: &str, max_level: u8) -> Vec<String> {
+    let lines: Vec<&str> = md.lines().collect();
+    let mut parts = vec![String::new()];
+    for (line, heading) in lines.iter().zip(scan_headings(&lines)) {
+        let splits = heading.is_some_and(|h| !h.setext && h.level <= max_level as usize);
+        if splits {
+            parts.push(String::new());
+        }
+        let part = parts.last_mut().expect("parts start non-empty");
+        part.push_str(line);
+        part.push('\n');
+    }
+    parts
+}
+
+/// Rewrite `[text](target)` links whose target document is a key of `notes`
+/// (source filename → note name) as wikilinks; fragments are dropped because
+/// Obsidian addresses headings by text, not id
+pub fn wikilinks(md: &str, notes: &HashMap<String, String>) -> String {
+    let mut result = String::with_capacity(md.len());
+    let mut rest = md;
+
+    while let Some(open) = rest.find('[') {
+        let is_image = rest[..open].ends_with('!');
+        let after = &rest[open + 1..];
+        let link = after.find("](").and_then(|text_len| {
+            let text = &after[..text_len];
+            if is_image || text.contains(['[', '\n']) {
+                return None;
+            }
+            let target_start = text_len + "](".len();
+            let target_len = after[target_start..].find(')')?;
+            let target = after[target_start..target_start + target_len]
+                .split_whitespace()
+                .next()
+                .unwrap_or_default();
+            if target.contains("://") || target.starts_with("mailto:") {
+                return None;
+            }
+            let note = notes.get(target_filename(target).as_ref())?;
+            Some((
+                wikilink(note, text),
+                open + 1 + target_start + target_len + 1,
+            ))
+        });
+
+        match link {
+            Some((wikilink, end)) => {
+                result.push_str(&rest[..open]);
+                result.push_str(&wikilink);
+                rest = &rest[end..];
+            }
+            None => {
+                result.push_str(&rest[..open + 1]);
+                rest = &rest[open + 1..];
+            }
+        }
+    }
+
+    result.push_str(rest);
+    result
+}
+
+/// List a chapter's audio and video files; files that were extracted are linked
+fn media_note(media: &[MediaFile], image_map: &ImageMap) -> String {
+    let extracted: HashSet<_> = image_map.keys().map(|href| target_filename(href)).collect();
+    let mut note = String::from("\n\n**Media files:**\n\n");
+    for file in media {
+        let name = target_filename(&file.src);
+        if extracted.contains(&name) {
+            note.push_str(&format!(
+                "- [{}]({}) ({})\n",
+                name,
+                file.src,
+                file.kind.to_lowercase()
+            ));
+        } else {
+            note.push_str(&format!("- `{}` ({})\n", name, file.kind.to_lowercase()));
+        }
+    }
+    note
+}
+
+/// Append an anchor to every ATX and setext heading outside code fences.
+/// `used` counts slugs across calls so anchors stay unique in combined output
+pub fn add_heading_anchors(
+    md: &str,
+    style: HeadingAnchors,
+    used: &mut HashMap<String, usize>,
+) -> String {
+    let lines: Vec<&str> = md.lines().collect();
+    let headings = scan_headings(&lines);
+    let mut result = String::with_capacity(md.len());
+
+    for (line, heading) in lines.iter().zip(&headings) {
+        result.push_str(line);
+        let text = heading.as_ref().map(|h| h.text);
+        // Leave headings that already carry an explicit anchor alone
+        if let Some(text) = text.filter(|t| !t.contains("{#") && !t.contains("<a id=")) {
+            let slug = unique_slug(&heading_slug(text), used);
+            if !slug.is_empty() {
+                match style {
+                    HeadingAnchors::Attribute => result.push_str(&format!(" {{#{}}}", slug)),
+                    HeadingAnchors::Html => result.push_str(&format!(" <a id=\"{}\"></a>", slug)),
+                }
+            }
+        }
+        result.push('\n');
+    }
+
+    result
+}
+
+/// Shallowest heading level (1 = `#`) in a markdown document
+pub fn min_heading_level(md: &str) -> Option<usize> {
+    let lines: Vec<&str> = md.lines().collect();
+    scan_headings(&lines)
+        .iter()
+        .flatten()
+        .map(|h| h.level)
+        .min()
+}
+
+/// Text of the heading on the first non-blank line, if that line is a heading
+pub fn leading_heading(md: &str) -> Option<&str> {
+    let lines: Vec<&str> = md.lines().collect();
+    let first = lines.iter().position(|l| !l.trim().is_empty())?;
+    scan_headings(&lines[first..first + 2.min(lines.len() - first)])
+        .into_iter()
+        .next()
+        .flatten()
+        .map(|h| h.text)
+}
+
+/// Move every heading `shift` levels deeper (or shallower when negative),
+/// clamped to 1..=6. Setext headings are rewritten in ATX form.
+pub fn shift_headings(md: &str, shift: isize) -> String {
+    let lines: Vec<&str> = md.lines().collect();
+    let headings = scan_headings(&lines);
+    let mut result = String::with_capacity(md.len());
+    let mut skip_underline = false;
+
+    for (line, heading) in lines.iter().zip(&headings) {
+        if skip_underline {
+            skip_underline = false;
+            continue;
+        }
+        match heading {
+            Some(heading) => {
+                let level = (heading.level as isize + shift).clamp(1, 6) as usize;
+                result.push_str(&format!("{} {}", "#".repeat(level), heading.text));
+                skip_underline = heading.setext;
+            }
+            None => result.push_str(line),
+        }
+        result.push('\n');
+    }
+
+    result
+}
+
+/// Close skipped heading levels so each heading is at most one level deeper than
+/// the heading it falls under (`#`, `###`, `####` → `#`, `##`, `###`); shallower
+/// headings and the first one keep their level. Changed setext headings are
+/// rewritten in ATX form.
+pub fn normalize_heading_levels(md: &str) -> String {
+    let lines: Vec<&str> = md.lines().collect();
+    let headings = scan_headings(&lines);
+    let mut result = String::with_capacity(md.len());
+    // (source level, output level) of the open headings, outermost first
+    let mut open: Vec<(usize, usize)> = Vec::new();
+    let mut skip_underline = false;
+
+    for (line, heading) in lines.iter().zip(&headings) {
+        if skip_underline {
+            skip_underline = false;
+            continue;
+        }
+        let Some(heading) = heading else {
+            result.push_str(line);
+            result.push('\n');
+            continue;
+        };
+
+        while open
+            .last()
+            .is_some_and(|&(source, _)| source >= heading.level)
+        {
+            open.pop();
+        }
+        let level = open.last().map_or(heading.level, |&(_, parent)| parent + 1);
+        open.push((heading.level, level));
+
+        if level == heading.level {
+            result.push_str(line);
+        } else {
+            // A closing `###` sequence would no longer match the level
+            let text = heading.text.trim_end_matches('#');
+            let text = text
+                .strip_suffix(' ')
+                .map_or(heading.text, |text| text.trim_end());
+            result.push_str(&format!("{} {}", "#".repeat(level), text));
+            skip_underline = heading.setext;
+        }
+        result.push('\n');
+    }
+
+    result
+}
+
+/// A heading found on a line; setext headings have their underline on the next line
+struct HeadingLine<'a> {
+    level: usize,
+    text: &'a str,
+    setext: bool,
+}
+
+/// The heading starting on each line, if any, ignoring fenced code blocks
+fn scan_headings<'a>(lines: &[&'a str]) -> Vec<Option<HeadingLine<'a>>> {
+    let mut headings = Vec::with_capacity(lines.len());
+    let mut in_fence = false;
+    let mut underline = false;
+
+    for (i, line) in lines.iter().enumerate() {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_fence = !in_fence;
+        }
+
+        // The underline of a setext heading is not a heading itself
+        if in_fence || std::mem::take(&mut underline) {
+            headings.push(None);
+            continue;
+        }
+
+        let heading = if let Some((level, text)) = atx_heading(line) {
+            Some(HeadingLine {
+                level,
+                text,
+                setext: false,
+            })
+        } else if let Some(level) = lines.get(i + 1).and_then(|next| setext_level(next)) {
+            underline = !trimmed.is_empty();
+            (!trimmed.is_empty()).then(|| HeadingLine {
+                level,
+                text: line.trim(),
+                setext: true,
+            })
+        } else {
+            None
+        };
+        headings.push(heading);
+    }
+
+    headings
+}
+
+fn atx_heading(line: &str) -> Option<(usize, &str)> {
+    let hashes = line.len() - line.trim_start_matches('#').len();
+    if !(1..=6).contains(&hashes) {
+        return None;
+    }
+    let text = line[hashes..].strip_prefix(' ')?.trim();
+    (!text.is_empty()).then_some((hashes, text))
+}
+
+/// Level of a setext underline: `===` is 1, `---` is 2
+fn setext_level(line: &str) -> Option<usize> {
+    let line = line.trim();
+    if line.is_empty() {
+        None
+    } else if line.chars().all(|c| c == '=') {
+        Some(1)
+    } else if line.chars().all(|c| c == '-') {
+        Some(2)
+    } else {
+        None
+    }
+}
+
+/// GitHub-style slug: lowercase alphanumerics with spaces turned into hyphens;
+/// link targets and markdown punctuation are dropped
+pub fn heading_slug(text: &str) -> String {
+    let mut slug = String::new();
+    let mut rest = text;
+
+    while let Some(c) = rest.chars().next() {
+        if rest.starts_with("](") {
+            // Skip the link target, keeping only the link text
+            rest = rest.find(')').map_or("", |end| &rest[end + 1..]);
+            continue;
+        }
+        if c.is_alphanumeric() {
+            slug.extend(c.to_lowercase());
+        } else if c == ' ' || c == '-' {
+            slug.push('-');
+        }
+        rest = &rest[c.len_utf8()..];
+    }
+
+    slug.trim_matches('-').to_string()
+}
+
+pub fn unique_slug(slug: &str, used: &mut HashMap<String, usize>) -> String {
+    let count = used.entry(slug.to_string()).or_insert(0);
+    *count += 1;
+    if *count == 1 {
+        slug.to_string()
+    } else {
+        format!("{}-{}", slug, *count - 1)
+    }
+}
+
+/// Wrap runs of at least `min` consecutive image-only lines (blank lines between
+/// them allowed) in a `<div class="gallery">`, with the images on one line so
+/// they render side by side
+pub fn gallery(md: &str, min: usize) -> String {
+    let lines: Vec<&str> = md.lines().collect();
+    let mut result = String::with_capacity(md.len());
+    let mut in_fence = false;
+    let mut i = 0;
+
+    while i < lines.len() {
+        let line = lines[i];
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_fence = !in_fence;
+        }
+
+        if !in_fence && is_image_line(line) {
+            // Collect the run, skipping blank lines between images
+            let mut images = Vec::new();
+            let mut end = i;
+            let mut j = i;
+            while j < lines.len() {
+                if is_image_line(lines[j]) {
+                    images.push(lines[j].trim());
+                    end = j + 1;
+                } else if !lines[j].trim().is_empty() {
+                    break;
+                }
+                j += 1;
+            }
+
+            let count: usize = images.iter().map(|l| image_references(l).len()).sum();
+            if count >= min.max(2) {
+                result.push_str("<div class=\"gallery\">\n\n");
+                result.push_str(&images.join(" "));
+                result.push_str("\n\n</div>\n");
+                i = end;
+                continue;
+            }
+        }
+
+        result.push_str(line);
+        result.push('\n');
+        i += 1;
+    }
+
+    result
+}
+
+/// True for a line made up only of `![alt](src)` images
+fn is_image_line(line: &str) -> bool {
+    let mut rest = line.trim();
+    if rest.is_empty() {
+        return false;
+    }
+    while !rest.is_empty() {
+        let Some(after) = rest.strip_prefix("![") else {
+            return false;
+        };
+        let Some(alt_len) = after.find("](") else {
+            return false;
+        };
+        let target = &after[alt_len + "](".len()..];
+        let Some(target_len) = target.find(')') else {
+            return false;
+        };
+        rest = target[target_len + 1..].trim_start();
+    }
+    true
+}
+
+/// Words of prose in markdown: whitespace-separated tokens with at least one
+/// letter or digit, so markers like `#`, `*` and `---` aren't counted
+pub fn word_count(md: &str) -> usize {
+    md.split_whitespace()
+        .filter(|token| token.chars().any(char::is_alphanumeric))
+        .count()
+}
+
+/// Replace page placeholders with an anchor or comment for the page label they index.
+/// A placeholder on a line of its own (a page break between blocks) is set apart by
+/// blank lines, so it can't join a table or list above it
+pub fn restore_page_anchors(md: &str, labels: &[String], style: PageAnchorStyle) -> String {
+    let mut spaced = String::with_capacity(md.len());
+    let mut lines = md.lines().peekable();
+    while let Some(line) = lines.next() {
+        let alone = line.starts_with(PAGE_PLACEHOLDER)
+            && line.split(PAGE_PLACEHOLDER).all(|part| {
+                part.trim_end_matches(|c: char| c.is_ascii_digit() || c == 'X')
+                    .is_empty()
+            });
+        if alone && !spaced.is_empty() && !spaced.ends_with("\n\n") {
+            spaced.push('\n');
+        }
+        spaced.push_str(line);
+        spaced.push('\n');
+        if alone && lines.peek().is_some_and(|next| !next.is_empty()) {
+            spaced.push('\n');
+        }
+    }
+    if !md.ends_with('\n') {
+        spaced.pop();
+    }
+
+    let mut result = String::with_capacity(spaced.len());
+    let mut rest = spaced.as_str();
+
+    while let Some(start) = rest.find(PAGE_PLACEHOLDER) {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + PAGE_PLACEHOLDER.len()..];
+        let digits = after.len() - after.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        let label = after[..digits]
+            .parse::<usize>()
+            .ok()
+            .and_then(|i| labels.get(i))
+            .filter(|_| after[digits..].starts_with('X'));
+        let Some(label) = label else {
+            result.push_str(PAGE_PLACEHOLDER);
+            rest = after;
+            continue;
+        };
+
+        match style {
+            PageAnchorStyle::Anchor => {
+                result.push_str(&format!("<a id=\"page-{}\"></a>", heading_slug(label)))
+            }
+            PageAnchorStyle::Comment => result.push_str(&format!("<!-- page {} -->", label)),
+        }
+        rest = &after[digits + 1..];
+    }
+
+    result.push_str(rest);
+    result
+}
+
+/// Title for a chapter that has none: the first sentence of its first paragraph of
+/// text (images and headings are passed over), as plain text cut to `max_words`
+pub fn title_from_content(md: &str, max_words: usize) -> Option<String> {
+    let mut text = String::new();
+    let mut in_paragraph = false;
+    let mut image_depth = 0usize;
+
+    for event in Parser::new_ext(md, Options::ENABLE_TABLES | Options::ENABLE_FOOTNOTES) {
+        match event {
+            Event::Start(Tag::Paragraph) => in_paragraph = true,
+            Event::End(TagEnd::Paragraph) => {
+                if text.chars().any(char::is_alphanumeric) {
+                    break;
+                }
+                in_paragraph = false;
+                text.clear();
+            }
+            Event::Start(Tag::Image { .. }) => image_depth += 1,
+            Event::End(TagEnd::Image) => image_depth = image_depth.saturating_sub(1),
+            Event::Text(t) | Event::Code(t) if in_paragraph && image_depth == 0 => {
+                text.push_str(&t)
+            }
+            Event::SoftBreak | Event::HardBreak if in_paragraph => text.push(' '),
+            _ => {}
+        }
+    }
+
+    let words: Vec<&str> = text.split_whitespace().collect();
+    if !words.iter().any(|w| w.chars().any(char::is_alphanumeric)) {
+        return None;
+    }
+    // The sentence ends at the first word closing with a full stop, `!` or `?`
+    let sentence_end = words
+        .iter()
+        .position(|w| {
+            w.trim_end_matches(['"', '\'', '”', '’', ')'])
+                .ends_with(['.', '!', '?'])
+        })
+        .map_or(words.len(), |i| i + 1);
+    let limit = max_words.max(1);
+
+    let mut title = words[..sentence_end.min(limit)].join(" ");
+    if sentence_end > limit {
+        title = title
+            .trim_end_matches([',', ';', ':', '—', '–'])
+            .to_string();
+        title.push('…');
+    }
+    Some(title)
+}
+
+/// Drop ATX heading lines with no text (`##`, `### ##`) outside code fences,
+/// along with the blank line that set them apart
+pub fn remove_empty_headings(md: &str) -> String {
+    let mut result = String::with_capacity(md.len());
+    let mut in_fence = false;
+    let mut removed = false;
+
+    for line in md.split_inclusive('\n') {
+        let trimmed = line.trim();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_fence = !in_fence;
+        }
+        if !in_fence && is_empty_heading(line) {
+            removed = true;
+            continue;
+        }
+        if removed && trimmed.is_empty() && (result.is_empty() || result.ends_with("\n\n")) {
+            continue;
+        }
+        removed = false;
+        result.push_str(line);
+    }
+
+    // A dropped heading at the end leaves the blank line before it trailing
+    if removed {
+        while result.ends_with("\n\n") {
+            result.pop();
+        }
+    }
+    result
+}
+
+/// `#` to `######` (indented at most three spaces) followed by nothing but
+/// whitespace and closing hashes
+fn is_empty_heading(line: &str) -> bool {
+    let line = line.trim_end();
+    let indent = line.len() - line.trim_start_matches(' ').len();
+    let marker = &line[indent..];
+    let hashes = marker.len() - marker.trim_start_matches('#').len();
+    indent <= 3
+        && (1..=6).contains(&hashes)
+        && marker[hashes..].trim().trim_matches('#').is_empty()
+        && (marker.len() == hashes || marker[hashes..].starts_with([' ', '\t']))
+}
+
+/// Turn list items into plain paragraphs that keep their marker as text:
+/// bullets become `•`, numbers keep their value with the period escaped.
+/// Nesting is dropped; continuation lines are unindented so they can't turn into code.
+pub fn flatten_lists(md: &str) -> String {
+    let mut result = String::with_capacity(md.len());
+    let mut in_fence = false;
+    let mut in_item = false;
+
+    for line in md.lines() {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_fence = !in_fence;
+        }
+        if in_fence {
+            in_item = false;
+            result.push_str(line);
+            result.push('\n');
+            continue;
+        }
+
+        if let Some((marker, text)) = list_item(trimmed) {
+            if in_item {
+                // Separate consecutive items so each stays its own paragraph
+                result.push('\n');
+            }
+            result.push_str(&marker);
+            result.push(' ');
+            result.push_str(text);
+            in_item = true;
+        } else if in_item && !trimmed.is_empty() && trimmed.len() < line.len() {
+            result.push_str(trimmed);
+        } else {
+            in_item = !trimmed.is_empty() && in_item;
+            result.push_str(line);
+        }
+        result.push('\n');
+    }
+
+    result
+}
+
+/// Plain-text marker and text of a list item line (already unindented)
+fn list_item(line: &str) -> Option<(String, &str)> {
+    if let Some(text) = ["- ", "* ", "+ "].iter().find_map(|b| line.strip_prefix(b)) {
+        return Some(("•".to_string(), text.trim_start()));
+    }
+    let digits = line.len() - line.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    if digits == 0 || digits > 9 {
+        return None;
+    }
+    let rest = &line[digits..];
+    let text = rest
+        .strip_prefix(". ")
+        .or_else(|| rest.strip_prefix(") "))?;
+    Some((
+        format!("{}\\{}", &line[..digits], &rest[..1]),
+        text.trim_start(),
+    ))
+}
+
+/// Join runs of footnote references (`[^1]`) separated by spaces into one group
+/// attached to the preceding text, e.g. `word [^1] [^2] .` → `word[^1][^2].`.
+/// A word after the group stays one space away; definitions and code are left alone
+pub fn collapse_footnote_refs(md: &str) -> String {
+    let mut result = String::with_capacity(md.len());
+    let mut in_fence = false;
+
+    for line in md.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_fence = !in_fence;
+        }
+        if in_fence || !line.contains("[^") {
+            result.push_str(line);
+            continue;
+        }
+
+        let mut rest = line;
+        let mut in_code = false;
+        while !rest.is_empty() {
+            if rest.starts_with('`') {
+                in_code = !in_code;
+            }
+            let group = if in_code || result.ends_with('\\') {
+                0
+            } else {
+                footnote_group(rest)
+            };
+            if group == 0 || (result.is_empty() || result.ends_with('\n')) && is_definition(rest) {
+                let len = rest.chars().next().map_or(1, char::len_utf8);
+                result.push_str(&rest[..len]);
+                rest = &rest[len..];
+                continue;
+            }
+
+            // A line that starts with a reference keeps its indentation
+            if !result.trim_end_matches([' ', '\t']).ends_with('\n') {
+                let kept = result.trim_end_matches([' ', '\t']).len();
+                result.truncate(kept);
+            }
+            result.push_str(&rest[..group].replace([' ', '\t'], ""));
+            rest = &rest[group..];
+
+            let after = rest.trim_start_matches([' ', '\t']);
+            if after.starts_with(['.', ',', ';', ':', '!', '?', ')', ']']) {
+                rest = after;
+            } else if after.len() < rest.len() && !after.starts_with(['\n', '\r']) {
+                result.push(' ');
+                rest = after;
+            }
+        }
+    }
+
+    result
+}
+
+/// Replace footnote links whose text is a bracketed number (`[[12]](#fn12)`,
+/// `[\[12\]](#fn12)`, `<sup>` inside or around) with `[^12]`. The marker opening a
+/// paragraph links back from the note itself, so it becomes the `[^12]:` definition.
+/// Code is left alone
+pub fn trim_footnote_markers(md: &str) -> String {
+    let mut result = String::with_capacity(md.len());
+    let mut in_fence = false;
+
+    for line in md.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_fence = !in_fence;
+        }
+        if in_fence || !line.contains("](") {
+            result.push_str(line);
+            continue;
+        }
+
+        if let Some((len, label)) = footnote_marker(line) {
+            result.push_str(&format!("[^{}]: ", label));
+            result.push_str(line[len..].trim_start_matches([' ', '\t']));
+            continue;
+        }
+        let mut rest = line;
+        let mut in_code = false;
+        while !rest.is_empty() {
+            if rest.starts_with('`') {
+                in_code = !in_code;
+            }
+            match footnote_marker(rest).filter(|_| !in_code && !result.ends_with('\\')) {
+                Some((len, label)) => {
+                    result.push_str(&format!("[^{}]", label));
+                    rest = &rest[len..];
+                }
+                None => {
+                    let len = rest.chars().next().map_or(1, char::len_utf8);
+                    result.push_str(&rest[..len]);
+                    rest = &rest[len..];
+                }
+            }
+        }
+    }
+
+    result
+}
+
+/// A link at the start of `text` whose text is a bracketed number and whose target
+/// has a fragment, optionally wrapped in `<sup>`: its length and the number
+fn footnote_marker(text: &str) -> Option<(usize, &str)> {
+    let wrapped = text.starts_with("<sup>");
+    let start = if wrapped { "<sup>".len() } else { 0 };
+    let link = text[start..].strip_prefix('[')?;
+    let text_len = link.find("](")?;
+    let label = bracketed_number(&link[..text_len])?;
+    let target = &link[text_len + 2..];
+    let target_len = target.find(')')?;
+    if !target[..target_len].contains('#') || target[..target_len].contains(char::is_whitespace) {
+        return None;
+    }
+
+    let len = start + 1 + text_len + 2 + target_len + 1;
+    match wrapped {
+        true if text[len..].starts_with("</sup>") => Some((len + "</sup>".len(), label)),
+        true => None,
+        false => Some((len, label)),
+    }
+}
+
+/// `12` from `[12]`, `\[12\]` or `<sup>[12]</sup>`
+fn bracketed_number(text: &str) -> Option<&str> {
+    let text = text
+        .strip_prefix("<sup>")
+        .and_then(|t| t.strip_suffix("</sup>"))
+        .unwrap_or(text);
+    let number = text
+        .strip_prefix("\\[")
+        .and_then(|t| t.strip_suffix("\\]"))
+        .or_else(|| text.strip_prefix('[')?.strip_suffix(']'))?;
+    (!number.is_empty() && number.len() <= 6 && number.chars().all(|c| c.is_ascii_digit()))
+        .then_some(number)
+}
+
+/// Length of the run of footnote references (`[^label]`, separated by spaces or
+/// tabs) at the start of `text`, or 0 when it doesn't start with one
+fn footnote_group(text: &str) -> usize {
+    let mut len = 0;
+    loop {
+        let candidate = &text[len..];
+        let gap = candidate.len() - candidate.trim_start_matches([' ', '\t']).len();
+        let gap = if len == 0 { 0 } else { gap };
+        let reference = &candidate[gap..];
+        let Some(label_len) = reference
+            .strip_prefix("[^")
+            .and_then(|label| label.find(']'))
+            .filter(|&n| n > 0 && !reference[2..2 + n].contains([' ', '[', '^']))
+        else {
+            return len;
+        };
+        len += gap + label_len + 3;
+    }
+}
+
+/// A footnote definition (`[^1]: …`) starting at `text`
+fn is_definition(text: &str) -> bool {
+    let group = footnote_group(text);
+    group > 0 && text[..group].matches("[^").count() == 1 && text[group..].starts_with(':')
+}
+
+/// Pad table columns to the width of their widest cell so the raw table lines up.
+/// Widths are measured in terminal columns, so CJK text counts double
+pub fn prettify_tables(md: &str) -> String {
+    let lines: Vec<&str> = md.lines().collect();
+    let mut result = String::with_capacity(md.len() + md.len() / 4);
+    let mut in_fence = false;
+    let mut i = 0;
+
+    while i < lines.len() {
+        let trimmed = lines[i].trim_start();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_fence = !in_fence;
+        }
+
+        // GFM only reads a table when the header and delimiter rows have as many cells
+        let is_table = !in_fence
+            && trimmed.starts_with('|')
+            && lines
+                .get(i + 1)
+                .and_then(|next| delimiter_row(next))
+                .is_some_and(|alignments| alignments.len() == table_cells(lines[i]).len());
+        if !is_table {
+            result.push_str(lines[i]);
+            result.push('\n');
+            i += 1;
+            continue;
+        }
+
+        let end = (i + 2..lines.len())
+            .find(|&j| !lines[j].trim_start().starts_with('|'))
+            .unwrap_or(lines.len());
+        let indent = &lines[i][..lines[i].len() - trimmed.len()];
+        result.push_str(&align_table(&lines[i..end], indent));
+        i = end;
+    }
+
+    if !md.ends_with('\n') {
+        result.pop();
+    }
+    result
+}
+
+/// Rework tables with more than `max_columns` columns: a table with a single body
+/// row becomes a `- **Header:** value` list, others are split into stacked tables
+/// of at most `max_columns` columns that each repeat the first (label) column and
+/// their part of the header row. `max_columns` must be at least 2
+pub fn wrap_wide_tables(md: &str, max_columns: usize) -> String {
+    let lines: Vec<&str> = md.lines().collect();
+    let mut result = String::with_capacity(md.len());
+    let mut in_fence = false;
+    let mut i = 0;
+
+    while i < lines.len() {
+        let trimmed = lines[i].trim_start();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_fence = !in_fence;
+        }
+
+        let alignments = (!in_fence && trimmed.starts_with('|'))
+            .then(|| lines.get(i + 1).and_then(|next| delimiter_row(next)))
+            .flatten()
+            .filter(|alignments| alignments.len() == table_cells(lines[i]).len());
+        let Some(alignments) = alignments.filter(|a| a.len() > max_columns) else {
+            result.push_str(lines[i]);
+            result.push('\n');
+            i += 1;
+            continue;
+        };
+
+        let end = (i + 2..lines.len())
+            .find(|&j| !lines[j].trim_start().starts_with('|'))
+            .unwrap_or(lines.len());
+        let indent = &lines[i][..lines[i].len() - trimmed.len()];
+        let header = table_cells(lines[i]);
+        let rows: Vec<Vec<String>> = lines[i + 2..end]
+            .iter()
+            .map(|line| {
+                let mut cells = table_cells(line);
+                cells.resize(header.len(), String::new());
+                cells
+            })
+            .collect();
+
+        match rows.as_slice() {
+            [row] => result.push_str(&key_value_list(&header, row, indent)),
+            _ => result.push_str(&split_table(
+                &header,
+                &alignments,
+                &rows,
+                max_columns,
+                indent,
+            )),
+        }
+        i = end;
+    }
+
+    if !md.ends_with('\n') {
+        result.pop();
+    }
+    result
+}
+
+/// One `- **Header:** value` item per column of a single-row table
+fn key_value_list(header: &[String], row: &[String], indent: &str) -> String {
+    let mut list = String::new();
+    for (key, value) in header.iter().zip(row) {
+        // Header cells are often bold already
+        let key = key
+            .strip_prefix("**")
+            .and_then(|k| k.strip_suffix("**"))
+            .unwrap_or(key);
+        let item = match key {
+            "" => value.clone(),
+            key => format!("**{}:** {}", key, value),
+        };
+        list.push_str(&format!("{}- {}\n", indent, item.trim_end()));
+    }
+    list
+}
+
+/// Stacked tables of the first column plus up to `max_columns - 1` further columns,
+/// separated by blank lines
+fn split_table(
+    header: &[String],
+    alignments: &[(bool, bool)],
+    rows: &[Vec<String>],
+    max_columns: usize,
+    indent: &str,
+) -> String {
+    let render = |cells: Vec<&str>| format!("{}| {} |\n", indent, cells.join(" | "));
+    let delimiter = |(left, right): (bool, bool)| match (left, right) {
+        (true, true) => ":---:",
+        (true, false) => ":---",
+        (false, true) => "---:",
+        (false, false) => "---",
+    };
+
+    let parts: Vec<String> = (1..header.len())
+        .collect::<Vec<_>>()
+        .chunks(max_columns - 1)
+        .map(|chunk| {
+            let columns: Vec<usize> = std::iter::once(0).chain(chunk.iter().copied()).collect();
+            let mut table = render(columns.iter().map(|&c| header[c].as_str()).collect());
+            table.push_str(&render(
+                columns.iter().map(|&c| delimiter(alignments[c])).collect(),
+            ));
+            for row in rows {
+                table.push_str(&render(columns.iter().map(|&c| row[c].as_str()).collect()));
+            }
+            table
+        })
+        .collect();
+    parts.join("\n")
+}
+
+/// Column alignments of a table delimiter row (`| :--- | ---: |`) as whether
+/// each cell has a left and a right colon, or `None` when the line isn't one
+fn delimiter_row(line: &str) -> Option<Vec<(bool, bool)>> {
+    // Without a pipe, `---` is a rule or a setext heading underline
+    if !line.contains('|') {
+        return None;
+    }
+    let cells = table_cells(line);
+    let alignments: Option<Vec<_>> = cells
+        .iter()
+        .map(|cell| {
+            let dashes = cell.trim_start_matches(':').trim_end_matches(':');
+            (!dashes.is_empty() && dashes.chars().all(|c| c == '-'))
+                .then(|| (cell.starts_with(':'), cell.ends_with(':')))
+        })
+        .collect();
+    alignments.filter(|a| !a.is_empty())
+}
+
+/// Trimmed cells of a table row, split on pipes outside code spans and escapes
+fn table_cells(line: &str) -> Vec<String> {
+    let row = line.trim();
+    let row = row.strip_prefix('|').unwrap_or(row);
+    let row = match row.strip_suffix('|') {
+        Some(inner) if !inner.ends_with('\\') => inner,
+        _ => row,
+    };
+
+    let mut cells = Vec::new();
+    let mut cell = String::new();
+    let mut in_code = false;
+    let mut chars = row.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                cell.push(c);
+                cell.extend(chars.next());
+            }
+            '`' => {
+                in_code = !in_code;
+                cell.push(c);
+            }
+            '|' if !in_code => cells.push(std::mem::take(&mut cell).trim().to_string()),
+            _ => cell.push(c),
+        }
+    }
+    cells.push(cell.trim().to_string());
+    cells
+}
+
+/// Re-render a table (header, delimiter row and body rows) with padded columns
+fn align_table(lines: &[&str], indent: &str) -> String {
+    let alignments = delimiter_row(lines[1]).unwrap_or_default();
+    let mut rows: Vec<Vec<String>> = lines
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| i != 1)
+        .map(|(_, line)| table_cells(line))
+        .collect();
+    // The header fixes the column count; cells past it (an unescaped `|` in the
+    // text) aren't rendered as a column, so they're kept as they are but not padded
+    let columns = alignments.len();
+    for row in &mut rows {
+        if row.len() < columns {
+            row.resize(columns, String::new());
+        }
+    }
+
+    // Delimiter cells need at least three dashes
+    let widths: Vec<usize> = (0..columns)
+        .map(|col| {
+            rows.iter()
+                .map(|row| display_width(&row[col]))
+                .max()
+                .unwrap_or(0)
+                .max(3)
+        })
+        .collect();
+    let alignment = |col: usize| alignments.get(col).copied().unwrap_or((false, false));
+
+    let render = |row: &[String]| {
+        let cells: Vec<String> = row
+            .iter()
+            .enumerate()
+            .map(|(col, cell)| {
+                let Some(&width) = widths.get(col) else {
+                    return cell.clone();
+                };
+                let pad = width - display_width(cell);
+                match alignment(col) {
+                    (false, true) => format!("{}{}", " ".repeat(pad), cell),
+                    (true, true) => {
+                        let left = pad / 2;
+                        format!("{}{}{}", " ".repeat(left), cell, " ".repeat(pad - left))
+                    }
+                    _ => format!("{}{}", cell, " ".repeat(pad)),
+                }
+            })
+            .collect();
+        format!("{}| {} |\n", indent, cells.join(" | "))
+    };
+
+    let delimiter: Vec<String> = (0..columns)
+        .map(|col| {
+            let (left, right) = alignment(col);
+            let dashes = widths[col] - usize::from(left) - usize::from(right);
+            format!(
+                "{}{}{}",
+                if left { ":" } else { "" },
+                "-".repeat(dashes),
+                if right { ":" } else { "" }
+            )
+        })
+        .collect();
+
+    let mut table = render(&rows[0]);
+    table.push_str(&format!("{}| {} |\n", indent, delimiter.join(" | ")));
+    for row in &rows[1..] {
+        table.push_str(&render(row));
+    }
+    table
+}
+
+/// Columns a string takes up in a monospaced font: East Asian wide and fullwidth
+/// characters take two, combining marks and zero-width characters none
+fn display_width(text: &str) -> usize {
+    text.chars()
+        .map(|c| match c as u32 {
+            0x0300..=0x036F | 0x200B..=0x200F | 0x20D0..=0x20FF | 0xFE00..=0xFE0F => 0,
+            0x1100..=0x115F
+            | 0x2E80..=0x303E
+            | 0x3041..=0x33FF
+            | 0x3400..=0x4DBF
+            | 0x4E00..=0x9FFF
+            | 0xA000..=0xA4CF
+            | 0xAC00..=0xD7A3
+            | 0xF900..=0xFAFF
+            | 0xFE30..=0xFE4F
+            | 0xFF00..=0xFF60
+            | 0xFFE0..=0xFFE6
+            | 0x1F300..=0x1F64F
+            | 0x1F900..=0x1F9FF
+            | 0x20000..=0x3FFFD => 2,
+            _ => 1,
+        })
+        .sum()
+}
+
+/// Quote a whole markdown document: every line gets a `> ` prefix (blank lines
+/// a bare `>`), so headings, lists and code fences stay inside one blockquote
+pub fn blockquote(md: &str) -> String {
+    let mut result = String::with_capacity(md.len() + md.len() / 8);
+    for line in md.lines() {
+        if line.is_empty() {
+            result.push_str(">\n");
+        } else {
+            result.push_str("> ");
+            result.push_str(line);
+            result.push('\n');
+        }
+    }
+    result
+}
+
+pub fn clean_markdown(md: &str, convert_entities: bool) -> String {
+    let mut result = if convert_entities {
+        decode_stray_entities(md)
+    } else {
+        md.to_string()
+    };
 
     // Collapse 3+ consecutive blank lines to 2
     while result.contains("\n\n\n") {
@@ -57,3 +1868,68 @@ pub fn clean_markdown(md: &str) -> String {
         trimmed + "\n"
     }
 }
+
+/// Decode character references html2md left in the text, outside code fences
+/// and code spans. `&lt;` / `&gt;` stay encoded so decoding can't create HTML tags,
+/// and spacer lines for empty paragraphs are kept.
+fn decode_stray_entities(md: &str) -> String {
+    let mut in_fence = false;
+    let lines: Vec<String> = md
+        .lines()
+        .map(|line| {
+            let trimmed = line.trim_start();
+            if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+                in_fence = !in_fence;
+                return line.to_string();
+            }
+            if in_fence || line.trim() == EMPTY_PARAGRAPH || !line.contains('&') {
+                return line.to_string();
+            }
+            decode_outside_code_spans(line)
+        })
+        .collect();
+    lines.join("\n")
+}
+
+fn decode_outside_code_spans(line: &str) -> String {
+    const KEEP: [&str; 2] = ["lt", "gt"];
+    let mut result = String::with_capacity(line.len());
+    let mut rest = line;
+
+    while let Some(start) = rest.find('`') {
+        result.push_str(&decode_entities_except(&rest[..start], &KEEP));
+        let run = rest[start..].len() - rest[start..].trim_start_matches('`').len();
+        let fence = &rest[start..start + run];
+        let after = &rest[start + run..];
+        // A code span closes at the next backtick run of the same length
+        match after.find(fence) {
+            Some(close) => {
+                let end = start + run + close + run;
+                result.push_str(&rest[start..end]);
+                rest = &rest[end..];
+            }
+            None => {
+                result.push_str(fence);
+                rest = after;
+            }
+        }
+    }
+
+    result.push_str(&decode_entities_except(rest, &KEEP));
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ignored_image_is_matched_by_path_not_filename() {
+        let ignored = HashSet::from(["/OEBPS/a/cover.jpg".to_string()]);
+        let md = "![A](../a/cover.jpg)\n\n![B](../b/cover.jpg)\n";
+        assert_eq!(
+            remove_ignored_images(md, "/OEBPS/Text/c1.xhtml", &ignored),
+            "\n\n![B](../b/cover.jpg)\n"
+        );
+    }
+}
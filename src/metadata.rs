@@ -1,55 +1,249 @@
+use crate::cli::MetadataField;
+use crate::docx_properties;
 use crate::reader::Metadata;
 
-pub fn format_metadata(meta: &Metadata) -> String {
-    let mut lines = Vec::new();
+/// Fields rendered when `--metadata-fields` isn't given, in header order
+pub const DEFAULT_FIELDS: [MetadataField; 7] = [
+    MetadataField::Title,
+    MetadataField::Author,
+    MetadataField::Publisher,
+    MetadataField::Language,
+    MetadataField::Rights,
+    MetadataField::ReadingTime,
+    MetadataField::Description,
+];
 
-    if let Some(ref title) = meta.title {
-        if !title.trim().is_empty() {
-            lines.push(format!("# {}", title));
-            lines.push(String::new());
+/// Header for a single input, rendering `fields` in order and then the `custom`
+/// properties the document has; `reading_minutes` is the estimated reading time,
+/// when computed
+pub fn format_metadata(
+    meta: &Metadata,
+    reading_minutes: Option<usize>,
+    fields: &[MetadataField],
+    custom: &[String],
+) -> String {
+    let mut lines: Vec<String> = Vec::new();
+    let present = |value: &Option<String>| value.clone().filter(|v| !v.trim().is_empty());
+
+    for field in fields {
+        match field {
+            // The title heading and description quote stand as blocks of their own
+            MetadataField::Title => {
+                if let Some(title) = present(&meta.title) {
+                    push_block(&mut lines, format!("# {}", title));
+                }
+            }
+            MetadataField::Description => {
+                if let Some(description) = present(&meta.description) {
+                    push_block(&mut lines, format!("> {}", description));
+                }
+            }
+            MetadataField::Author => {
+                let authors: Vec<_> = meta
+                    .authors
+                    .iter()
+                    .map(|a| a.as_str())
+                    .filter(|a| !a.trim().is_empty())
+                    .collect();
+                if !authors.is_empty() {
+                    lines.push(format!("**Author:** {}", authors.join(", ")));
+                }
+            }
+            MetadataField::Publisher => {
+                if let Some(publisher) = present(&meta.publisher) {
+                    lines.push(format!("**Publisher:** {}", publisher));
+                }
+            }
+            MetadataField::Language => {
+                if let Some(language) = present(&meta.language) {
+                    lines.push(format!("**Language:** {}", language));
+                }
+            }
+            MetadataField::Date => {
+                if let Some(date) = present(&meta.date) {
+                    lines.push(format!("**Date:** {}", date));
+                }
+            }
+            MetadataField::Rights => {
+                if let Some(rights) = present(&meta.rights) {
+                    lines.push(format!("**Rights:** {}", one_line(&rights)));
+                }
+            }
+            MetadataField::ReadingTime => {
+                if let Some(minutes) = reading_minutes {
+                    lines.push(reading_time_line(minutes));
+                }
+            }
         }
     }
 
-    let non_empty_authors: Vec<_> = meta
-        .authors
-        .iter()
-        .filter(|a| !a.trim().is_empty())
-        .collect();
-    if !non_empty_authors.is_empty() {
-        let joined: Vec<_> = non_empty_authors.iter().map(|a| a.as_str()).collect();
-        lines.push(format!("**Author:** {}", joined.join(", ")));
+    for name in custom {
+        if let Some((key, value)) = docx_properties::find_property(&meta.custom_properties, name) {
+            lines.push(format!("**{}:** {}", key, value.trim()));
+        }
     }
 
-    if let Some(ref publisher) = meta.publisher {
-        if !publisher.trim().is_empty() {
-            lines.push(format!("**Publisher:** {}", publisher));
-        }
+    if lines.is_empty() {
+        return String::new();
+    }
+    if lines.last().is_some_and(|line| !line.is_empty()) {
+        lines.push(String::new());
     }
+    lines.push("---".to_string());
+    lines.push(String::new());
 
-    if let Some(ref language) = meta.language {
-        if !language.trim().is_empty() {
-            lines.push(format!("**Language:** {}", language));
-        }
+    // Ensure the metadata block ends with a trailing newline
+    lines.join("\n") + "\n"
+}
+
+/// Push `block` set apart from the lines around it by blank lines
+fn push_block(lines: &mut Vec<String>, block: String) {
+    if lines.last().is_some_and(|line| !line.is_empty()) {
+        lines.push(String::new());
     }
+    lines.push(block);
+    lines.push(String::new());
+}
+
+/// Header for merged output: one line per input listing its label and authors
+pub fn format_combined_metadata(
+    sources: &[(&str, &Metadata)],
+    reading_minutes: Option<usize>,
+) -> String {
+    let mut lines = vec!["**Sources:**".to_string(), String::new()];
+
+    for (i, (title, meta)) in sources.iter().enumerate() {
+        let authors: Vec<_> = meta
+            .authors
+            .iter()
+            .map(|a| a.as_str())
+            .filter(|a| !a.trim().is_empty())
+            .collect();
 
-    if let Some(ref description) = meta.description {
-        if !description.trim().is_empty() {
-            lines.push(String::new());
-            lines.push(format!("> {}", description));
+        if authors.is_empty() {
+            lines.push(format!("{}. *{}*", i + 1, title));
+        } else {
+            lines.push(format!("{}. *{}* — {}", i + 1, title, authors.join(", ")));
         }
     }
 
-    if !lines.is_empty() {
-        lines.push(String::new());
-        lines.push("---".to_string());
+    if let Some(minutes) = reading_minutes {
         lines.push(String::new());
+        lines.push(reading_time_line(minutes));
     }
 
-    let result = lines.join("\n");
-    // Ensure the metadata block ends with a trailing newline
-    if result.is_empty() {
-        result
-    } else {
-        result + "\n"
+    lines.push(String::new());
+    lines.push("---".to_string());
+    lines.push(String::new());
+
+    lines.join("\n") + "\n"
+}
+
+/// License texts can run over several lines, which would end the `**Rights:**` line
+fn one_line(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn reading_time_line(minutes: usize) -> String {
+    format!("**Reading time:** ~{} min", minutes)
+}
+
+/// Placeholder values for an `--output-template` README: every metadata field
+/// (blank when missing) and the standard `{metadata}` header
+pub fn template_values(meta: &Metadata, metadata_header: &str) -> Vec<(&'static str, String)> {
+    let text = |value: &Option<String>| value.clone().unwrap_or_default().trim().to_string();
+    vec![
+        ("title", text(&meta.title)),
+        ("author", meta.authors.join(", ")),
+        ("publisher", text(&meta.publisher)),
+        ("language", text(&meta.language)),
+        ("date", text(&meta.date)),
+        ("rights", text(&meta.rights)),
+        ("description", text(&meta.description)),
+        ("metadata", metadata_header.trim_end().to_string()),
+    ]
+}
+
+/// Replace `{name}` placeholders that have a value in `values` in one pass, so
+/// braces inside a value are never expanded; other braces are left as written.
+/// A line holding nothing but an empty placeholder is dropped, along with the
+/// blank line after it when it stood as a paragraph of its own
+pub fn fill_template(template: &str, values: &[(&str, String)]) -> String {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(open) = rest.find('{') {
+        result.push_str(&rest[..open]);
+        rest = &rest[open..];
+
+        let value = rest.find('}').and_then(|close| {
+            let name = &rest[1..close];
+            values
+                .iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| (value, close))
+        });
+        match value {
+            Some((value, close)) => {
+                rest = &rest[close + 1..];
+                let line_start = result.is_empty() || result.ends_with('\n');
+                let line_end =
+                    rest.is_empty() || rest.starts_with('\n') || rest.starts_with("\r\n");
+                if value.is_empty() && line_start && line_end {
+                    rest = skip_line_break(rest);
+                    // A blank paragraph also takes the blank line separating it
+                    if result.is_empty() || result.ends_with("\n\n") || result.ends_with("\n\r\n") {
+                        rest = skip_line_break(rest);
+                    }
+                } else {
+                    result.push_str(value);
+                }
+            }
+            None => {
+                result.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    result.push_str(rest);
+    result
+}
+
+/// `text` after its leading line break, if it starts with one
+fn skip_line_break(text: &str) -> &str {
+    text.strip_prefix("\r\n")
+        .or_else(|| text.strip_prefix('\n'))
+        .unwrap_or(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn template_values_are_not_expanded_again() {
+        let values = [
+            ("title", "Braces {toc} in a title".to_string()),
+            ("toc", "1. [One](one.md)".to_string()),
+        ];
+        let filled = fill_template("# {title}\n\n{toc}\n", &values);
+        assert_eq!(filled, "# Braces {toc} in a title\n\n1. [One](one.md)\n");
+    }
+
+    #[test]
+    fn only_lines_of_empty_placeholders_are_dropped() {
+        let values = [
+            ("cover", String::new()),
+            ("author", String::new()),
+            ("toc", "1. [One](one.md)".to_string()),
+        ];
+        let template = "{cover}\n# Book\n\n\n\nBy {author}\n\n{toc}\n";
+        let filled = fill_template(template, &values);
+        assert_eq!(filled, "# Book\n\n\n\nBy \n\n1. [One](one.md)\n");
+
+        let template = "{cover}\n\n# Book\n\n{author}\n\n{toc}\n";
+        let filled = fill_template(template, &values);
+        assert_eq!(filled, "# Book\n\n1. [One](one.md)\n");
     }
 }
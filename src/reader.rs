@@ -1,10 +1,19 @@
+use crate::cli::{EditMarkup, Endnotes, IndentStyle, PageAnchorStyle};
+use crate::image::ImageMap;
 use anyhow::Result;
+use std::collections::{HashMap, HashSet};
 
 /// Shared chapter representation across all input formats
 pub struct Chapter {
     pub title: Option<String>,
     /// Already-converted markdown content
     pub content: String,
+    /// Semantic landmark type (`cover`, `toc`, `bodymatter`, …) when the source labels one
+    pub landmark: Option<String>,
+    /// Path of the source document within the book, when the format has one per chapter
+    pub source_href: Option<String>,
+    /// `idref` of the spine item (or manifest `id`) the chapter was read from
+    pub source_idref: Option<String>,
 }
 
 /// Shared image representation across all input formats
@@ -13,6 +22,15 @@ pub struct ImageResource {
     pub data: Vec<u8>,
 }
 
+/// One entry of a book's navigation (table of contents), listed depth-first
+pub struct NavEntry {
+    /// Nesting level, `0` for top-level entries
+    pub depth: usize,
+    pub title: String,
+    /// Document the entry points at, without fragment; `None` for pure group labels
+    pub href: Option<String>,
+}
+
 /// Shared metadata representation across all input formats
 pub struct Metadata {
     pub title: Option<String>,
@@ -20,14 +38,138 @@ pub struct Metadata {
     pub publisher: Option<String>,
     pub language: Option<String>,
     pub description: Option<String>,
+    /// Publication date as given by the source, e.g. `2023-01-25`
+    pub date: Option<String>,
+    /// Copyright or license statement (`dc:rights`)
+    pub rights: Option<String>,
+    /// Creators with the `aut` role, or `None` when the source records no roles
+    pub primary_authors: Option<Vec<String>>,
+    /// DOCX custom document properties and document variables, by name
+    pub custom_properties: HashMap<String, String>,
+}
+
+impl Metadata {
+    /// Narrow `authors` to the primary authors, leaving it unchanged when no
+    /// roles were recorded or none of the creators is marked as an author
+    pub fn keep_primary_authors(&mut self) {
+        if let Some(primary) = self.primary_authors.take().filter(|p| !p.is_empty()) {
+            self.authors = primary;
+        }
+    }
+}
+
+/// Trim author names and drop blanks and case-insensitive repeats, keeping
+/// first-seen order (EPUBs may list one creator per refinement)
+pub fn dedupe_authors(authors: impl IntoIterator<Item = String>) -> Vec<String> {
+    let mut seen = HashSet::new();
+    authors
+        .into_iter()
+        .map(|author| author.trim().to_string())
+        .filter(|author| !author.is_empty() && seen.insert(author.to_lowercase()))
+        .collect()
+}
+
+/// Shared conversion options across all input formats, set from the CLI
+#[derive(Debug, Default, Clone)]
+pub struct ConvertOptions {
+    /// Derive alt text from the image filename when the source provides none
+    pub image_alt_from_filename: bool,
+    /// Preserve empty paragraphs as spacer lines instead of dropping them
+    pub keep_empty_paragraphs: bool,
+    /// Keep the EPUB 3 navigation document as a chapter
+    pub keep_nav: bool,
+    /// Convert embedded (`<iframe>` / `<object>`) book documents in place
+    pub inline_embedded: bool,
+    /// Wrap DOCX runs with an explicit text color in a colored `<span>`
+    pub preserve_color: bool,
+    /// Replace images that were not extracted with a `[image: alt]` text placeholder
+    pub image_placeholder: bool,
+    /// Collapse line breaks and whitespace runs inside DOCX table cells to single spaces
+    pub collapse_table_whitespace: bool,
+    /// Point DOCX links to bookmarks on headings at the heading's text slug
+    pub heading_slugs: bool,
+    /// Turn EPUB spans styled bold/italic by CSS classes into `<strong>` / `<em>`
+    pub css_emphasis: bool,
+    /// Only keep chapters between the `bodymatter` and `backmatter` landmarks
+    pub body_matter_only: bool,
+    /// Split DOCX output into chapters before headings of this level or shallower
+    pub split_by_heading: Option<u8>,
+    /// Move the EPUB cover page to the front, exempt from `body_matter_only`
+    pub keep_cover_as_first_chapter: bool,
+    /// Spell out `<abbr title>` on first use and unwrap `<time>` to its text
+    pub expand_abbr: bool,
+    /// Mark DOCX right-to-left paragraphs and runs with `dir="rtl"` blocks and RLM/LRM marks
+    pub preserve_direction: bool,
+    /// Mark EPUB `page-list` positions with page anchors or comments
+    pub page_anchors: Option<PageAnchorStyle>,
+    /// Keep DOCX optional hyphens as U+00AD instead of dropping them
+    pub keep_soft_hyphens: bool,
+    /// Backslash-escape literal markdown characters (`*`, `_`, `#`, `[`, `]`, `` ` ``) in text
+    pub escape_markdown: bool,
+    /// Merge adjacent identical emphasis spans in EPUB markdown
+    pub merge_adjacent_formatting: bool,
+    /// Turn bracketed-number footnote links in EPUB markdown into `[^N]` footnotes
+    pub trim_footnote_markers: bool,
+    /// How EPUB `<ins>` / `<del>` elements are written
+    pub edit_markup: EditMarkup,
+    /// Decode HTML character references left in the final markdown
+    pub convert_entities: bool,
+    /// Remove scripts, event handlers and `javascript:` URLs from EPUB HTML
+    pub html_sanitize: bool,
+    /// Token for line breaks inside table cells; DOCX uses `<br>` and EPUB keeps
+    /// html2md's `<br/>` when unset
+    pub cell_break: Option<String>,
+    /// Keep line breaks within a paragraph as hard markdown line breaks
+    pub preserve_line_breaks: bool,
+    /// Replace line breaks inside headings with spaces
+    pub strip_soft_returns_in_headings: bool,
+    /// How DOCX paragraph left indentation is kept, if at all
+    pub preserve_indent: Option<IndentStyle>,
+    /// Spaces per level when indentation is kept as spaces
+    pub indent_width: usize,
+    /// Keep a leading hand-written table of contents chapter instead of skipping it
+    pub keep_manual_toc: bool,
+    /// Whether DOCX endnotes get their own trailing section or become footnotes
+    pub endnotes: Endnotes,
+    /// Append the DOCX tracked changes as a Revision History section
+    pub changelog: bool,
+    /// Original hrefs of decorative images left out; their references are removed
+    pub ignored_images: HashSet<String>,
+    /// Title untitled chapters after their opening sentence instead of "Chapter N"
+    pub rename_untitled: bool,
+    /// Reduce the metadata authors to creators with the `aut` role
+    pub authors_only: bool,
 }
 
 /// Trait for reading document formats (EPUB, DOCX, etc.)
 pub trait BookReader {
-    /// Extract chapters as markdown content
-    fn chapters(&self) -> Result<Vec<Chapter>>;
+    /// Extract chapters as markdown content, rewriting image references via `image_map`.
+    /// `on_chapter` is called with the chapter's number and the chapter count as each
+    /// one is converted
+    fn chapters(
+        &self,
+        image_map: &ImageMap,
+        options: &ConvertOptions,
+        on_chapter: &dyn Fn(usize, usize),
+    ) -> Result<Vec<Chapter>>;
     /// Extract embedded images
     fn images(&self) -> Result<Vec<ImageResource>>;
+    /// Extract embedded audio and video files
+    fn media(&self) -> Result<Vec<ImageResource>> {
+        Ok(Vec::new())
+    }
     /// Extract document metadata
     fn metadata(&self) -> Metadata;
+    /// Navigation entries in reading order, with nesting given by depth
+    fn nav(&self) -> Vec<NavEntry> {
+        Vec::new()
+    }
+    /// Original href of the cover image, matching an `ImageResource::original_href`
+    fn cover_href(&self) -> Option<String> {
+        None
+    }
+    /// Read just the cover image, without extracting the others
+    fn cover_image(&self) -> Result<Option<ImageResource>> {
+        Ok(None)
+    }
 }
@@ -0,0 +1,134 @@
+//! Structural checks on generated markdown with a CommonMark parser. Any input
+//! parses, so the checks look for markup the parser had to fall back on reading
+//! as literal text: unmatched emphasis, table rows outside a table, and links to
+//! reference definitions that don't exist.
+
+use pulldown_cmark::{BrokenLink, Event, LinkType, Options, Parser, Tag, TagEnd};
+use std::ops::Range;
+
+/// A problem found in a markdown document
+pub struct Issue {
+    /// 1-based line number
+    pub line: usize,
+    pub message: String,
+}
+
+pub fn check_markdown(md: &str) -> Vec<Issue> {
+    let mut issues = Vec::new();
+    let mut broken = Vec::new();
+
+    let options =
+        Options::ENABLE_TABLES | Options::ENABLE_FOOTNOTES | Options::ENABLE_STRIKETHROUGH;
+    let parser = Parser::new_with_broken_link_callback(
+        md,
+        options,
+        Some(|link: BrokenLink| {
+            broken.push((link.span, link.link_type, link.reference.to_string()));
+            None
+        }),
+    );
+
+    let mut code_depth = 0usize;
+    for (event, range) in parser.into_offset_iter() {
+        match event {
+            Event::Start(Tag::CodeBlock(_)) => code_depth += 1,
+            Event::End(TagEnd::CodeBlock) => code_depth = code_depth.saturating_sub(1),
+            Event::Text(_) if code_depth == 0 => check_text(md, range, &mut issues),
+            _ => {}
+        }
+    }
+
+    for (span, link_type, reference) in broken {
+        // `[text]` alone is usually prose (`[sic]`), not a link; footnote markers are not
+        let dangling = match link_type {
+            LinkType::Reference | LinkType::Collapsed => true,
+            LinkType::Shortcut => reference.starts_with('^'),
+            _ => false,
+        };
+        if dangling {
+            issues.push(Issue {
+                line: line_of(md, span.start),
+                message: format!("link reference `{}` has no definition", reference),
+            });
+        }
+    }
+
+    // A split marker run is reported once per line
+    issues.sort_by_key(|issue| issue.line);
+    issues.dedup_by(|a, b| a.line == b.line && a.message == b.message);
+    issues
+}
+
+/// Look for emphasis markers and table pipes left over in a run of plain text
+fn check_text(md: &str, range: Range<usize>, issues: &mut Vec<Issue>) {
+    let source = &md[range.clone()];
+    let line = line_of(md, range.start);
+
+    // A row the table extension rejected comes back as a paragraph starting with `|`
+    let line_start = md[..range.start].rfind('\n').map_or(0, |i| i + 1);
+    if source.starts_with('|') && md[line_start..range.start].trim().is_empty() {
+        issues.push(Issue {
+            line,
+            message: "table row is not part of a table (missing or malformed delimiter row)"
+                .to_string(),
+        });
+        return;
+    }
+
+    // Text after an escape (`\*`) starts with the escaped character, minus the backslash
+    let start = if md[..range.start].ends_with('\\') {
+        range.start + source.chars().next().map_or(0, char::len_utf8)
+    } else {
+        range.start
+    };
+    if let Some(marker) = stray_emphasis(md, start..range.end) {
+        issues.push(Issue {
+            line,
+            message: format!("unmatched emphasis marker `{}`", marker),
+        });
+    }
+}
+
+/// First unescaped `*`/`_` run in `md[range]` that could open or close emphasis but was
+/// read as text. The parser splits text around such runs, so neighbours are looked up
+/// in the whole document. Runs with whitespace on both sides and `_` inside a word
+/// can't be emphasis
+fn stray_emphasis(md: &str, range: Range<usize>) -> Option<String> {
+    let text = &md[range.clone()];
+    let mut runs = text.char_indices().peekable();
+    while let Some((i, c)) = runs.next() {
+        if c == '\\' {
+            runs.next();
+            continue;
+        }
+        if c != '*' && c != '_' {
+            continue;
+        }
+
+        let mut end = i + 1;
+        while let Some(&(j, _)) = runs.peek().filter(|&&(_, next)| next == c) {
+            end = j + 1;
+            runs.next();
+        }
+        // Widen to the whole run, which the parser may have split over several events
+        let run_start = md[..range.start + i].trim_end_matches(c).len();
+        let run_end = md.len() - md[range.start + end..].trim_start_matches(c).len();
+        let before = md[..run_start].chars().next_back();
+        let after = md[run_end..].chars().next();
+        let spaced = |ch: Option<char>| ch.is_none_or(char::is_whitespace);
+        let word = |ch: Option<char>| ch.is_some_and(char::is_alphanumeric);
+
+        if spaced(before) && spaced(after) {
+            continue;
+        }
+        if c == '_' && word(before) && word(after) {
+            continue;
+        }
+        return Some(md[run_start..run_end].to_string());
+    }
+    None
+}
+
+fn line_of(md: &str, offset: usize) -> usize {
+    md[..offset].matches('\n').count() + 1
+}